@@ -0,0 +1,916 @@
+use std::{fmt::Write, io, path::Path};
+
+use crate::repo::{Arch, Suite};
+
+/// The local copy of the repositories used by an offline build
+#[derive(Clone, Copy)]
+pub struct OfflineRepos<'a> {
+    /// URL the copy is served at, which every repository of the build starts with
+    pub url: &'a str,
+    /// Directory of the copy, for builders that mount it
+    pub path: &'a Path,
+}
+
+/// Everything a builder needs to build binaries from a source package
+pub struct BuildJob<'a> {
+    pub arch: &'a Arch,
+    pub suite: &'a Suite,
+    /// Source package to build
+    pub dsc: &'a Path,
+    /// Directory the build results and the build log are written to
+    pub path: &'a Path,
+    /// Ubuntu mirror for this suite and architecture
+    pub mirror: &'a str,
+    /// Additional sources.list entries, signed by key
+    pub repositories: Vec<String>,
+    pub key: &'a Path,
+    /// Additional sources.list entries that are trusted without a key
+    pub trusted_repositories: Vec<String>,
+    /// Directory of the trusted repositories, for builders that mount them
+    pub local_repo_dir: Option<&'a Path>,
+    /// Directory of debs that must be available for dependency resolution
+    pub extra_dir: Option<&'a Path>,
+    /// Refuse network access during the build, with all repositories in the local copy
+    pub offline: Option<OfflineRepos<'a>>,
+}
+
+impl BuildJob<'_> {
+    /// Name of the build log, matching the name sbuild uses
+    pub fn log_name(&self) -> String {
+        let stem = self
+            .dsc
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        format!("{}_{}.build", stem, self.arch.id())
+    }
+}
+
+/// A backend used to build binary packages. Builders produce bash scripts, which are run either
+/// locally or on a remote builder over ssh.
+pub trait Builder: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Script creating the build environment for a suite and architecture, if it is missing
+    fn create_script(&self, suite: &Suite, arch: &Arch, mirror: &str) -> String;
+
+    /// Script updating the build environment for a suite and architecture
+    fn update_script(&self, suite: &Suite, arch: &Arch) -> String;
+
+    /// Script building a source package, writing results to the job path
+    fn build_script(&self, job: &BuildJob) -> String;
+
+    /// Shell command failing if the builder can not be used on a host
+    fn health_script(&self) -> String;
+
+    /// URL of local repositories mounted into builds, for builders whose builds can not reach
+    /// servers on the loopback address of the host
+    fn mounted_repo_url(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// URL the local copy of the repositories is mounted at in offline builds, for builders
+    /// whose offline builds have no network at all. The copy must be on the same machine.
+    fn mounted_mirror_url(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Arguments selecting the autopkgtest virtualization server for the build environment
+    fn autopkgtest_virt(&self, suite: &Suite, arch: &Arch) -> String;
+
+    /// Arguments selecting the piuparts base system for the build environment
+    fn piuparts_args(&self, suite: &Suite, arch: &Arch) -> String;
+
+    /// Location of the build environment, for display
+    fn environment(&self, suite: &Suite, arch: &Arch) -> String;
+
+    /// Directory on the host where build environments are stored
+    fn storage(&self) -> &'static str;
+
+    /// Script printing whether the build environment and its snapshot exist, as two words that
+    /// are either "yes" or "no"
+    fn status_script(&self, suite: &Suite, arch: &Arch) -> String;
+
+    /// Script running a shell command as root inside the build environment
+    fn exec_script(&self, suite: &Suite, arch: &Arch, command: &str) -> String;
+
+    /// Script saving a snapshot of the build environment, replacing any previous snapshot
+    fn snapshot_script(&self, suite: &Suite, arch: &Arch) -> String;
+
+    /// Script replacing the build environment with its snapshot
+    fn rollback_script(&self, suite: &Suite, arch: &Arch) -> String;
+
+    /// Script deleting the build environment and its snapshot
+    fn delete_script(&self, suite: &Suite, arch: &Arch) -> String;
+}
+
+// Snapshots of directory based build environments are copies next to them
+fn dir_status_script(path: &str) -> String {
+    format!(
+        r#"#!/usr/bin/env bash
+
+if [ -d '{path}' ]; then echo -n yes; else echo -n no; fi
+if [ -d '{path}.prev' ]; then echo " yes"; else echo " no"; fi
+"#,
+        path = path
+    )
+}
+
+fn dir_exec_script(path: &str, command: &str) -> String {
+    format!(
+        r#"#!/usr/bin/env bash
+
+sudo chroot '{path}' sh -s <<'EOF'
+{command}
+EOF
+"#,
+        path = path,
+        command = command
+    )
+}
+
+fn dir_snapshot_script(path: &str) -> String {
+    format!(
+        r#"#!/usr/bin/env bash
+
+set -ex
+
+sudo rm -rf '{path}.prev'
+sudo cp -a --reflink=auto '{path}' '{path}.prev'
+"#,
+        path = path
+    )
+}
+
+fn dir_rollback_script(path: &str) -> String {
+    format!(
+        r#"#!/usr/bin/env bash
+
+set -ex
+
+test -d '{path}.prev'
+sudo rm -rf '{path}.broken'
+if [ -d '{path}' ]
+then
+    sudo mv '{path}' '{path}.broken'
+fi
+sudo mv '{path}.prev' '{path}'
+sudo rm -rf '{path}.broken'
+"#,
+        path = path
+    )
+}
+
+fn dir_delete_script(path: &str) -> String {
+    format!(
+        r#"#!/usr/bin/env bash
+
+set -ex
+
+sudo rm -rf '{path}' '{path}.prev'
+"#,
+        path = path
+    )
+}
+
+/// Names of the builders
+pub const NAMES: &[&str] = &["sbuild", "pbuilder", "cowbuilder", "podman", "docker"];
+
+static PODMAN: Container = Container("podman");
+static DOCKER: Container = Container("docker");
+
+/// Find a builder by name
+pub fn builder(name: &str) -> Option<&'static dyn Builder> {
+    match name {
+        "sbuild" => Some(&Sbuild),
+        "pbuilder" | "cowbuilder" => Some(&Cowbuilder),
+        "podman" => Some(&PODMAN),
+        "docker" => Some(&DOCKER),
+        _ => None,
+    }
+}
+
+/// Builds in sbuild schroots located in /srv/chroot
+pub struct Sbuild;
+
+impl Sbuild {
+    fn chroot(suite: &Suite, arch: &Arch) -> String {
+        format!("/srv/chroot/{}-{}-sbuild", suite.id(), arch.id())
+    }
+}
+
+impl Builder for Sbuild {
+    fn name(&self) -> &'static str {
+        "sbuild"
+    }
+
+    fn create_script(&self, suite: &Suite, arch: &Arch, mirror: &str) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+CHROOT='{chroot}'
+if [ ! -d "$CHROOT" ]
+then
+    set -ex
+
+    sudo auto-apt-proxy sbuild-createchroot \
+        '--include=gnupg,auto-apt-proxy' \
+        '--components=main,restricted,universe,multiverse' \
+        '--arch={arch}' \
+        '{suite}' \
+        "$CHROOT" \
+        '{ubuntu_mirror}'
+fi
+"#,
+            chroot = Self::chroot(suite, arch),
+            arch = arch.id(),
+            suite = suite.id(),
+            ubuntu_mirror = mirror
+        )
+    }
+
+    fn update_script(&self, suite: &Suite, arch: &Arch) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -ex
+
+sudo sbuild-update \
+    '--update' \
+    '--dist-upgrade' \
+    '--clean' \
+    '--autoclean' \
+    '--autoremove' \
+    '--arch={arch}' \
+    '{suite}' \
+"#,
+            arch = arch.id(),
+            suite = suite.id()
+        )
+    }
+
+    fn build_script(&self, job: &BuildJob) -> String {
+        let mut extra_args = String::new();
+        for repository in job.repositories.iter() {
+            writeln!(extra_args, "    '--extra-repository={}' \\", repository).unwrap();
+        }
+        writeln!(
+            extra_args,
+            "    '--extra-repository-key={}' \\",
+            job.key.display()
+        )
+        .unwrap();
+        if let Some(extra_dir) = job.extra_dir {
+            writeln!(
+                extra_args,
+                "    '--extra-package={}' \\",
+                extra_dir.display()
+            )
+            .unwrap();
+        }
+        for repository in job.trusted_repositories.iter() {
+            writeln!(extra_args, "    '--extra-repository={}' \\", repository).unwrap();
+        }
+
+        // Offline builds use the unshare backend, which builds in a network namespace without
+        // network access. It unpacks a tarball of the schroot, which is recreated when the
+        // schroot is updated.
+        let mut offline_config = String::new();
+        if job.offline.is_some() {
+            offline_config = format!(
+                r#"TARBALL="$HOME/.cache/sbuild/pop-ci-{suite}-{arch}.tar"
+if [ ! -f "$TARBALL" ] || [ '{chroot}/var/lib/dpkg/status' -nt "$TARBALL" ]
+then
+    mkdir -p "$(dirname "$TARBALL")"
+    sudo tar --create --file "$TARBALL.$$" --directory '{chroot}' --exclude='./dev/*' --exclude='./proc/*' --exclude='./sys/*' .
+    sudo chown "$(id -u):$(id -g)" "$TARBALL.$$"
+    mv "$TARBALL.$$" "$TARBALL"
+fi
+SBUILD_CONFIG="$(mktemp)"
+trap 'rm -f "$SBUILD_CONFIG"' EXIT
+cat > "$SBUILD_CONFIG" <<'EOF'
+if (-f "$ENV{{HOME}}/.sbuildrc") {{ do "$ENV{{HOME}}/.sbuildrc"; }}
+$chroot_mode = 'unshare';
+$enable_network = 0;
+1;
+EOF
+export SBUILD_CONFIG
+"#,
+                suite = job.suite.id(),
+                arch = job.arch.id(),
+                chroot = Self::chroot(job.suite, job.arch)
+            );
+            writeln!(extra_args, "    \"--chroot=$TARBALL\" \\").unwrap();
+        }
+
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -e
+
+mkdir -p '{path}'
+cd '{path}'
+{offline_config}sbuild \
+    '--quiet' \
+    '{arch_all}' \
+    '--arch={arch}' \
+    '--dist={suite}' \
+{extra_args}    '--no-apt-distupgrade' \
+    '--no-run-autopkgtest' \
+    '--no-run-lintian' \
+    '--no-run-piuparts' \
+    '{dsc}'
+"#,
+            arch_all = if job.arch.build_all() {
+                "--arch-all"
+            } else {
+                "--no-arch-all"
+            },
+            arch = job.arch.id(),
+            suite = job.suite.id(),
+            extra_args = extra_args,
+            offline_config = offline_config,
+            path = job.path.display(),
+            dsc = job.dsc.display()
+        )
+    }
+
+    fn health_script(&self) -> String {
+        "sbuild --version > /dev/null".to_string()
+    }
+
+    fn autopkgtest_virt(&self, suite: &Suite, arch: &Arch) -> String {
+        format!("schroot '{}-{}-sbuild'", suite.id(), arch.id())
+    }
+
+    fn piuparts_args(&self, suite: &Suite, arch: &Arch) -> String {
+        format!("'--schroot={}-{}-sbuild'", suite.id(), arch.id())
+    }
+
+    fn environment(&self, suite: &Suite, arch: &Arch) -> String {
+        Self::chroot(suite, arch)
+    }
+
+    fn storage(&self) -> &'static str {
+        "/srv/chroot"
+    }
+
+    fn status_script(&self, suite: &Suite, arch: &Arch) -> String {
+        dir_status_script(&Self::chroot(suite, arch))
+    }
+
+    fn exec_script(&self, suite: &Suite, arch: &Arch, command: &str) -> String {
+        dir_exec_script(&Self::chroot(suite, arch), command)
+    }
+
+    fn snapshot_script(&self, suite: &Suite, arch: &Arch) -> String {
+        dir_snapshot_script(&Self::chroot(suite, arch))
+    }
+
+    fn rollback_script(&self, suite: &Suite, arch: &Arch) -> String {
+        dir_rollback_script(&Self::chroot(suite, arch))
+    }
+
+    fn delete_script(&self, suite: &Suite, arch: &Arch) -> String {
+        // The schroot configuration written by sbuild-createchroot must also be removed, or
+        // creating the chroot again results in duplicate schroots
+        format!(
+            "{}sudo rm -f /etc/schroot/chroot.d/{}-{}-sbuild-*\n",
+            dir_delete_script(&Self::chroot(suite, arch)),
+            suite.id(),
+            arch.id()
+        )
+    }
+}
+
+/// Builds in pbuilder environments managed by cowbuilder in /var/cache/pbuilder
+pub struct Cowbuilder;
+
+impl Cowbuilder {
+    fn basepath(suite: &Suite, arch: &Arch) -> String {
+        format!("/var/cache/pbuilder/{}-{}.cow", suite.id(), arch.id())
+    }
+}
+
+impl Builder for Cowbuilder {
+    fn name(&self) -> &'static str {
+        "cowbuilder"
+    }
+
+    fn create_script(&self, suite: &Suite, arch: &Arch, mirror: &str) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+BASEPATH='{basepath}'
+if [ ! -d "$BASEPATH" ]
+then
+    set -ex
+
+    sudo cowbuilder \
+        --create \
+        --basepath "$BASEPATH" \
+        '--distribution={suite}' \
+        '--architecture={arch}' \
+        '--mirror={ubuntu_mirror}' \
+        '--components=main restricted universe multiverse' \
+        '--debootstrapopts=--include=gnupg,ca-certificates'
+fi
+"#,
+            basepath = Self::basepath(suite, arch),
+            arch = arch.id(),
+            suite = suite.id(),
+            ubuntu_mirror = mirror
+        )
+    }
+
+    fn update_script(&self, suite: &Suite, arch: &Arch) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -ex
+
+sudo cowbuilder \
+    --update \
+    --basepath '{basepath}' \
+    --autocleanaptcache
+"#,
+            basepath = Self::basepath(suite, arch)
+        )
+    }
+
+    fn build_script(&self, job: &BuildJob) -> String {
+        // Extra repositories are added by a hook, as pbuilder only applies its othermirror
+        // option when creating or updating the base
+        let key_dir = job.key.parent().unwrap_or_else(|| Path::new("/"));
+        let mut sources = String::new();
+        for repository in job.repositories.iter() {
+            writeln!(
+                sources,
+                "{}",
+                repository.replacen(
+                    "deb ",
+                    &format!("deb [signed-by={}] ", job.key.display()),
+                    1
+                )
+            )
+            .unwrap();
+        }
+        for repository in job.trusted_repositories.iter() {
+            writeln!(sources, "{}", repository).unwrap();
+        }
+
+        let mut bindmounts = vec![key_dir.to_path_buf()];
+        if let Some(extra_dir) = job.extra_dir {
+            writeln!(
+                sources,
+                "deb [trusted=yes] file://{} ./",
+                extra_dir.display()
+            )
+            .unwrap();
+            bindmounts.push(extra_dir.to_path_buf());
+        }
+
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -e -o pipefail
+
+mkdir -p '{path}'
+cd '{path}'
+
+HOOKDIR="$(mktemp -d)"
+trap 'rm -rf "$HOOKDIR"' EXIT
+cat > "$HOOKDIR/D10pop-ci-sources" <<'EOF'
+#!/bin/sh
+set -e
+cat > /etc/apt/sources.list.d/pop-ci.list <<'SOURCES'
+{sources}SOURCES
+apt-get update
+EOF
+chmod +x "$HOOKDIR/D10pop-ci-sources"
+
+sudo cowbuilder \
+    --build \
+    --basepath '{basepath}' \
+    '--architecture={arch}' \
+    '--distribution={suite}' \
+    --buildresult '{path}' \
+    --hookdir "$HOOKDIR" \
+    --bindmounts '{bindmounts}' \
+    --use-network '{use_network}' \
+    {arch_all} \
+    '{dsc}' \
+    2>&1 | tee '{log_name}'
+"#,
+            sources = sources,
+            basepath = Self::basepath(job.suite, job.arch),
+            arch = job.arch.id(),
+            suite = job.suite.id(),
+            path = job.path.display(),
+            bindmounts = bindmounts
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+            use_network = if job.offline.is_some() { "no" } else { "yes" },
+            arch_all = if job.arch.build_all() {
+                "--debbuildopts -b"
+            } else {
+                "--binary-arch"
+            },
+            dsc = job.dsc.display(),
+            log_name = job.log_name()
+        )
+    }
+
+    fn health_script(&self) -> String {
+        // cowbuilder has no option that runs without root
+        "command -v cowbuilder > /dev/null".to_string()
+    }
+
+    fn autopkgtest_virt(&self, suite: &Suite, arch: &Arch) -> String {
+        // autopkgtest has no pbuilder backend, use a chroot of the cowbuilder base
+        format!("chroot '{}'", Self::basepath(suite, arch))
+    }
+
+    fn piuparts_args(&self, suite: &Suite, arch: &Arch) -> String {
+        format!("'--existing-chroot={}'", Self::basepath(suite, arch))
+    }
+
+    fn environment(&self, suite: &Suite, arch: &Arch) -> String {
+        Self::basepath(suite, arch)
+    }
+
+    fn storage(&self) -> &'static str {
+        "/var/cache/pbuilder"
+    }
+
+    fn status_script(&self, suite: &Suite, arch: &Arch) -> String {
+        dir_status_script(&Self::basepath(suite, arch))
+    }
+
+    fn exec_script(&self, suite: &Suite, arch: &Arch, command: &str) -> String {
+        dir_exec_script(&Self::basepath(suite, arch), command)
+    }
+
+    fn snapshot_script(&self, suite: &Suite, arch: &Arch) -> String {
+        dir_snapshot_script(&Self::basepath(suite, arch))
+    }
+
+    fn rollback_script(&self, suite: &Suite, arch: &Arch) -> String {
+        dir_rollback_script(&Self::basepath(suite, arch))
+    }
+
+    fn delete_script(&self, suite: &Suite, arch: &Arch) -> String {
+        dir_delete_script(&Self::basepath(suite, arch))
+    }
+}
+
+/// Builds in a fresh Ubuntu container image, using podman or docker
+pub struct Container(pub &'static str);
+
+const OFFLINE_MIRROR_URL: &str = "file:///pop-ci/mirror";
+
+impl Container {
+    fn platform(arch: &Arch) -> io::Result<&'static str> {
+        match arch.id() {
+            "amd64" => Ok("linux/amd64"),
+            "i386" => Ok("linux/386"),
+            "arm64" => Ok("linux/arm64"),
+            "armhf" => Ok("linux/arm/v7"),
+            other => Err(io::Error::other(format!(
+                "no container platform for architecture {}",
+                other
+            ))),
+        }
+    }
+
+    fn upstream(suite: &Suite) -> String {
+        format!("docker.io/library/ubuntu:{}", suite.id())
+    }
+
+    // The upstream tag only refers to the architecture pulled last, so each architecture is
+    // tagged locally
+    fn image(suite: &Suite, arch: &Arch) -> String {
+        format!("localhost/pop-ci/ubuntu:{}-{}", suite.id(), arch.id())
+    }
+
+    // Snapshots are kept as a local tag of the previous image
+    fn snapshot(suite: &Suite, arch: &Arch) -> String {
+        format!(
+            "localhost/pop-ci-snapshot/ubuntu:{}-{}",
+            suite.id(),
+            arch.id()
+        )
+    }
+
+    /// Script failing with an error, for architectures without a container platform
+    fn error_script(err: io::Error) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+echo 'pop-ci: {err}' >&2
+exit 1
+"#,
+            err = err
+        )
+    }
+
+    /// Commands pulling the upstream image of an architecture and tagging it locally. Pulls of
+    /// the same suite are serialized, so the upstream tag is not replaced before it is tagged.
+    fn pull_commands(&self, suite: &Suite, arch: &Arch) -> io::Result<String> {
+        Ok(format!(
+            r#"(
+    flock 9
+    {program} pull --platform '{platform}' '{upstream}'
+    {program} tag '{upstream}' '{image}'
+) 9> '/tmp/pop-ci-{program}-ubuntu-{suite}.lock'"#,
+            program = self.0,
+            platform = Self::platform(arch)?,
+            upstream = Self::upstream(suite),
+            image = Self::image(suite, arch),
+            suite = suite.id()
+        ))
+    }
+}
+
+impl Builder for Container {
+    fn name(&self) -> &'static str {
+        self.0
+    }
+
+    fn create_script(&self, suite: &Suite, arch: &Arch, _mirror: &str) -> String {
+        let pull = match self.pull_commands(suite, arch) {
+            Ok(ok) => ok,
+            Err(err) => return Self::error_script(err),
+        };
+        format!(
+            r#"#!/usr/bin/env bash
+
+if ! {program} image inspect '{image}' > /dev/null 2>&1
+then
+    set -ex
+
+    {pull}
+fi
+"#,
+            program = self.0,
+            image = Self::image(suite, arch),
+            pull = pull
+        )
+    }
+
+    fn update_script(&self, suite: &Suite, arch: &Arch) -> String {
+        let pull = match self.pull_commands(suite, arch) {
+            Ok(ok) => ok,
+            Err(err) => return Self::error_script(err),
+        };
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -ex
+
+{pull}
+"#,
+            pull = pull
+        )
+    }
+
+    fn build_script(&self, job: &BuildJob) -> String {
+        let platform = match Self::platform(job.arch) {
+            Ok(ok) => ok,
+            Err(err) => return Self::error_script(err),
+        };
+
+        // Offline builds have no network, and read the local copy of the repositories from a
+        // volume instead
+        let source = |line: String| match job.offline {
+            Some(offline) => line.replacen(offline.url, OFFLINE_MIRROR_URL, 1),
+            None => line,
+        };
+
+        let mut sources = String::new();
+        writeln!(
+            sources,
+            "{}",
+            source(format!(
+                "deb {} {} main restricted universe multiverse",
+                job.mirror,
+                job.suite.id()
+            ))
+        )
+        .unwrap();
+        for repository in job.repositories.iter() {
+            writeln!(
+                sources,
+                "{}",
+                source(repository.replacen("deb ", "deb [signed-by=/pop-ci/key.asc] ", 1))
+            )
+            .unwrap();
+        }
+        for repository in job.trusted_repositories.iter() {
+            writeln!(sources, "{}", repository).unwrap();
+        }
+
+        let mut volumes = format!(
+            "--volume '{}:/pop-ci/source:ro' --volume '{}:/pop-ci/key.asc:ro' --volume '{}:/pop-ci/build'",
+            job.dsc.parent().unwrap_or_else(|| Path::new("/")).display(),
+            job.key.display(),
+            job.path.display()
+        );
+        if let Some(local_repo_dir) = job.local_repo_dir {
+            write!(
+                volumes,
+                " --volume '{}:/pop-ci/pockets:ro'",
+                local_repo_dir.display()
+            )
+            .unwrap();
+        }
+        let mut extra_index = String::new();
+        if let Some(extra_dir) = job.extra_dir {
+            extra_index.push_str(
+                "echo 'deb [trusted=yes] file:///pop-ci/extra ./' >> /etc/apt/sources.list\n",
+            );
+            write!(volumes, " --volume '{}:/pop-ci/extra'", extra_dir.display()).unwrap();
+        }
+
+        let mut network = String::new();
+        if let Some(offline) = job.offline {
+            write!(
+                volumes,
+                " --volume '{}:/pop-ci/mirror:ro'",
+                offline.path.display()
+            )
+            .unwrap();
+            network.push_str("--network none ");
+        }
+
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -e -o pipefail
+
+mkdir -p '{path}'
+cd '{path}'
+SCRIPT="$(mktemp)"
+trap 'rm -f "$SCRIPT"' EXIT
+cat > "$SCRIPT" <<'EOF'
+#!/usr/bin/env bash
+set -ex
+export DEBIAN_FRONTEND=noninteractive
+cat > /etc/apt/sources.list <<'SOURCES'
+{sources}SOURCES
+rm -f /etc/apt/sources.list.d/ubuntu.sources
+apt-get update
+apt-get install --yes --no-install-recommends apt-utils build-essential ca-certificates devscripts equivs
+{extra_index}apt-get update
+mkdir /pop-ci/work
+cd /pop-ci/work
+dpkg-source --extract '/pop-ci/source/{dsc_name}' source
+cd source
+mk-build-deps --install --remove --tool 'apt-get --yes --no-install-recommends' debian/control
+dpkg-buildpackage --no-sign {arch_all}
+cp ../*.deb ../*.changes ../*.buildinfo /pop-ci/build/
+find .. -maxdepth 1 -name '*.ddeb' -exec cp {{}} /pop-ci/build/ ';'
+EOF
+
+{program} run \
+    --rm \
+    --platform '{platform}' \
+    {network}{volumes} \
+    --volume "$SCRIPT:/pop-ci/build.sh:ro" \
+    '{image}' \
+    bash /pop-ci/build.sh \
+    2>&1 | tee '{log_name}'
+"#,
+            path = job.path.display(),
+            sources = sources,
+            extra_index = extra_index,
+            dsc_name = job
+                .dsc
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            arch_all = if job.arch.build_all() {
+                "--build=binary"
+            } else {
+                "--build=any"
+            },
+            program = self.0,
+            platform = platform,
+            network = network,
+            volumes = volumes,
+            image = Self::image(job.suite, job.arch),
+            log_name = job.log_name()
+        )
+    }
+
+    fn health_script(&self) -> String {
+        // Also fails if the docker daemon is not running
+        format!("{} info > /dev/null", self.0)
+    }
+
+    fn mounted_repo_url(&self) -> Option<&'static str> {
+        // Containers have their own network, where the loopback address is the container
+        Some("file:///pop-ci/pockets")
+    }
+
+    fn mounted_mirror_url(&self) -> Option<&'static str> {
+        Some(OFFLINE_MIRROR_URL)
+    }
+
+    fn autopkgtest_virt(&self, suite: &Suite, arch: &Arch) -> String {
+        format!("{} '{}'", self.0, Self::image(suite, arch))
+    }
+
+    fn piuparts_args(&self, suite: &Suite, arch: &Arch) -> String {
+        format!("'--docker-image={}'", Self::image(suite, arch))
+    }
+
+    fn environment(&self, suite: &Suite, arch: &Arch) -> String {
+        Self::image(suite, arch)
+    }
+
+    fn storage(&self) -> &'static str {
+        match self.0 {
+            "docker" => "/var/lib/docker",
+            _ => "/var/lib/containers",
+        }
+    }
+
+    fn status_script(&self, suite: &Suite, arch: &Arch) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+if {program} image inspect '{image}' > /dev/null 2>&1; then echo -n yes; else echo -n no; fi
+if {program} image inspect '{snapshot}' > /dev/null 2>&1; then echo " yes"; else echo " no"; fi
+"#,
+            program = self.0,
+            image = Self::image(suite, arch),
+            snapshot = Self::snapshot(suite, arch)
+        )
+    }
+
+    fn exec_script(&self, suite: &Suite, arch: &Arch, command: &str) -> String {
+        let platform = match Self::platform(arch) {
+            Ok(ok) => ok,
+            Err(err) => return Self::error_script(err),
+        };
+        format!(
+            r#"#!/usr/bin/env bash
+
+{program} run --rm --interactive --platform '{platform}' '{image}' sh -s <<'EOF'
+{command}
+EOF
+"#,
+            program = self.0,
+            platform = platform,
+            image = Self::image(suite, arch),
+            command = command
+        )
+    }
+
+    fn snapshot_script(&self, suite: &Suite, arch: &Arch) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -ex
+
+{program} tag '{image}' '{snapshot}'
+"#,
+            program = self.0,
+            image = Self::image(suite, arch),
+            snapshot = Self::snapshot(suite, arch)
+        )
+    }
+
+    fn rollback_script(&self, suite: &Suite, arch: &Arch) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -ex
+
+{program} tag '{snapshot}' '{image}'
+{program} rmi '{snapshot}'
+"#,
+            program = self.0,
+            image = Self::image(suite, arch),
+            snapshot = Self::snapshot(suite, arch)
+        )
+    }
+
+    fn delete_script(&self, suite: &Suite, arch: &Arch) -> String {
+        format!(
+            r#"#!/usr/bin/env bash
+
+set -x
+
+{program} rmi --force '{image}' '{snapshot}'
+true
+"#,
+            program = self.0,
+            image = Self::image(suite, arch),
+            snapshot = Self::snapshot(suite, arch)
+        )
+    }
+}
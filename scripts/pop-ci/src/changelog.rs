@@ -0,0 +1,213 @@
+use json::JsonValue;
+use std::{collections::BTreeMap, fmt, fs, io, path::Path, process};
+
+use crate::{control::Control, index};
+
+/// Versions of the source packages listed in the dists of a suite, by package
+pub fn source_versions(dists_path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut versions = BTreeMap::new();
+    for path in index::files(dists_path)? {
+        let relative = index::relative_path(dists_path, &path)?;
+        if !relative.ends_with("/source/Sources") || relative.contains("/by-hash/") {
+            continue;
+        }
+        for control in Control::parse_all(&fs::read_to_string(&path)?) {
+            if let (Some(package), Some(version)) = (control.get("Package"), control.get("Version"))
+            {
+                versions.insert(package.to_string(), version.to_string());
+            }
+        }
+    }
+    Ok(versions)
+}
+
+/// Compare Debian versions with dpkg, returning true if a is greater than b
+fn version_gt(a: &str, b: &str) -> io::Result<bool> {
+    let status = process::Command::new("dpkg")
+        .arg("--compare-versions")
+        .arg(a)
+        .arg("gt")
+        .arg(b)
+        .status()?;
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(io::Error::other(format!(
+            "dpkg --compare-versions {} gt {} failed with {}",
+            a, b, status
+        ))),
+    }
+}
+
+/// A change to a source package of a suite
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Change {
+    Added {
+        package: String,
+        version: String,
+    },
+    Upgraded {
+        package: String,
+        old: String,
+        new: String,
+    },
+    Downgraded {
+        package: String,
+        old: String,
+        new: String,
+    },
+    Removed {
+        package: String,
+        version: String,
+    },
+}
+
+impl Change {
+    /// Changes between the versions of source packages before and after a run
+    pub fn diff(
+        old: &BTreeMap<String, String>,
+        new: &BTreeMap<String, String>,
+    ) -> io::Result<Vec<Self>> {
+        let mut changes = Vec::new();
+        for (package, version) in new.iter() {
+            let package = package.clone();
+            match old.get(&package) {
+                None => changes.push(Self::Added {
+                    package,
+                    version: version.clone(),
+                }),
+                Some(old_version) if old_version == version => (),
+                Some(old_version) => {
+                    let old = old_version.clone();
+                    let new = version.clone();
+                    changes.push(if version_gt(&new, &old)? {
+                        Self::Upgraded { package, old, new }
+                    } else {
+                        Self::Downgraded { package, old, new }
+                    });
+                }
+            }
+        }
+        for (package, version) in old.iter() {
+            if !new.contains_key(package) {
+                changes.push(Self::Removed {
+                    package: package.clone(),
+                    version: version.clone(),
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.package().cmp(b.package()));
+        Ok(changes)
+    }
+
+    pub fn package(&self) -> &str {
+        match self {
+            Self::Added { package, .. }
+            | Self::Upgraded { package, .. }
+            | Self::Downgraded { package, .. }
+            | Self::Removed { package, .. } => package,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Added { .. } => "added",
+            Self::Upgraded { .. } => "upgraded",
+            Self::Downgraded { .. } => "downgraded",
+            Self::Removed { .. } => "removed",
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = JsonValue::new_object();
+        value["change"] = self.kind().into();
+        value["package"] = self.package().into();
+        match self {
+            Self::Added { version, .. } | Self::Removed { version, .. } => {
+                value["version"] = version.as_str().into();
+            }
+            Self::Upgraded { old, new, .. } | Self::Downgraded { old, new, .. } => {
+                value["old"] = old.as_str().into();
+                value["new"] = new.as_str().into();
+            }
+        }
+        value
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Added { package, version } | Self::Removed { package, version } => {
+                write!(f, "{} {} {}", self.kind(), package, version)
+            }
+            Self::Upgraded { package, old, new } | Self::Downgraded { package, old, new } => {
+                write!(f, "{} {} {} → {}", self.kind(), package, old, new)
+            }
+        }
+    }
+}
+
+/// Changes of a run, by pocket and suite
+#[derive(Clone, Debug, Default)]
+pub struct Changelog {
+    pockets: BTreeMap<String, BTreeMap<String, Vec<Change>>>,
+    archived: Vec<String>,
+}
+
+impl Changelog {
+    /// Record the changes of a suite of a pocket, ignoring suites without changes
+    pub fn add(&mut self, pocket: &str, suite: &str, changes: Vec<Change>) {
+        if !changes.is_empty() {
+            self.pockets
+                .entry(pocket.to_string())
+                .or_default()
+                .insert(suite.to_string(), changes);
+        }
+    }
+
+    /// Record a pocket that stopped being published
+    pub fn archive(&mut self, pocket: &str) {
+        self.archived.push(pocket.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pockets.is_empty() && self.archived.is_empty()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = JsonValue::new_object();
+        value["pockets"] = JsonValue::new_object();
+        for (pocket, suites) in self.pockets.iter() {
+            let mut pocket_value = JsonValue::new_object();
+            for (suite, changes) in suites.iter() {
+                pocket_value[suite.as_str()] = changes
+                    .iter()
+                    .map(Change::to_json)
+                    .collect::<Vec<_>>()
+                    .into();
+            }
+            value["pockets"][pocket.as_str()] = pocket_value;
+        }
+        value["archived"] = self.archived.clone().into();
+        value
+    }
+}
+
+impl fmt::Display for Changelog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (pocket, suites) in self.pockets.iter() {
+            writeln!(f, "{}:", pocket)?;
+            for (suite, changes) in suites.iter() {
+                writeln!(f, "  {}:", suite)?;
+                for change in changes.iter() {
+                    writeln!(f, "    {}", change)?;
+                }
+            }
+        }
+        for pocket in self.archived.iter() {
+            writeln!(f, "{}: archived", pocket)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    config::{BuilderConfig, ChrootConfig},
+    repo::{Arch, Suite},
+    util::{check_output, check_status},
+};
+
+/// Operations on the build environments of builders
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ChrootCommand {
+    Create,
+    Update,
+    List,
+    Verify,
+    Delete,
+    Rollback,
+}
+
+impl ChrootCommand {
+    pub const ALL: &'static [Self] = &[
+        Self::Create,
+        Self::Update,
+        Self::List,
+        Self::Verify,
+        Self::Delete,
+        Self::Rollback,
+    ];
+
+    pub fn new(id: &str) -> Option<Self> {
+        Self::ALL.iter().find(|command| command.id() == id).copied()
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Update => "update",
+            Self::List => "list",
+            Self::Verify => "verify",
+            Self::Delete => "delete",
+            Self::Rollback => "rollback",
+        }
+    }
+}
+
+impl fmt::Display for ChrootCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChrootStatus {
+    pub exists: bool,
+    pub snapshot: bool,
+    /// Number of packages in the recorded manifest
+    pub packages: Option<usize>,
+}
+
+// Prints one line per installed package: name, version and architecture
+const MANIFEST_COMMAND: &str =
+    "dpkg-query --show --showformat='${Package} ${Version} ${Architecture}\\n'";
+
+// Fails if packages are half installed or have broken dependencies
+const VERIFY_COMMAND: &str = "set -e\ndpkg --audit\napt-get check -qq";
+
+/// Whether a host can build for a suite and architecture
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Readiness {
+    Ready,
+    Created,
+    NotReady(String),
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        !matches!(self, Self::NotReady(_))
+    }
+}
+
+impl fmt::Display for Readiness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ready => write!(f, "ready"),
+            Self::Created => write!(f, "ready (created)"),
+            Self::NotReady(reason) => write!(f, "not ready: {}", reason),
+        }
+    }
+}
+
+/// Manages the build environments of the builders on the hosts of a pool, with package
+/// manifests recorded locally
+pub struct Chroots {
+    config: ChrootConfig,
+    path: PathBuf,
+}
+
+impl Chroots {
+    pub fn new<P: AsRef<Path>>(config: ChrootConfig, path: P) -> Self {
+        Self {
+            config,
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Ubuntu mirror a host uses to create build environments
+    pub fn mirror(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> io::Result<String> {
+        host.mirrors
+            .ubuntu(suite.id(), arch.id())
+            .map(|mirror| mirror.to_string())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no ubuntu mirror for {} {}", suite.id(), arch.id()),
+                )
+            })
+    }
+
+    /// Path to the package manifest of a build environment
+    pub fn manifest_path(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> PathBuf {
+        self.path
+            .join(host.name())
+            .join(host.backend().name())
+            .join(format!("{}-{}", suite.id(), arch.id()))
+    }
+
+    fn snapshot_manifest_path(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> PathBuf {
+        self.manifest_path(host, suite, arch).with_extension("prev")
+    }
+
+    fn run(&self, host: &BuilderConfig, script: &str) -> io::Result<()> {
+        host.command(script).status().and_then(check_status)
+    }
+
+    fn output(&self, host: &BuilderConfig, script: &str) -> io::Result<String> {
+        let output = host
+            .command(script)
+            .stderr(process::Stdio::inherit())
+            .output()
+            .and_then(check_output)?;
+        String::from_utf8(output.stdout)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn status(
+        &self,
+        host: &BuilderConfig,
+        suite: &Suite,
+        arch: &Arch,
+    ) -> io::Result<ChrootStatus> {
+        let output = self.output(host, &host.backend().status_script(suite, arch))?;
+        let mut words = output.split_whitespace();
+        let exists = words.next() == Some("yes");
+        let snapshot = words.next() == Some("yes");
+        let packages = fs::read_to_string(self.manifest_path(host, suite, arch))
+            .ok()
+            .map(|manifest| manifest.lines().count());
+        Ok(ChrootStatus {
+            exists,
+            snapshot,
+            packages,
+        })
+    }
+
+    /// Check that a build environment can be created on a host: the mirror must carry the suite
+    /// for the architecture, its release must be signed by the keyring, and there must be enough
+    /// free space
+    pub fn preflight(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> io::Result<()> {
+        let script = format!(
+            r#"#!/usr/bin/env bash
+
+set -e
+
+fail() {{
+    echo "$1" >&2
+    exit 1
+}}
+
+RELEASE="$(mktemp)"
+trap 'rm -f "$RELEASE"' EXIT
+curl --fail --silent --show-error --location --max-time 60 \
+    --output "$RELEASE" \
+    '{mirror}/dists/{suite}/InRelease' \
+    || fail "mirror {mirror} does not carry {suite}"
+grep -q ' main/binary-{arch}/Packages' "$RELEASE" \
+    || fail "mirror {mirror} does not carry {suite} for {arch}"
+test -f '{keyring}' \
+    || fail "keyring {keyring} not found"
+gpgv --keyring '{keyring}' "$RELEASE" > /dev/null 2>&1 \
+    || fail "{suite} on mirror {mirror} is not signed by a key in {keyring}"
+
+DIR='{storage}'
+while [ ! -d "$DIR" ]
+do
+    DIR="$(dirname "$DIR")"
+done
+FREE="$(df --output=avail --block-size=1G "$DIR" | tail -n 1 | tr -d ' ')"
+[ "$FREE" -ge '{min_free_gb}' ] \
+    || fail "only ${{FREE}} GiB free in $DIR, {min_free_gb} GiB required"
+"#,
+            mirror = self.mirror(host, suite, arch)?,
+            suite = suite.id(),
+            arch = arch.id(),
+            keyring = self.config.keyring.display(),
+            storage = host.backend().storage(),
+            min_free_gb = self.config.min_free_gb
+        );
+        let output = host
+            .command(&script)
+            .stdout(process::Stdio::null())
+            .output()?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(
+                String::from_utf8_lossy(&output.stderr)
+                    .lines()
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            ))
+        }
+    }
+
+    /// Create the build environment if it is missing, returning true if it was created
+    pub fn create(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> io::Result<bool> {
+        if self.status(host, suite, arch)?.exists {
+            return Ok(false);
+        }
+        self.preflight(host, suite, arch)?;
+        self.run(
+            host,
+            &host
+                .backend()
+                .create_script(suite, arch, &self.mirror(host, suite, arch)?),
+        )?;
+        self.record_manifest(host, suite, arch)?;
+        Ok(true)
+    }
+
+    /// Create the build environment if it is missing, reporting whether it can be used
+    pub fn prepare(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> Readiness {
+        match self.create(host, suite, arch) {
+            Ok(true) => Readiness::Created,
+            Ok(false) => Readiness::Ready,
+            Err(err) => Readiness::NotReady(err.to_string()),
+        }
+    }
+
+    /// Update the build environment after saving a snapshot of it. If the update or the
+    /// verification afterwards fails, the snapshot is restored.
+    pub fn update(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> io::Result<()> {
+        self.run(host, &host.backend().snapshot_script(suite, arch))?;
+        let manifest_path = self.manifest_path(host, suite, arch);
+        if manifest_path.is_file() {
+            fs::copy(
+                &manifest_path,
+                self.snapshot_manifest_path(host, suite, arch),
+            )?;
+        }
+
+        let res = self
+            .run(host, &host.backend().update_script(suite, arch))
+            .and_then(|()| self.verify(host, suite, arch));
+        if let Err(err) = res {
+            eprintln!(
+                "Chroots::update: {}: {}: {}: update failed, rolling back: {}",
+                host.name(),
+                suite.id(),
+                arch.id(),
+                err
+            );
+            self.rollback(host, suite, arch)?;
+            return Err(err);
+        }
+
+        self.record_manifest(host, suite, arch)?;
+        Ok(())
+    }
+
+    /// Check that the build environment exists and that its packages are consistent
+    pub fn verify(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> io::Result<()> {
+        if !self.status(host, suite, arch)?.exists {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found", host.backend().environment(suite, arch)),
+            ));
+        }
+        self.run(
+            host,
+            &host.backend().exec_script(suite, arch, VERIFY_COMMAND),
+        )
+    }
+
+    /// Replace the build environment with the snapshot saved by the last update
+    pub fn rollback(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> io::Result<()> {
+        self.run(host, &host.backend().rollback_script(suite, arch))?;
+        let snapshot_manifest_path = self.snapshot_manifest_path(host, suite, arch);
+        if snapshot_manifest_path.is_file() {
+            fs::rename(
+                snapshot_manifest_path,
+                self.manifest_path(host, suite, arch),
+            )
+        } else {
+            self.record_manifest(host, suite, arch).map(|_| ())
+        }
+    }
+
+    pub fn delete(&self, host: &BuilderConfig, suite: &Suite, arch: &Arch) -> io::Result<()> {
+        self.run(host, &host.backend().delete_script(suite, arch))?;
+        for path in [
+            self.manifest_path(host, suite, arch),
+            self.snapshot_manifest_path(host, suite, arch),
+        ] {
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the packages installed in the build environment, returning the number of packages
+    pub fn record_manifest(
+        &self,
+        host: &BuilderConfig,
+        suite: &Suite,
+        arch: &Arch,
+    ) -> io::Result<usize> {
+        let mut packages: Vec<String> = self
+            .output(
+                host,
+                &host.backend().exec_script(suite, arch, MANIFEST_COMMAND),
+            )?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect();
+        packages.sort();
+
+        let path = self.manifest_path(host, suite, arch);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut data = packages.join("\n");
+        data.push('\n');
+        let partial_path = path.with_extension("partial");
+        fs::write(&partial_path, data)?;
+        fs::rename(partial_path, path)?;
+        Ok(packages.len())
+    }
+
+    /// Run a command on the build environment of suite and arch on a host
+    pub fn command(
+        &self,
+        command: ChrootCommand,
+        host: &BuilderConfig,
+        suite: &Suite,
+        arch: &Arch,
+    ) -> io::Result<()> {
+        let environment = host.backend().environment(suite, arch);
+        match command {
+            ChrootCommand::Create => {
+                if self.create(host, suite, arch)? {
+                    eprintln!("{}: created {}", host.name(), environment);
+                } else {
+                    eprintln!("{}: {} already exists", host.name(), environment);
+                }
+            }
+            ChrootCommand::Update => {
+                self.update(host, suite, arch)?;
+                eprintln!("{}: updated {}", host.name(), environment);
+            }
+            ChrootCommand::List => {
+                let status = self.status(host, suite, arch)?;
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    host.name(),
+                    suite.id(),
+                    arch.id(),
+                    if status.exists { "present" } else { "missing" },
+                    if status.snapshot {
+                        "snapshot"
+                    } else {
+                        "no-snapshot"
+                    },
+                    match status.packages {
+                        Some(packages) => format!("{} packages", packages),
+                        None => "no manifest".to_string(),
+                    }
+                );
+            }
+            ChrootCommand::Verify => {
+                self.verify(host, suite, arch)?;
+                eprintln!("{}: verified {}", host.name(), environment);
+            }
+            ChrootCommand::Delete => {
+                self.delete(host, suite, arch)?;
+                eprintln!("{}: deleted {}", host.name(), environment);
+            }
+            ChrootCommand::Rollback => {
+                self.rollback(host, suite, arch)?;
+                eprintln!("{}: rolled back {}", host.name(), environment);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,15 @@
+use json::JsonValue;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{builder, index::ComponentRules};
+
 /// Repos to build for both Pop and Ubuntu
-pub static DEV_REPOS: &'static [&'static str] = &[
+pub static DEV_REPOS: &[&str] = &[
     "accountsservice",
     "alsa-ucm-conf",
     "alsa-utils",
@@ -62,10 +72,10 @@ pub static DEV_REPOS: &'static [&'static str] = &[
 ];
 
 /// Repos from DEV_REPOS to build for only Ubuntu
-pub static DEV_ONLY_REPOS: &'static [&'static str] = &["system76-ubuntu-repo"];
+pub static DEV_ONLY_REPOS: &[&str] = &["system76-ubuntu-repo"];
 
 /// Repos to build for Pop 20.04, in addition to DEV_REPOS
-pub static POP_FOCAL_REPOS: &'static [&'static str] = &[
+pub static POP_FOCAL_REPOS: &[&str] = &[
     "alacritty",
     "appstream-data",
     "apt",
@@ -140,3 +150,977 @@ pub static POP_FOCAL_REPOS: &'static [&'static str] = &[
     "v4l2loopback",
     "wallpapers",
 ];
+
+/// A host that binary builds can be run on
+#[derive(Clone, Debug)]
+pub struct BuilderConfig {
+    /// ssh destination, or None to build on this machine
+    pub host: Option<String>,
+    /// Architectures this host builds
+    pub arches: Vec<String>,
+    /// Maximum number of concurrent builds, or 0 for no limit
+    pub slots: usize,
+    /// Directory on the host corresponding to the local working directory. If not set, the host
+    /// must have the same filesystem layout as this machine.
+    pub workdir: Option<PathBuf>,
+    /// Mirrors used by this host, which are the global mirrors with this host's overrides
+    pub mirrors: MirrorConfig,
+    /// Build backend used on this host, or None to use the default of the run
+    pub builder: Option<String>,
+}
+
+impl BuilderConfig {
+    fn from_json(value: &JsonValue, base: &Path) -> io::Result<Self> {
+        let mut arches = Vec::new();
+        for arch in value["arches"].members() {
+            arches.push(
+                arch.as_str()
+                    .ok_or_else(|| invalid_data("builder arches must be strings"))?
+                    .to_string(),
+            );
+        }
+        if arches.is_empty() {
+            return Err(invalid_data("builder must have at least one arch"));
+        }
+
+        let builder = value["builder"].as_str().map(|name| name.to_string());
+        if let Some(name) = &builder {
+            if !builder::NAMES.contains(&name.as_str()) {
+                return Err(invalid_data(format!("unknown builder {}", name)));
+            }
+        }
+
+        Ok(Self {
+            host: value["host"].as_str().map(|host| host.to_string()),
+            arches,
+            slots: value["slots"].as_usize().unwrap_or(0),
+            workdir: value["workdir"].as_str().map(PathBuf::from),
+            mirrors: MirrorConfig::from_json(&value["mirrors"], base)?,
+            builder,
+        })
+    }
+}
+
+/// Ubuntu mirrors, and the Pop apt repositories and keys builds use. Entries are looked up from
+/// the most specific key to the least specific: "suite/arch" then "arch" for Ubuntu mirrors, and
+/// "pop/suite" then "pop" for the rest, where builds of dev repos use "dev" instead of "pop".
+#[derive(Clone, Debug, Default)]
+pub struct MirrorConfig {
+    pub ubuntu: BTreeMap<String, String>,
+    pub release: BTreeMap<String, String>,
+    pub staging: BTreeMap<String, String>,
+    pub keys: BTreeMap<String, PathBuf>,
+    /// Launchpad PPAs that source packages are uploaded to
+    pub dput: BTreeMap<String, String>,
+}
+
+impl MirrorConfig {
+    /// The mirrors used when not configured, with the keys kept in the scripts directory
+    pub fn defaults() -> Self {
+        fn map(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        }
+
+        Self {
+            ubuntu: map(&[
+                ("focal/amd64", "http://us.archive.ubuntu.com/ubuntu"),
+                ("focal/i386", "http://us.archive.ubuntu.com/ubuntu"),
+                ("amd64", "http://apt.pop-os.org/ubuntu"),
+                ("i386", "http://apt.pop-os.org/ubuntu"),
+                ("arm64", "http://ports.ubuntu.com/ubuntu-ports"),
+                ("armhf", "http://ports.ubuntu.com/ubuntu-ports"),
+            ]),
+            // Launchpad used prior to Pop 21.10 and Ubuntu 22.04
+            release: map(&[
+                ("pop", "http://apt.pop-os.org/release"),
+                ("pop/bionic", "http://ppa.launchpad.net/system76/pop/ubuntu"),
+                ("pop/focal", "http://ppa.launchpad.net/system76/pop/ubuntu"),
+                ("dev", "http://apt.pop-os.org/release-ubuntu"),
+                (
+                    "dev/bionic",
+                    "http://ppa.launchpad.net/system76-dev/stable/ubuntu",
+                ),
+                (
+                    "dev/focal",
+                    "http://ppa.launchpad.net/system76-dev/stable/ubuntu",
+                ),
+            ]),
+            staging: map(&[
+                ("pop", "http://apt.pop-os.org/staging/master"),
+                (
+                    "pop/bionic",
+                    "http://ppa.launchpad.net/system76/proposed/ubuntu",
+                ),
+                (
+                    "pop/focal",
+                    "http://ppa.launchpad.net/system76/proposed/ubuntu",
+                ),
+                ("dev", "http://apt.pop-os.org/staging-ubuntu/master"),
+                (
+                    "dev/bionic",
+                    "http://ppa.launchpad.net/system76-dev/pre-stable/ubuntu",
+                ),
+                (
+                    "dev/focal",
+                    "http://ppa.launchpad.net/system76-dev/pre-stable/ubuntu",
+                ),
+            ]),
+            // Found from the source of pop-ci, which is in the scripts directory, so that they do
+            // not depend on the working directory
+            keys: map(&[
+                ("pop", ".iso.asc"),
+                ("pop/bionic", ".ppa.asc"),
+                ("pop/focal", ".ppa.asc"),
+                ("dev", ".iso.asc"),
+                ("dev/bionic", ".ppa-dev.asc"),
+                ("dev/focal", ".ppa-dev.asc"),
+            ])
+            .into_iter()
+            .map(|(key, value)| {
+                (
+                    key,
+                    Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join(value),
+                )
+            })
+            .collect(),
+            dput: map(&[
+                ("pop/bionic", "ppa:system76/proposed"),
+                ("pop/focal", "ppa:system76/proposed"),
+                ("dev", "ppa:system76-dev/pre-stable"),
+            ]),
+        }
+    }
+
+    /// Parse mirrors from JSON, with relative key paths resolved from base
+    fn from_json(value: &JsonValue, base: &Path) -> io::Result<Self> {
+        fn map(value: &JsonValue, name: &str) -> io::Result<BTreeMap<String, String>> {
+            let mut map = BTreeMap::new();
+            for (key, url) in value[name].entries() {
+                let url = url
+                    .as_str()
+                    .ok_or_else(|| invalid_data(format!("mirrors {} must be strings", name)))?;
+                map.insert(key.to_string(), url.trim_end_matches('/').to_string());
+            }
+            Ok(map)
+        }
+
+        Ok(Self {
+            ubuntu: map(value, "ubuntu")?,
+            release: map(value, "release")?,
+            staging: map(value, "staging")?,
+            keys: map(value, "keys")?
+                .into_iter()
+                .map(|(key, path)| (key, base.join(path)))
+                .collect(),
+            dput: map(value, "dput")?,
+        })
+    }
+
+    /// Add the entries of other, replacing existing entries. An entry of other also replaces the
+    /// existing entries that are more specific than it, so that "amd64" overrides "focal/amd64"
+    /// and "pop" overrides "pop/focal", while more specific entries of other still take precedence
+    pub fn merge(&mut self, other: &Self) {
+        fn merge_map<T: Clone>(
+            map: &mut BTreeMap<String, T>,
+            other: &BTreeMap<String, T>,
+            shadows: fn(&str, &str) -> bool,
+        ) {
+            map.retain(|existing, _| !other.keys().any(|key| shadows(key, existing)));
+            map.extend(other.clone());
+        }
+
+        // Ubuntu entries are "suite/arch" or "arch"
+        fn ubuntu_shadows(key: &str, existing: &str) -> bool {
+            existing
+                .strip_suffix(key)
+                .is_some_and(|prefix| prefix.ends_with('/'))
+        }
+
+        // Other entries are "distro/suite" or "distro"
+        fn distro_shadows(key: &str, existing: &str) -> bool {
+            existing
+                .strip_prefix(key)
+                .is_some_and(|suffix| suffix.starts_with('/'))
+        }
+
+        merge_map(&mut self.ubuntu, &other.ubuntu, ubuntu_shadows);
+        merge_map(&mut self.release, &other.release, distro_shadows);
+        merge_map(&mut self.staging, &other.staging, distro_shadows);
+        merge_map(&mut self.keys, &other.keys, distro_shadows);
+        merge_map(&mut self.dput, &other.dput, distro_shadows);
+    }
+
+    fn lookup<'a, T>(map: &'a BTreeMap<String, T>, keys: &[String]) -> Option<&'a T> {
+        keys.iter().find_map(|key| map.get(key))
+    }
+
+    fn distro_keys(suite: &str, dev: bool) -> [String; 2] {
+        let distro = if dev { "dev" } else { "pop" };
+        [format!("{}/{}", distro, suite), distro.to_string()]
+    }
+
+    pub fn ubuntu(&self, suite: &str, arch: &str) -> Option<&str> {
+        Self::lookup(
+            &self.ubuntu,
+            &[format!("{}/{}", suite, arch), arch.to_string()],
+        )
+        .map(|url| url.as_str())
+    }
+
+    pub fn release(&self, suite: &str, dev: bool) -> Option<&str> {
+        Self::lookup(&self.release, &Self::distro_keys(suite, dev)).map(|url| url.as_str())
+    }
+
+    pub fn staging(&self, suite: &str, dev: bool) -> Option<&str> {
+        Self::lookup(&self.staging, &Self::distro_keys(suite, dev)).map(|url| url.as_str())
+    }
+
+    pub fn key(&self, suite: &str, dev: bool) -> Option<&Path> {
+        Self::lookup(&self.keys, &Self::distro_keys(suite, dev)).map(|path| path.as_path())
+    }
+
+    pub fn dput(&self, suite: &str, dev: bool) -> Option<&str> {
+        Self::lookup(&self.dput, &Self::distro_keys(suite, dev)).map(|ppa| ppa.as_str())
+    }
+}
+
+/// Settings used when creating build environments
+#[derive(Clone, Debug)]
+pub struct ChrootConfig {
+    /// Keyring on the builders that the mirror's releases must be signed with
+    pub keyring: PathBuf,
+    /// Free space, in GiB, required on a builder before creating a build environment
+    pub min_free_gb: u64,
+}
+
+impl Default for ChrootConfig {
+    fn default() -> Self {
+        Self {
+            keyring: PathBuf::from("/usr/share/keyrings/ubuntu-archive-keyring.gpg"),
+            min_free_gb: 10,
+        }
+    }
+}
+
+impl ChrootConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        let mut config = Self::default();
+        if let Some(keyring) = value["keyring"].as_str() {
+            config.keyring = PathBuf::from(keyring);
+        }
+        if let Some(min_free_gb) = value["min_free_gb"].as_u64() {
+            config.min_free_gb = min_free_gb;
+        }
+        Ok(config)
+    }
+}
+
+/// Settings for offline builds, which use a local copy of the repositories in mirrors
+#[derive(Clone, Debug)]
+pub struct OfflineConfig {
+    /// Directory the repositories are copied to
+    pub path: PathBuf,
+    /// Timestamp of the snapshot.ubuntu.com snapshot to copy Ubuntu repositories from, like
+    /// 20250101T000000Z, instead of their current state
+    pub snapshot: Option<String>,
+    /// URL builders use for the local copy, required for remote builders
+    pub url: Option<String>,
+}
+
+impl Default for OfflineConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("_build/offline"),
+            snapshot: None,
+            url: None,
+        }
+    }
+}
+
+impl OfflineConfig {
+    fn from_json(value: &JsonValue, base: &Path) -> io::Result<Self> {
+        let mut config = Self::default();
+        if let Some(path) = value["path"].as_str() {
+            config.path = base.join(path);
+        }
+        config.snapshot = value["snapshot"].as_str().map(|x| x.to_string());
+        config.url = value["url"]
+            .as_str()
+            .map(|x| x.trim_end_matches('/').to_string());
+        Ok(config)
+    }
+}
+
+/// Settings for the generated apt repository indexes
+#[derive(Clone, Debug)]
+pub struct DistsConfig {
+    /// Store indexes by hash and set Acquire-By-Hash in Release files
+    pub by_hash: bool,
+    /// How long by-hash indexes of previous generations are kept after being replaced
+    pub by_hash_grace: Duration,
+    /// Component of packages not routed elsewhere
+    pub default_component: String,
+    /// Component of binary packages by Section, or by the area of a Section like non-free
+    pub section_components: BTreeMap<String, String>,
+    /// Publish debug symbol packages in a debug component below their component, like
+    /// main/debug, instead of in a single debug component
+    pub debug_components: bool,
+    /// How long Release files are valid for, set as Valid-Until so that apt detects stale or
+    /// replayed mirrors
+    pub valid_for: Option<Duration>,
+    /// Release files that expire within this time are refreshed and signed again
+    pub refresh_before: Duration,
+}
+
+impl Default for DistsConfig {
+    fn default() -> Self {
+        Self {
+            by_hash: true,
+            by_hash_grace: Duration::from_secs(24 * 60 * 60),
+            default_component: "main".to_string(),
+            section_components: BTreeMap::new(),
+            debug_components: true,
+            valid_for: None,
+            refresh_before: Duration::from_secs(2 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl DistsConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        let mut config = Self::default();
+        if let Some(by_hash) = value["by_hash"].as_bool() {
+            config.by_hash = by_hash;
+        }
+        if let Some(hours) = value["by_hash_grace_hours"].as_u64() {
+            config.by_hash_grace = Duration::from_secs(hours * 60 * 60);
+        }
+        if let Some(component) = value["default_component"].as_str() {
+            config.default_component = component.to_string();
+        }
+        for (section, component) in value["section_components"].entries() {
+            let component = component.as_str().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("component of section {} is not a string", section),
+                )
+            })?;
+            config
+                .section_components
+                .insert(section.to_string(), component.to_string());
+        }
+        if let Some(debug_components) = value["debug_components"].as_bool() {
+            config.debug_components = debug_components;
+        }
+        config.valid_for = valid_for(&value["valid_for_hours"]).flatten();
+        if let Some(hours) = value["refresh_before_hours"].as_u64() {
+            config.refresh_before = Duration::from_secs(hours * 60 * 60);
+        }
+        Ok(config)
+    }
+}
+
+/// Validity of Release files from a number of hours, where 0 disables Valid-Until
+fn valid_for(value: &JsonValue) -> Option<Option<Duration>> {
+    let hours = value.as_u64()?;
+    Some(if hours == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(hours * 60 * 60))
+    })
+}
+
+/// Settings for expiring and archiving pockets
+#[derive(Clone, Debug)]
+pub struct LifecycleConfig {
+    /// Pockets without changes for this long stop being published, if set
+    pub expire_after: Option<Duration>,
+    /// How long pockets stay published after they stop having packages, like when their
+    /// branches are deleted
+    pub grace: Duration,
+    /// How long the last state of expired pockets is kept
+    pub archive_for: Duration,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        Self {
+            expire_after: None,
+            grace: Duration::from_secs(7 * 24 * 60 * 60),
+            archive_for: Duration::from_secs(90 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl LifecycleConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        let mut config = Self::default();
+        if let Some(days) = value["expire_after_days"].as_u64() {
+            config.expire_after = Some(Duration::from_secs(days * 24 * 60 * 60));
+        }
+        if let Some(days) = value["grace_days"].as_u64() {
+            config.grace = Duration::from_secs(days * 24 * 60 * 60);
+        }
+        if let Some(days) = value["archive_days"].as_u64() {
+            config.archive_for = Duration::from_secs(days * 24 * 60 * 60);
+        }
+        Ok(config)
+    }
+}
+
+/// Where apt repositories are published to
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PublishTarget {
+    /// A directory synchronized with rsync, on a host over ssh if set
+    Rsync {
+        host: Option<String>,
+        path: String,
+        /// Remote shell used to reach the host
+        rsh: String,
+    },
+    /// A local directory
+    Local { path: PathBuf },
+    /// A bucket of S3 compatible object storage, using the aws command
+    S3 {
+        bucket: String,
+        prefix: String,
+        /// Endpoint of storage that is not AWS
+        endpoint: Option<String>,
+        profile: Option<String>,
+    },
+}
+
+impl PublishTarget {
+    /// The target of a subdirectory of this target
+    pub fn join(&self, subdir: &str) -> Self {
+        match self {
+            Self::Rsync { host, path, rsh } => Self::Rsync {
+                host: host.clone(),
+                path: format!("{}/{}", path.trim_end_matches('/'), subdir),
+                rsh: rsh.clone(),
+            },
+            Self::Local { path } => Self::Local {
+                path: path.join(subdir),
+            },
+            Self::S3 {
+                bucket,
+                prefix,
+                endpoint,
+                profile,
+            } => Self::S3 {
+                bucket: bucket.clone(),
+                prefix: if prefix.is_empty() {
+                    subdir.to_string()
+                } else {
+                    format!("{}/{}", prefix, subdir)
+                },
+                endpoint: endpoint.clone(),
+                profile: profile.clone(),
+            },
+        }
+    }
+
+    fn from_json(value: &JsonValue, base: &Path) -> io::Result<Self> {
+        let string = |key: &str| -> io::Result<String> {
+            value[key].as_str().map(|x| x.to_string()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("publish target is missing {}", key),
+                )
+            })
+        };
+        match value["type"].as_str() {
+            Some("rsync") => Ok(Self::Rsync {
+                host: value["host"].as_str().map(|host| host.to_string()),
+                path: string("path")?,
+                rsh: value["rsh"].as_str().unwrap_or("ssh").to_string(),
+            }),
+            Some("local") => Ok(Self::Local {
+                path: base.join(string("path")?),
+            }),
+            Some("s3") => Ok(Self::S3 {
+                bucket: string("bucket")?,
+                prefix: value["prefix"]
+                    .as_str()
+                    .unwrap_or("")
+                    .trim_matches('/')
+                    .to_string(),
+                endpoint: value["endpoint"].as_str().map(|x| x.to_string()),
+                profile: value["profile"].as_str().map(|x| x.to_string()),
+            }),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown publish target type {:?}", other),
+            )),
+        }
+    }
+}
+
+/// Publish targets of the staging repositories, by distro: "pop", or "dev" for dev builds
+#[derive(Clone, Debug)]
+pub struct PublishConfig {
+    pub targets: BTreeMap<String, PublishTarget>,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        let rsync = |path: &str| PublishTarget::Rsync {
+            host: Some("ubuntu@apt-origin.pop-os.org".to_string()),
+            path: path.to_string(),
+            rsh: "ssh".to_string(),
+        };
+        let mut targets = BTreeMap::new();
+        targets.insert("pop".to_string(), rsync("/var/www/html/staging/"));
+        targets.insert("dev".to_string(), rsync("/var/www/html/staging-ubuntu/"));
+        Self { targets }
+    }
+}
+
+impl PublishConfig {
+    fn from_json(value: &JsonValue, base: &Path) -> io::Result<Self> {
+        let mut config = Self::default();
+        for (distro, target) in value.entries() {
+            config
+                .targets
+                .insert(distro.to_string(), PublishTarget::from_json(target, base)?);
+        }
+        Ok(config)
+    }
+
+    pub fn target(&self, dev: bool) -> Option<&PublishTarget> {
+        self.targets.get(if dev { "dev" } else { "pop" })
+    }
+}
+
+/// Settings for snapshots of pockets
+#[derive(Clone, Debug)]
+pub struct SnapshotConfig {
+    /// Number of snapshots kept for each pocket, where 0 disables snapshots
+    pub keep: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self { keep: 10 }
+    }
+}
+
+impl SnapshotConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        let mut config = Self::default();
+        if let Some(keep) = value["keep"].as_usize() {
+            config.keep = keep;
+        }
+        Ok(config)
+    }
+}
+
+/// Settings for reporting the package changes of each run
+#[derive(Clone, Debug, Default)]
+pub struct ChangelogConfig {
+    /// URL of a chat webhook the changes are posted to, as JSON with a text field
+    pub webhook: Option<String>,
+}
+
+impl ChangelogConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        Ok(Self {
+            webhook: value["webhook"].as_str().map(|url| url.to_string()),
+        })
+    }
+}
+
+/// Settings for exporting debug symbols for debuginfod
+#[derive(Clone, Debug, Default)]
+pub struct DebuginfodConfig {
+    /// Directory debug symbols are exported to, in the layout of the debuginfod HTTP API
+    pub path: Option<PathBuf>,
+}
+
+impl DebuginfodConfig {
+    fn from_json(value: &JsonValue, base: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: value["path"].as_str().map(|path| base.join(path)),
+        })
+    }
+}
+
+/// Program used to make OpenPGP signatures. Signing always runs an external program, there is
+/// no OpenPGP implementation built in.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum SigningBackend {
+    /// GnuPG, with keys selected by fingerprint from its keyring
+    #[default]
+    Gpg,
+    /// A Stateless OpenPGP command line program like sqop or rsop, with secret keys read from
+    /// files
+    SopCommand(String),
+}
+
+/// Settings for signing apt repositories and build provenance
+#[derive(Clone, Debug, Default)]
+pub struct SigningConfig {
+    pub backend: SigningBackend,
+    /// Fingerprints of the signing keys with gpg, or secret key files with a sop command. All
+    /// keys sign, so that a new key can be introduced before the old one is retired. When empty,
+    /// the gpg key of DEBEMAIL is used.
+    pub keys: Vec<String>,
+    /// GnuPG home directory to use instead of the one of the invoking user
+    pub gnupghome: Option<PathBuf>,
+    /// Fingerprints of the keys that clients should require future Release files to be signed
+    /// by, set as Signed-By in Release files
+    pub signed_by: Vec<String>,
+}
+
+impl SigningConfig {
+    fn from_json(value: &JsonValue, base: &Path) -> io::Result<Self> {
+        let backend = match value["backend"].as_str() {
+            None | Some("gpg") => SigningBackend::Gpg,
+            Some("sop-command") => {
+                SigningBackend::SopCommand(value["command"].as_str().unwrap_or("sop").to_string())
+            }
+            Some(other) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown signing backend {}", other),
+                ))
+            }
+        };
+
+        let mut keys = Vec::new();
+        for key in value["keys"].members() {
+            let key = key.as_str().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "signing key is not a string")
+            })?;
+            keys.push(match backend {
+                SigningBackend::Gpg => key.replace(' ', ""),
+                SigningBackend::SopCommand(_) => base.join(key).to_string_lossy().into_owned(),
+            });
+        }
+
+        let mut signed_by = Vec::new();
+        for fingerprint in value["signed_by"].members() {
+            let fingerprint = fingerprint.as_str().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "signed_by fingerprint is not a string",
+                )
+            })?;
+            signed_by.push(fingerprint.replace(' ', ""));
+        }
+
+        Ok(Self {
+            backend,
+            keys,
+            gnupghome: value["gnupghome"].as_str().map(|path| base.join(path)),
+            signed_by,
+        })
+    }
+}
+
+/// Settings for a QA stage. Unset values fall back to the less specific configuration.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QaStageConfig {
+    /// Run this stage
+    pub enabled: Option<bool>,
+    /// Keep packages failing this stage out of the pocket's apt repository
+    pub gate: Option<bool>,
+}
+
+impl QaStageConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        // A stage can be configured using only a boolean to enable it
+        if let Some(enabled) = value.as_bool() {
+            return Ok(Self {
+                enabled: Some(enabled),
+                gate: None,
+            });
+        }
+
+        Ok(Self {
+            enabled: value["enabled"].as_bool(),
+            gate: value["gate"].as_bool(),
+        })
+    }
+
+    fn or(self, other: Self) -> Self {
+        Self {
+            enabled: self.enabled.or(other.enabled),
+            gate: self.gate.or(other.gate),
+        }
+    }
+}
+
+/// QA stage settings by stage name
+#[derive(Clone, Debug, Default)]
+pub struct QaConfig(pub BTreeMap<String, QaStageConfig>);
+
+impl QaConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        let mut stages = BTreeMap::new();
+        for (stage, stage_value) in value.entries() {
+            stages.insert(stage.to_string(), QaStageConfig::from_json(stage_value)?);
+        }
+        Ok(Self(stages))
+    }
+
+    fn stage(&self, stage: &str) -> QaStageConfig {
+        self.0.get(stage).copied().unwrap_or_default()
+    }
+}
+
+/// Settings for a single repository
+#[derive(Clone, Debug, Default)]
+pub struct RepoConfig {
+    /// Component of the apt repository that packages from this repository are published in
+    pub component: Option<String>,
+    pub qa: QaConfig,
+}
+
+impl RepoConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        Ok(Self {
+            component: value["component"].as_str().map(|x| x.to_string()),
+            qa: QaConfig::from_json(&value["qa"])?,
+        })
+    }
+}
+
+/// Settings for a single pocket
+#[derive(Clone, Debug, Default)]
+pub struct PocketConfig {
+    pub qa: QaConfig,
+    /// Validity of Release files of this pocket, overriding the dists setting when set
+    pub valid_for: Option<Option<Duration>>,
+    /// Never expire this pocket
+    pub keep: bool,
+}
+
+impl PocketConfig {
+    fn from_json(value: &JsonValue) -> io::Result<Self> {
+        Ok(Self {
+            qa: QaConfig::from_json(&value["qa"])?,
+            valid_for: valid_for(&value["valid_for_hours"]),
+            keep: value["keep"].as_bool().unwrap_or(false),
+        })
+    }
+}
+
+/// Configuration loaded from a JSON file
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub builders: Vec<BuilderConfig>,
+    pub changelog: ChangelogConfig,
+    pub chroot: ChrootConfig,
+    pub debuginfod: DebuginfodConfig,
+    pub dists: DistsConfig,
+    pub lifecycle: LifecycleConfig,
+    pub mirrors: MirrorConfig,
+    pub offline: OfflineConfig,
+    pub qa: QaConfig,
+    pub repos: BTreeMap<String, RepoConfig>,
+    pub pockets: BTreeMap<String, PocketConfig>,
+    pub publish: PublishConfig,
+    pub signing: SigningConfig,
+    pub snapshots: SnapshotConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            builders: vec![BuilderConfig {
+                host: None,
+                arches: vec!["amd64".to_string(), "i386".to_string()],
+                slots: 0,
+                workdir: None,
+                mirrors: MirrorConfig::defaults(),
+                builder: None,
+            }],
+            changelog: ChangelogConfig::default(),
+            chroot: ChrootConfig::default(),
+            debuginfod: DebuginfodConfig::default(),
+            dists: DistsConfig::default(),
+            lifecycle: LifecycleConfig::default(),
+            mirrors: MirrorConfig::defaults(),
+            offline: OfflineConfig::default(),
+            qa: QaConfig::default(),
+            repos: BTreeMap::new(),
+            pockets: BTreeMap::new(),
+            publish: PublishConfig::default(),
+            signing: SigningConfig::default(),
+            snapshots: SnapshotConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let data = fs::read_to_string(path.as_ref())?;
+        let value = json::parse(&data).map_err(invalid_data)?;
+
+        // Relative paths in the config are relative to the directory containing it
+        let base = path.as_ref().parent().unwrap_or_else(|| Path::new("."));
+
+        let mut config = Self::default();
+        config
+            .mirrors
+            .merge(&MirrorConfig::from_json(&value["mirrors"], base)?);
+        if value.has_key("builders") {
+            config.builders.clear();
+            for builder in value["builders"].members() {
+                let mut builder = BuilderConfig::from_json(builder, base)?;
+                let mut mirrors = config.mirrors.clone();
+                mirrors.merge(&builder.mirrors);
+                builder.mirrors = mirrors;
+                config.builders.push(builder);
+            }
+        } else {
+            for builder in config.builders.iter_mut() {
+                builder.mirrors = config.mirrors.clone();
+            }
+        }
+        config.changelog = ChangelogConfig::from_json(&value["changelog"])?;
+        config.chroot = ChrootConfig::from_json(&value["chroot"])?;
+        config.debuginfod = DebuginfodConfig::from_json(&value["debuginfod"], base)?;
+        config.dists = DistsConfig::from_json(&value["dists"])?;
+        config.lifecycle = LifecycleConfig::from_json(&value["lifecycle"])?;
+        config.offline = OfflineConfig::from_json(&value["offline"], base)?;
+        config.qa = QaConfig::from_json(&value["qa"])?;
+        config.publish = PublishConfig::from_json(&value["publish"], base)?;
+        config.signing = SigningConfig::from_json(&value["signing"], base)?;
+        config.snapshots = SnapshotConfig::from_json(&value["snapshots"])?;
+        for (name, repo) in value["repos"].entries() {
+            config
+                .repos
+                .insert(name.to_string(), RepoConfig::from_json(repo)?);
+        }
+        for (name, pocket) in value["pockets"].entries() {
+            config
+                .pockets
+                .insert(name.to_string(), PocketConfig::from_json(pocket)?);
+        }
+        Ok(config)
+    }
+
+    /// How packages are routed into the components of the generated apt repositories
+    pub fn component_rules(&self) -> ComponentRules {
+        ComponentRules {
+            default: self.dists.default_component.clone(),
+            repos: self
+                .repos
+                .iter()
+                .filter_map(|(name, repo)| {
+                    repo.component
+                        .as_ref()
+                        .map(|component| (name.clone(), component.clone()))
+                })
+                .collect(),
+            sections: self.dists.section_components.clone(),
+            debug: self.dists.debug_components,
+        }
+    }
+
+    /// Whether a pocket expires when it has no changes, which the master pocket never does
+    pub fn pocket_expires(&self, pocket: &str) -> bool {
+        pocket != "master"
+            && !self
+                .pockets
+                .get(pocket)
+                .is_some_and(|pocket_config| pocket_config.keep)
+    }
+
+    /// How long Release files of a pocket are valid for
+    pub fn valid_for(&self, pocket: &str) -> Option<Duration> {
+        self.pockets
+            .get(pocket)
+            .and_then(|pocket_config| pocket_config.valid_for)
+            .unwrap_or(self.dists.valid_for)
+    }
+
+    /// Settings for a QA stage, where pocket settings override repo settings, which override
+    /// global settings
+    pub fn qa_stage(&self, stage: &str, repo: &str, pocket: &str) -> QaStageConfig {
+        let mut config = QaStageConfig::default();
+        if let Some(pocket_config) = self.pockets.get(pocket) {
+            config = config.or(pocket_config.qa.stage(stage));
+        }
+        if let Some(repo_config) = self.repos.get(repo) {
+            config = config.or(repo_config.qa.stage(stage));
+        }
+        config.or(self.qa.stage(stage))
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_merge_generic_overrides_specific_defaults() {
+        let value = json::parse(
+            r#"{
+                "ubuntu": {
+                    "amd64": "http://mirror.local/ubuntu",
+                    "jammy/arm64": "http://mirror.local/ports"
+                },
+                "release": { "pop": "http://mirror.local/release" },
+                "staging": { "pop/focal": "http://mirror.local/staging-focal" },
+                "keys": { "pop": "mirror.asc" }
+            }"#,
+        )
+        .unwrap();
+        let mut mirrors = MirrorConfig::defaults();
+        mirrors.merge(&MirrorConfig::from_json(&value, Path::new("/config")).unwrap());
+
+        // Generic user entries replace the more specific defaults they shadow
+        assert_eq!(
+            mirrors.ubuntu("focal", "amd64"),
+            Some("http://mirror.local/ubuntu")
+        );
+        assert_eq!(
+            mirrors.ubuntu("noble", "amd64"),
+            Some("http://mirror.local/ubuntu")
+        );
+        assert_eq!(
+            mirrors.release("focal", false),
+            Some("http://mirror.local/release")
+        );
+        assert_eq!(
+            mirrors.key("focal", false),
+            Some(Path::new("/config/mirror.asc"))
+        );
+
+        // Specific user entries win over generic ones, and other defaults are kept
+        assert_eq!(
+            mirrors.ubuntu("jammy", "arm64"),
+            Some("http://mirror.local/ports")
+        );
+        assert_eq!(
+            mirrors.ubuntu("noble", "arm64"),
+            Some("http://ports.ubuntu.com/ubuntu-ports")
+        );
+        assert_eq!(
+            mirrors.ubuntu("focal", "i386"),
+            Some("http://us.archive.ubuntu.com/ubuntu")
+        );
+        assert_eq!(
+            mirrors.staging("focal", false),
+            Some("http://mirror.local/staging-focal")
+        );
+        assert_eq!(
+            mirrors.staging("noble", false),
+            Some("http://apt.pop-os.org/staging/master")
+        );
+        assert_eq!(
+            mirrors.release("focal", true),
+            Some("http://ppa.launchpad.net/system76-dev/stable/ubuntu")
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+/// A single deb822 paragraph, as found in .dsc, .changes and debian/control files
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Control(BTreeMap<String, String>);
+
+impl Control {
+    /// Parse the first paragraph of a deb822 file, skipping any PGP armor
+    pub fn parse(data: &str) -> Self {
+        Self::parse_all(data).into_iter().next().unwrap_or_default()
+    }
+
+    /// Parse all paragraphs of a deb822 file, skipping any PGP armor
+    pub fn parse_all(data: &str) -> Vec<Self> {
+        let mut paragraphs = Vec::new();
+        let mut fields = BTreeMap::new();
+        let mut last_key: Option<String> = None;
+        let mut in_signature = false;
+        for line in data.lines() {
+            if line.starts_with("-----BEGIN PGP SIGNATURE") {
+                in_signature = true;
+                continue;
+            }
+            if line.starts_with("-----END PGP SIGNATURE") {
+                in_signature = false;
+                continue;
+            }
+            if in_signature || line.starts_with("-----BEGIN PGP SIGNED MESSAGE") {
+                continue;
+            }
+            if line.starts_with("Hash: ") && fields.is_empty() && paragraphs.is_empty() {
+                // Armor header of a clearsigned message
+                continue;
+            }
+
+            if line.trim().is_empty() {
+                if !fields.is_empty() {
+                    paragraphs.push(Self(fields));
+                    fields = BTreeMap::new();
+                }
+                last_key = None;
+                continue;
+            }
+
+            if line.starts_with(' ') || line.starts_with('\t') {
+                // Continuation of the previous field
+                if let Some(key) = &last_key {
+                    if let Some(value) = fields.get_mut(key) {
+                        value.push('\n');
+                        value.push_str(line);
+                    }
+                }
+                continue;
+            }
+
+            if let Some((key, value)) = line.split_once(':') {
+                let key = key.trim().to_owned();
+                fields.insert(key.clone(), value.trim().to_owned());
+                last_key = Some(key);
+            }
+        }
+        if !fields.is_empty() {
+            paragraphs.push(Self(fields));
+        }
+        paragraphs
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|value| value.as_str())
+    }
+
+    pub fn fields(&self) -> &BTreeMap<String, String> {
+        &self.0
+    }
+
+    /// Names of the binary packages a source package produces, from the Binary field
+    pub fn binaries(&self) -> Vec<String> {
+        self.get("Binary")
+            .map(|binary| {
+                binary
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|name| !name.is_empty())
+                    .map(|name| name.to_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Names of all packages referenced in Build-Depends, Build-Depends-Arch, and
+    /// Build-Depends-Indep, including alternatives
+    pub fn build_depends(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for key in &["Build-Depends", "Build-Depends-Arch", "Build-Depends-Indep"] {
+            if let Some(value) = self.get(key) {
+                names.extend(relation_names(value));
+            }
+        }
+        names
+    }
+}
+
+/// Package names in a relationship field, ignoring versions, architectures and build profiles
+pub fn relation_names(value: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for relation in value.split(',') {
+        for alternative in relation.split('|') {
+            let name = alternative
+                .trim()
+                .split(|c: char| c.is_whitespace() || c == '(' || c == '[' || c == '<')
+                .next()
+                .unwrap_or("");
+            // Strip architecture qualifiers like :any and :native
+            let name = name.split(':').next().unwrap_or("");
+            if !name.is_empty() {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_depends_names() {
+        let control = Control::parse(
+            "Source: foo
+Build-Depends: debhelper-compat (= 13),
+ libfoo-dev (>= 1.0) | libbar-dev [amd64 arm64],
+ pkg-config:native,
+ python3:any <!nocheck>
+Build-Depends-Arch: libbaz-dev [linux-any] <cross>
+Build-Depends-Indep: dh-sequence-sphinxdoc <!nodoc>
+",
+        );
+        assert_eq!(
+            control.build_depends(),
+            vec![
+                "debhelper-compat",
+                "libfoo-dev",
+                "libbar-dev",
+                "pkg-config",
+                "python3",
+                "libbaz-dev",
+                "dh-sequence-sphinxdoc",
+            ]
+        );
+    }
+
+    #[test]
+    fn binaries_names() {
+        let control = Control::parse("Source: foo\nBinary: foo, libfoo1,\n libfoo-dev\n");
+        assert_eq!(control.binaries(), vec!["foo", "libfoo1", "libfoo-dev"]);
+        assert!(Control::parse("Source: foo\n").binaries().is_empty());
+    }
+}
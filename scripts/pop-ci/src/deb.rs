@@ -0,0 +1,254 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::util::sha256;
+
+/// A member of an ar archive
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ArMember {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Read the members of an ar archive, like a .deb
+pub fn read_ar(data: &[u8]) -> io::Result<Vec<ArMember>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    if !data.starts_with(b"!<arch>\n") {
+        return Err(invalid("not an ar archive"));
+    }
+
+    let mut members = Vec::new();
+    let mut i = 8;
+    while i + 60 <= data.len() {
+        let header = &data[i..i + 60];
+        if &header[58..60] != b"`\n" {
+            return Err(invalid("invalid ar member header"));
+        }
+        let name = String::from_utf8_lossy(&header[0..16])
+            .trim_end()
+            .trim_end_matches('/')
+            .to_string();
+        let size: usize = String::from_utf8_lossy(&header[48..58])
+            .trim()
+            .parse()
+            .map_err(|_| invalid("invalid ar member size"))?;
+        let start = i + 60;
+        let end = start + size;
+        if end > data.len() {
+            return Err(invalid("truncated ar member"));
+        }
+        members.push(ArMember {
+            name,
+            data: data[start..end].to_vec(),
+        });
+        // Members are aligned to two bytes
+        i = end + (size % 2);
+    }
+    Ok(members)
+}
+
+/// A file, directory or link inside a tar member of a .deb
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TarEntry {
+    pub path: String,
+    pub kind: char,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: u64,
+    pub link: Option<String>,
+    /// SHA256 of the contents of regular files
+    pub sha256: Option<String>,
+}
+
+/// Decompress a tar member of a .deb, based on its name
+pub fn decompress<'a>(name: &str, data: &'a [u8]) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(if name.ends_with(".gz") {
+        Box::new(flate2::read::GzDecoder::new(data))
+    } else if name.ends_with(".xz") {
+        Box::new(xz2::read::XzDecoder::new(data))
+    } else if name.ends_with(".zst") {
+        Box::new(zstd::stream::read::Decoder::new(data)?)
+    } else if name.ends_with(".tar") {
+        Box::new(data)
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported compression of {}", name),
+        ));
+    })
+}
+
+/// Read the entries of a tar member of a .deb
+pub fn tar_entries(member: &ArMember) -> io::Result<Vec<TarEntry>> {
+    let mut archive = tar::Archive::new(decompress(&member.name, &member.data)?);
+    let mut entries = Vec::new();
+    for entry_res in archive.entries()? {
+        let mut entry = entry_res?;
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular => '-',
+            tar::EntryType::Directory => 'd',
+            tar::EntryType::Symlink => 'l',
+            tar::EntryType::Link => 'h',
+            _ => '?',
+        };
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mode = header.mode()?;
+        let uid = header.uid()?;
+        let gid = header.gid()?;
+        let mtime = header.mtime()?;
+        let link = entry
+            .link_name()?
+            .map(|link| link.to_string_lossy().into_owned());
+        let sha256 = if kind == '-' {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            Some(sha256(&data))
+        } else {
+            None
+        };
+        entries.push(TarEntry {
+            path,
+            kind,
+            mode,
+            uid,
+            gid,
+            mtime,
+            link,
+            sha256,
+        });
+    }
+    Ok(entries)
+}
+
+/// List the paths of the files and links in a tar member of a .deb, without reading their
+/// contents
+pub fn tar_paths(member: &ArMember) -> io::Result<Vec<String>> {
+    let mut archive = tar::Archive::new(decompress(&member.name, &member.data)?);
+    let mut paths = Vec::new();
+    for entry_res in archive.entries()? {
+        let entry = entry_res?;
+        if entry.header().entry_type() != tar::EntryType::Directory {
+            paths.push(entry.path()?.to_string_lossy().into_owned());
+        }
+    }
+    Ok(paths)
+}
+
+/// Read the regular files of a tar member of a .deb whose path matches a filter
+pub fn tar_files<F: Fn(&str) -> bool>(
+    member: &ArMember,
+    filter: F,
+) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = tar::Archive::new(decompress(&member.name, &member.data)?);
+    let mut files = Vec::new();
+    for entry_res in archive.entries()? {
+        let mut entry = entry_res?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if filter(&path) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            files.push((path, data));
+        }
+    }
+    Ok(files)
+}
+
+/// A binary package file
+pub struct Deb {
+    pub members: Vec<ArMember>,
+}
+
+impl Deb {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            members: read_ar(&fs::read(path)?)?,
+        })
+    }
+
+    pub fn member(&self, prefix: &str) -> Option<&ArMember> {
+        self.members
+            .iter()
+            .find(|member| member.name.starts_with(prefix))
+    }
+
+    /// The control file of the package
+    pub fn control(&self) -> io::Result<String> {
+        let member = self.member("control.tar").ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "deb has no control.tar member")
+        })?;
+        let mut archive = tar::Archive::new(decompress(&member.name, &member.data)?);
+        for entry_res in archive.entries()? {
+            let mut entry = entry_res?;
+            if entry.path()?.to_string_lossy().trim_start_matches("./") == "control" {
+                let mut control = String::new();
+                entry.read_to_string(&mut control)?;
+                return Ok(control);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "deb has no control file",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tar_paths_skips_directories() {
+        let header = |kind, size| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_size(size);
+            header.set_mode(0o755);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header
+        };
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_data(
+                &mut header(tar::EntryType::Directory, 0),
+                "./usr/",
+                io::empty(),
+            )
+            .unwrap();
+        builder
+            .append_data(
+                &mut header(tar::EntryType::Regular, 5),
+                "./usr/foo",
+                &b"hello"[..],
+            )
+            .unwrap();
+        builder
+            .append_link(&mut header(tar::EntryType::Symlink, 0), "./usr/bar", "foo")
+            .unwrap();
+        let member = ArMember {
+            name: "data.tar".to_string(),
+            data: builder.into_inner().unwrap(),
+        };
+
+        assert_eq!(tar_paths(&member).unwrap(), vec!["usr/foo", "usr/bar"]);
+        assert_eq!(
+            tar_entries(&member)
+                .unwrap()
+                .into_iter()
+                .filter(|entry| entry.kind != 'd')
+                .map(|entry| entry.path)
+                .collect::<Vec<_>>(),
+            tar_paths(&member).unwrap()
+        );
+    }
+}
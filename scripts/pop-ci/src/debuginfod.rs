@@ -0,0 +1,48 @@
+use std::{fs, io, path::Path};
+
+use crate::deb::{self, Deb};
+
+/// Directory of debug files in debug symbol packages, by build ID
+const BUILD_ID_DIR: &str = "usr/lib/debug/.build-id/";
+
+/// Export the debug files of a debug symbol package to a directory in the layout of the
+/// debuginfod HTTP API, buildid/<build id>/debuginfo, so it can be served by any web server.
+/// Returns the number of debug files exported.
+pub fn export<P: AsRef<Path>, Q: AsRef<Path>>(ddeb_path: P, path: Q) -> io::Result<usize> {
+    let ddeb = Deb::open(ddeb_path)?;
+    let member = match ddeb.member("data.tar") {
+        Some(some) => some,
+        None => return Ok(0),
+    };
+
+    let mut exported = 0;
+    let files = deb::tar_files(member, |file_path| {
+        file_path.trim_start_matches("./").starts_with(BUILD_ID_DIR)
+            && file_path.ends_with(".debug")
+    })?;
+    for (file_path, data) in files {
+        // usr/lib/debug/.build-id/ab/cdef.debug has the build ID abcdef
+        let build_id: String = file_path
+            .trim_start_matches("./")
+            .trim_start_matches(BUILD_ID_DIR)
+            .trim_end_matches(".debug")
+            .chars()
+            .filter(|c| *c != '/')
+            .collect();
+        if build_id.is_empty() || !build_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+
+        let build_id_dir = path.as_ref().join("buildid").join(&build_id);
+        let debuginfo_path = build_id_dir.join("debuginfo");
+        if debuginfo_path.exists() {
+            continue;
+        }
+        fs::create_dir_all(&build_id_dir)?;
+        let partial_path = build_id_dir.join("partial.debuginfo");
+        fs::write(&partial_path, data)?;
+        fs::rename(partial_path, debuginfo_path)?;
+        exported += 1;
+    }
+    Ok(exported)
+}
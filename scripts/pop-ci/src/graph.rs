@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Sort nodes into waves, where every node only depends on nodes in earlier waves. Nodes that
+/// are part of a dependency cycle are placed together in the final wave.
+pub fn waves<K: Clone + Ord>(deps: &BTreeMap<K, BTreeSet<K>>) -> Vec<Vec<K>> {
+    let mut remaining: BTreeMap<K, BTreeSet<K>> = deps
+        .iter()
+        .map(|(node, node_deps)| {
+            // Ignore dependencies on unknown nodes and on self
+            let node_deps = node_deps
+                .iter()
+                .filter(|dep| *dep != node && deps.contains_key(dep))
+                .cloned()
+                .collect();
+            (node.clone(), node_deps)
+        })
+        .collect();
+
+    let mut waves = Vec::new();
+    while !remaining.is_empty() {
+        let wave: Vec<K> = remaining
+            .iter()
+            .filter(|(_, node_deps)| node_deps.is_empty())
+            .map(|(node, _)| node.clone())
+            .collect();
+
+        if wave.is_empty() {
+            // Dependency cycle, build everything left at once
+            waves.push(remaining.into_keys().collect());
+            break;
+        }
+
+        for node in wave.iter() {
+            remaining.remove(node);
+        }
+        for node_deps in remaining.values_mut() {
+            for node in wave.iter() {
+                node_deps.remove(node);
+            }
+        }
+        waves.push(wave);
+    }
+    waves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(u32, &[u32])]) -> BTreeMap<u32, BTreeSet<u32>> {
+        edges
+            .iter()
+            .map(|(node, deps)| (*node, deps.iter().cloned().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn waves_of_chain() {
+        let deps = graph(&[(1, &[2]), (2, &[3]), (3, &[])]);
+        assert_eq!(waves(&deps), vec![vec![3], vec![2], vec![1]]);
+    }
+
+    #[test]
+    fn waves_of_diamond() {
+        let deps = graph(&[(1, &[2, 3]), (2, &[4]), (3, &[4]), (4, &[])]);
+        assert_eq!(waves(&deps), vec![vec![4], vec![2, 3], vec![1]]);
+    }
+
+    #[test]
+    fn waves_ignore_self_and_unknown_deps() {
+        let deps = graph(&[(1, &[1, 2]), (2, &[5])]);
+        assert_eq!(waves(&deps), vec![vec![2], vec![1]]);
+    }
+
+    #[test]
+    fn waves_put_cycles_last() {
+        // 4 depends on the cycle of 2 and 3, so it can only be built with it
+        let deps = graph(&[(1, &[]), (2, &[1, 3]), (3, &[2]), (4, &[3])]);
+        assert_eq!(waves(&deps), vec![vec![1], vec![2, 3, 4]]);
+    }
+}
@@ -0,0 +1,1152 @@
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Write as FmtWrite},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    deb,
+    util::{parse_rfc2822, rfc2822, sha256},
+};
+
+/// A deb822 paragraph that keeps the order of its fields, for writing indexes
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stanza(Vec<(String, String)>);
+
+impl Stanza {
+    /// Parse the first paragraph of a deb822 file, skipping any PGP armor. Continuation lines
+    /// are kept as they are, after a newline.
+    pub fn parse(data: &str) -> Self {
+        let mut fields: Vec<(String, String)> = Vec::new();
+        let mut in_signature = false;
+        for line in data.lines() {
+            if line.starts_with("-----BEGIN PGP SIGNATURE") {
+                in_signature = true;
+                continue;
+            }
+            if line.starts_with("-----END PGP SIGNATURE") {
+                in_signature = false;
+                continue;
+            }
+            if in_signature || line.starts_with("-----BEGIN PGP SIGNED MESSAGE") {
+                continue;
+            }
+            if line.starts_with("Hash: ") && fields.is_empty() {
+                // Armor header of a clearsigned message
+                continue;
+            }
+
+            if line.trim().is_empty() {
+                if !fields.is_empty() {
+                    break;
+                }
+                continue;
+            }
+
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+                continue;
+            }
+
+            if let Some((key, value)) = line.split_once(':') {
+                fields.push((key.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+        Self(fields)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set a field, keeping its position if it exists, otherwise adding it before the field
+    /// named before, or at the end
+    pub fn insert_before(&mut self, before: &str, key: &str, value: String) {
+        if let Some(field) = self.0.iter_mut().find(|(field, _)| field == key) {
+            field.1 = value;
+        } else if let Some(i) = self.0.iter().position(|(field, _)| field == before) {
+            self.0.insert(i, (key.to_owned(), value));
+        } else {
+            self.0.push((key.to_owned(), value));
+        }
+    }
+
+    /// Set a field, keeping its position if it exists, otherwise adding it at the end
+    pub fn set(&mut self, key: &str, value: String) {
+        self.insert_before("", key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let i = self.0.iter().position(|(field, _)| field == key)?;
+        Some(self.0.remove(i).1)
+    }
+
+    /// Rename a field and move it to the start of the paragraph
+    pub fn rename_first(&mut self, from: &str, to: &str) {
+        if let Some(value) = self.remove(from) {
+            self.remove(to);
+            self.0.insert(0, (to.to_owned(), value));
+        }
+    }
+}
+
+impl fmt::Display for Stanza {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, value) in self.0.iter() {
+            if value.is_empty() || value.starts_with('\n') {
+                writeln!(f, "{}:{}", key, value)?;
+            } else {
+                writeln!(f, "{}: {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes.iter() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+/// Size and hashes of a file, as listed in indexes
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Checksums {
+    pub size: u64,
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+    pub sha512: String,
+}
+
+impl Checksums {
+    pub fn new(data: &[u8]) -> Self {
+        Self {
+            size: data.len() as u64,
+            md5: hex(&Md5::digest(data)),
+            sha1: hex(&Sha1::digest(data)),
+            sha256: hex(&Sha256::digest(data)),
+            sha512: hex(&Sha512::digest(data)),
+        }
+    }
+
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(&fs::read(path)?))
+    }
+}
+
+/// Path of a file below root, relative to root
+pub fn relative_path(root: &Path, path: &Path) -> io::Result<String> {
+    path.strip_prefix(root)
+        .map(|relative| relative.to_string_lossy().into_owned())
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not in {}", path.display(), root.display()),
+            )
+        })
+}
+
+/// A binary package in the pool, with its Packages stanza and the files it contains
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolDeb {
+    pub stanza: Stanza,
+    pub contents: Vec<String>,
+}
+
+impl PoolDeb {
+    /// Read a binary package, with its Filename relative to the repository root
+    pub fn new(root: &Path, path: &Path) -> io::Result<Self> {
+        Self::from_data(root, path, &fs::read(path)?)
+    }
+
+    fn from_data(root: &Path, path: &Path, data: &[u8]) -> io::Result<Self> {
+        let checksums = Checksums::new(data);
+        let package = deb::Deb {
+            members: deb::read_ar(data)?,
+        };
+
+        let mut stanza = Stanza::parse(&package.control()?);
+        // Same position as apt-ftparchive, before the description
+        for (key, value) in [
+            ("Filename", relative_path(root, path)?),
+            ("Size", checksums.size.to_string()),
+            ("MD5sum", checksums.md5),
+            ("SHA1", checksums.sha1),
+            ("SHA256", checksums.sha256),
+            ("SHA512", checksums.sha512),
+        ] {
+            stanza.insert_before("Description", key, value);
+        }
+
+        let mut contents = Vec::new();
+        if let Some(member) = package.member("data.tar") {
+            for path in deb::tar_paths(member)? {
+                contents.push(path.trim_start_matches("./").to_string());
+            }
+        }
+
+        Ok(Self { stanza, contents })
+    }
+
+    pub fn arch(&self) -> &str {
+        self.stanza.get("Architecture").unwrap_or_default()
+    }
+
+    /// Location of the package in Contents indexes
+    pub fn location(&self) -> String {
+        format!(
+            "{}/{}",
+            self.stanza.get("Section").unwrap_or("misc"),
+            self.stanza.get("Package").unwrap_or_default()
+        )
+    }
+}
+
+/// Packages stanzas and contents of debs by the SHA256 of the deb, so that only new packages
+/// are read when indexes are generated. Entries do not include the Filename, as the same deb
+/// may be in the pool of multiple pockets.
+pub struct IndexCache {
+    path: PathBuf,
+}
+
+impl IndexCache {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn entry_path(&self, sha256: &str) -> PathBuf {
+        self.path.join(&sha256[..2]).join(sha256)
+    }
+
+    fn load(&self, entry_path: &Path) -> Option<PoolDeb> {
+        let value = json::parse(&fs::read_to_string(entry_path).ok()?).ok()?;
+        let stanza = Stanza::parse(value["stanza"].as_str()?);
+        let contents = value["contents"]
+            .members()
+            .filter_map(|path| path.as_str().map(|path| path.to_string()))
+            .collect();
+        Some(PoolDeb { stanza, contents })
+    }
+
+    fn store(&self, entry_path: &Path, deb: &PoolDeb) -> io::Result<()> {
+        let mut value = json::JsonValue::new_object();
+        value["stanza"] = deb.stanza.to_string().into();
+        value["contents"] = deb.contents.clone().into();
+
+        if let Some(parent) = entry_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut partial_name = std::ffi::OsString::from("partial.");
+        partial_name.push(entry_path.file_name().unwrap_or_default());
+        let partial_path = entry_path.with_file_name(partial_name);
+        fs::write(&partial_path, value.dump())?;
+        fs::rename(partial_path, entry_path)
+    }
+
+    /// Read a binary package, using the cached stanza and contents if the deb was seen before
+    pub fn deb(&self, root: &Path, path: &Path) -> io::Result<PoolDeb> {
+        let data = fs::read(path)?;
+        let entry_path = self.entry_path(&sha256(&data));
+        let filename = relative_path(root, path)?;
+
+        if let Some(mut deb) = self.load(&entry_path) {
+            // Mark the entry as used, so it is kept by prune
+            fs::File::options()
+                .write(true)
+                .open(&entry_path)?
+                .set_modified(SystemTime::now())?;
+            deb.stanza.insert_before("Size", "Filename", filename);
+            return Ok(deb);
+        }
+
+        let mut deb = PoolDeb::from_data(root, path, &data)?;
+        deb.stanza.remove("Filename");
+        self.store(&entry_path, &deb)?;
+        deb.stanza.insert_before("Size", "Filename", filename);
+        Ok(deb)
+    }
+
+    /// Remove entries that have not been used for max_age
+    pub fn prune(&self, max_age: Duration) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry_path in files(&self.path)? {
+            let age = fs::metadata(&entry_path)?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            if age > max_age {
+                fs::remove_file(&entry_path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Read a source package, returning its Sources stanza with the Directory relative to the
+/// repository root
+pub fn source_stanza(root: &Path, dsc_path: &Path) -> io::Result<Stanza> {
+    let data = fs::read(dsc_path)?;
+    let checksums = Checksums::new(&data);
+    let mut stanza = Stanza::parse(&String::from_utf8_lossy(&data));
+    stanza.rename_first("Source", "Package");
+
+    let dsc_name = dsc_path.file_name().unwrap_or_default().to_string_lossy();
+    for (key, hash) in [
+        ("Files", &checksums.md5),
+        ("Checksums-Sha1", &checksums.sha1),
+        ("Checksums-Sha256", &checksums.sha256),
+    ] {
+        if let Some(value) = stanza.get(key) {
+            let value = format!("\n {} {} {}{}", hash, checksums.size, dsc_name, value);
+            stanza.set(key, value);
+        }
+    }
+
+    let directory = dsc_path.parent().unwrap_or(root);
+    stanza.set("Directory", relative_path(root, directory)?);
+    Ok(stanza)
+}
+
+/// Write the Packages index of a flat repository of the debs in a directory
+pub fn write_flat_packages(path: &Path) -> io::Result<()> {
+    let mut packages = String::new();
+    for deb_path in files(path)? {
+        if deb_path
+            .extension()
+            .is_some_and(|extension| extension == "deb")
+        {
+            if !packages.is_empty() {
+                packages.push('\n');
+            }
+            write!(packages, "{}", PoolDeb::new(path, &deb_path)?.stanza).unwrap();
+        }
+    }
+    fs::write(path.join("Packages"), packages)
+}
+
+/// Write an index, with gzip and xz compressed copies next to it
+pub fn write_compressed(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::write(path, data)?;
+
+    let mut gz_name = path.file_name().unwrap_or_default().to_os_string();
+    gz_name.push(".gz");
+    let mut gz = flate2::write::GzEncoder::new(
+        fs::File::create(path.with_file_name(gz_name))?,
+        flate2::Compression::best(),
+    );
+    gz.write_all(data)?;
+    gz.finish()?;
+
+    let mut xz_name = path.file_name().unwrap_or_default().to_os_string();
+    xz_name.push(".xz");
+    let mut xz = xz2::write::XzEncoder::new(fs::File::create(path.with_file_name(xz_name))?, 6);
+    xz.write_all(data)?;
+    xz.finish()?;
+
+    Ok(())
+}
+
+/// Fields of the Release file of a suite
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReleaseInfo {
+    pub origin: String,
+    pub label: String,
+    pub suite: String,
+    pub version: String,
+    pub codename: String,
+    pub description: String,
+    /// Components that are always published, components that packages are routed to are added
+    pub components: Vec<String>,
+    pub archs: Vec<String>,
+    /// Also store indexes by their hash, so clients are not affected by indexes changing while
+    /// they download them
+    pub acquire_by_hash: bool,
+    /// Fingerprints of the keys future Release files must be signed by
+    pub signed_by: Vec<String>,
+    /// How long the Release file is valid for after its date
+    pub valid_for: Option<Duration>,
+}
+
+impl ReleaseInfo {
+    /// Release file of a component index directory
+    fn component_release(&self, component: &str, arch: &str) -> String {
+        let mut release = String::new();
+        writeln!(release, "Archive: {}", self.suite).unwrap();
+        writeln!(release, "Version: {}", self.version).unwrap();
+        writeln!(release, "Component: {}", component).unwrap();
+        writeln!(release, "Origin: {}", self.origin).unwrap();
+        writeln!(release, "Label: {}", self.label).unwrap();
+        writeln!(release, "Architecture: {}", arch).unwrap();
+        release
+    }
+}
+
+fn walk(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry_res in fs::read_dir(path)? {
+        let entry = entry_res?;
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// All files below a directory, sorted
+pub fn files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    if path.is_dir() {
+        walk(path, &mut files)?;
+    }
+    files.sort();
+    Ok(files)
+}
+
+const BY_HASH: &str = "by-hash";
+
+/// Hard link a file, or copy it if it can not be linked
+pub fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Store the indexes of a directory by their SHA256 in by-hash/SHA256
+pub fn write_by_hash(path: &Path) -> io::Result<()> {
+    for entry_res in fs::read_dir(path)? {
+        let entry = entry_res?;
+        if !entry.file_type()?.is_file() || entry.file_name() == "Release" {
+            continue;
+        }
+        let by_hash_path = path
+            .join(BY_HASH)
+            .join("SHA256")
+            .join(sha256(&fs::read(entry.path())?));
+        if !by_hash_path.exists() {
+            link_or_copy(&entry.path(), &by_hash_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Keep the by-hash indexes of the previous generation of a suite, so clients that fetched
+/// its Release file can still download them. Indexes that were current in the previous
+/// generation are kept for the grace period from now, older ones until the grace period after
+/// they were replaced. Returns the number of indexes kept.
+pub fn keep_by_hash(previous: &Path, dists_path: &Path, grace: Duration) -> io::Result<usize> {
+    let mut current_hashes = BTreeMap::<PathBuf, BTreeSet<String>>::new();
+    let mut kept = 0;
+    for path in files(previous)? {
+        let relative = relative_path(previous, &path)?;
+        let hash_dir = match path.parent() {
+            Some(some) if some.parent().is_some_and(|x| x.ends_with(BY_HASH)) => some,
+            _ => continue,
+        };
+        let index_dir = hash_dir.parent().and_then(|x| x.parent()).unwrap();
+
+        let target = dists_path.join(&relative);
+        if target.exists() {
+            // Still current
+            continue;
+        }
+
+        if !current_hashes.contains_key(index_dir) {
+            let mut hashes = BTreeSet::new();
+            for entry_res in fs::read_dir(index_dir)? {
+                let entry = entry_res?;
+                if entry.file_type()?.is_file() {
+                    hashes.insert(sha256(&fs::read(entry.path())?));
+                }
+            }
+            current_hashes.insert(index_dir.to_path_buf(), hashes);
+        }
+        let hash = path.file_name().unwrap_or_default().to_string_lossy();
+        if current_hashes[index_dir].contains(hash.as_ref()) {
+            // Replaced by this generation, the grace period starts now
+            link_or_copy(&path, &target)?;
+            fs::File::options()
+                .write(true)
+                .open(&target)?
+                .set_modified(SystemTime::now())?;
+        } else {
+            let age = fs::metadata(&path)?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            if age > grace {
+                continue;
+            }
+            link_or_copy(&path, &target)?;
+        }
+        kept += 1;
+    }
+    Ok(kept)
+}
+
+/// Generate the Release file of a suite, listing every index in its dists directory
+pub fn release(dists_path: &Path, info: &ReleaseInfo, date: u64) -> io::Result<String> {
+    let mut release = String::new();
+    writeln!(release, "Origin: {}", info.origin).unwrap();
+    writeln!(release, "Label: {}", info.label).unwrap();
+    writeln!(release, "Suite: {}", info.suite).unwrap();
+    writeln!(release, "Version: {}", info.version).unwrap();
+    writeln!(release, "Codename: {}", info.codename).unwrap();
+    writeln!(release, "Date: {}", rfc2822(date)).unwrap();
+    if let Some(valid_for) = info.valid_for {
+        writeln!(
+            release,
+            "Valid-Until: {}",
+            rfc2822(date + valid_for.as_secs())
+        )
+        .unwrap();
+    }
+    writeln!(release, "Architectures: {}", info.archs.join(" ")).unwrap();
+    writeln!(release, "Components: {}", info.components.join(" ")).unwrap();
+    writeln!(release, "Description: {}", info.description).unwrap();
+    if !info.signed_by.is_empty() {
+        writeln!(release, "Signed-By: {}", info.signed_by.join(", ")).unwrap();
+    }
+    if info.acquire_by_hash {
+        writeln!(release, "Acquire-By-Hash: yes").unwrap();
+    }
+
+    let mut indexes = Vec::new();
+    for path in files(dists_path)? {
+        let name = relative_path(dists_path, &path)?;
+        if name == "Release" || name == "InRelease" || name == "Release.gpg" {
+            continue;
+        }
+        if name.split('/').any(|part| part == BY_HASH) {
+            continue;
+        }
+        indexes.push((name, Checksums::file(&path)?));
+    }
+
+    type HashFn = fn(&Checksums) -> &str;
+    let hashes: [(&str, HashFn); 4] = [
+        ("MD5Sum", |checksums| &checksums.md5),
+        ("SHA1", |checksums| &checksums.sha1),
+        ("SHA256", |checksums| &checksums.sha256),
+        ("SHA512", |checksums| &checksums.sha512),
+    ];
+    for (field, hash) in hashes.iter() {
+        writeln!(release, "{}:", field).unwrap();
+        for (name, checksums) in indexes.iter() {
+            writeln!(
+                release,
+                " {} {:>16} {}",
+                hash(checksums),
+                checksums.size,
+                name
+            )
+            .unwrap();
+        }
+    }
+    Ok(release)
+}
+
+/// Check if the Release file of a dists directory should be refreshed at now, because it
+/// expires within refresh_before or its validity does not match valid_for
+pub fn release_needs_refresh(
+    dists_path: &Path,
+    valid_for: Option<Duration>,
+    refresh_before: Duration,
+    now: u64,
+) -> io::Result<bool> {
+    let stanza = Stanza::parse(&fs::read_to_string(dists_path.join("Release"))?);
+    let date = stanza.get("Date").and_then(parse_rfc2822);
+    let valid_until = stanza.get("Valid-Until").and_then(parse_rfc2822);
+    let current_valid_for = match (date, valid_until) {
+        (Some(date), Some(valid_until)) => Some(valid_until.saturating_sub(date)),
+        _ => None,
+    };
+    Ok(
+        current_valid_for != valid_for.map(|valid_for| valid_for.as_secs())
+            || valid_until.is_some_and(|valid_until| valid_until <= now + refresh_before.as_secs()),
+    )
+}
+
+/// Update the Date and Valid-Until of the Release file of a dists directory, without
+/// regenerating any indexes. The Release file has to be signed again afterwards. It is replaced
+/// rather than modified, as it may be linked from snapshots.
+pub fn refresh_release(
+    dists_path: &Path,
+    date: u64,
+    valid_for: Option<Duration>,
+) -> io::Result<()> {
+    let path = dists_path.join("Release");
+    let mut stanza = Stanza::parse(&fs::read_to_string(&path)?);
+    stanza.set("Date", rfc2822(date));
+    match valid_for {
+        Some(valid_for) => stanza.insert_before(
+            "Architectures",
+            "Valid-Until",
+            rfc2822(date + valid_for.as_secs()),
+        ),
+        None => {
+            stanza.remove("Valid-Until");
+        }
+    }
+    let partial_path = dists_path.join("Release.partial");
+    fs::write(&partial_path, stanza.to_string())?;
+    fs::rename(partial_path, path)
+}
+
+/// How packages are routed into the components of a suite
+#[derive(Clone, Debug)]
+pub struct ComponentRules {
+    pub default: String,
+    /// Component of all packages built from a repository, by repository name
+    pub repos: BTreeMap<String, String>,
+    /// Component of binary packages by Section, or by the area of a Section like non-free/libs
+    pub sections: BTreeMap<String, String>,
+    /// Put debug symbol packages in a debug component below their component, like main/debug,
+    /// instead of in a single debug component
+    pub debug: bool,
+}
+
+impl Default for ComponentRules {
+    fn default() -> Self {
+        Self {
+            default: "main".to_string(),
+            repos: BTreeMap::new(),
+            sections: BTreeMap::new(),
+            debug: true,
+        }
+    }
+}
+
+impl ComponentRules {
+    /// Component of the source packages of a repository
+    pub fn source(&self, repo: &str) -> &str {
+        self.repos.get(repo).unwrap_or(&self.default)
+    }
+
+    /// Component of a binary package built from a repository
+    pub fn binary(&self, repo: &str, stanza: &Stanza) -> String {
+        let section = stanza.get("Section").unwrap_or_default();
+        let area = section.split_once('/').map_or(section, |(area, _)| area);
+        let component = self
+            .repos
+            .get(repo)
+            .or_else(|| self.sections.get(section))
+            .or_else(|| self.sections.get(area))
+            .unwrap_or(&self.default);
+
+        // Debug symbols are always kept out of the components of the packages themselves
+        let package = stanza.get("Package").unwrap_or_default();
+        let filename = stanza.get("Filename").unwrap_or_default();
+        if package.ends_with("-dbgsym") || section == "debug" || filename.ends_with(".ddeb") {
+            if self.debug {
+                format!("{}/debug", component)
+            } else {
+                "debug".to_string()
+            }
+        } else {
+            component.clone()
+        }
+    }
+}
+
+/// Generate the indexes and Release file of a suite in dists_path, from the packages found
+/// below pool_path, which has a directory for each repository. Files are listed relative to
+/// root, the root of the repository.
+pub fn generate_dists(
+    cache: &IndexCache,
+    root: &Path,
+    pool_path: &Path,
+    dists_path: &Path,
+    info: &ReleaseInfo,
+    rules: &ComponentRules,
+    date: u64,
+) -> io::Result<()> {
+    let mut info = info.clone();
+    let mut debs = Vec::new();
+    let mut sources = BTreeMap::<String, String>::new();
+    for path in files(pool_path)? {
+        let repo = relative_path(pool_path, &path)?
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if file_name.ends_with(".deb") || file_name.ends_with(".ddeb") {
+            let deb = cache.deb(root, &path)?;
+            debs.push((rules.binary(&repo, &deb.stanza), deb));
+        } else if file_name.ends_with(".dsc") {
+            let component_sources = sources.entry(rules.source(&repo).to_string()).or_default();
+            if !component_sources.is_empty() {
+                component_sources.push('\n');
+            }
+            write!(component_sources, "{}", source_stanza(root, &path)?).unwrap();
+        }
+    }
+
+    let mut components: BTreeSet<String> = info.components.iter().cloned().collect();
+    components.extend(sources.keys().cloned());
+    components.extend(debs.iter().map(|(component, _)| component.clone()));
+    info.components = components.into_iter().collect();
+
+    for component in info.components.iter() {
+        let component_dir = dists_path.join(component);
+
+        let source_dir = component_dir.join("source");
+        fs::create_dir_all(&source_dir)?;
+        write_compressed(
+            &source_dir.join("Sources"),
+            sources
+                .get(component)
+                .map_or(&[][..], |sources| sources.as_bytes()),
+        )?;
+        fs::write(
+            source_dir.join("Release"),
+            info.component_release(component, "source"),
+        )?;
+        if info.acquire_by_hash {
+            write_by_hash(&source_dir)?;
+        }
+
+        for arch in info.archs.iter() {
+            let mut packages = String::new();
+            let mut contents = BTreeMap::<&str, BTreeSet<String>>::new();
+            for (_, deb) in debs.iter().filter(|(deb_component, deb)| {
+                deb_component == component && (deb.arch() == arch || deb.arch() == "all")
+            }) {
+                if !packages.is_empty() {
+                    packages.push('\n');
+                }
+                write!(packages, "{}", deb.stanza).unwrap();
+                for path in deb.contents.iter() {
+                    contents
+                        .entry(path.as_str())
+                        .or_default()
+                        .insert(deb.location());
+                }
+            }
+
+            let binary_dir = component_dir.join(format!("binary-{}", arch));
+            fs::create_dir_all(&binary_dir)?;
+            write_compressed(&binary_dir.join("Packages"), packages.as_bytes())?;
+            fs::write(
+                binary_dir.join("Release"),
+                info.component_release(component, arch),
+            )?;
+            if info.acquire_by_hash {
+                write_by_hash(&binary_dir)?;
+            }
+
+            let mut contents_data = String::new();
+            for (path, locations) in contents.iter() {
+                let locations: Vec<_> = locations.iter().map(|x| x.as_str()).collect();
+                writeln!(contents_data, "{:<55} {}", path, locations.join(",")).unwrap();
+            }
+            write_compressed(
+                &component_dir.join(format!("Contents-{}", arch)),
+                contents_data.as_bytes(),
+            )?;
+        }
+        if info.acquire_by_hash {
+            write_by_hash(&component_dir)?;
+        }
+    }
+
+    fs::write(
+        dists_path.join("Release"),
+        release(dists_path, &info, date)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn component_rules_route_debug_symbols() {
+        let deb = |package: &str, section: &str, filename: &str| {
+            Stanza::parse(&format!(
+                "Package: {}\nSection: {}\nFilename: {}\n",
+                package, section, filename
+            ))
+        };
+        let mut rules = ComponentRules::default();
+        rules
+            .sections
+            .insert("non-free".to_string(), "non-free".to_string());
+
+        let libfoo = deb("libfoo", "libs", "pool/foo/libfoo_1_amd64.deb");
+        let dbgsym = deb(
+            "libfoo-dbgsym",
+            "libs",
+            "pool/foo/libfoo-dbgsym_1_amd64.ddeb",
+        );
+        let non_free = deb(
+            "bar-dbgsym",
+            "non-free/libs",
+            "pool/bar/bar-dbgsym_1_amd64.deb",
+        );
+        let ddeb = deb("baz", "libs", "pool/baz/baz_1_amd64.ddeb");
+
+        assert_eq!(rules.binary("foo", &libfoo), "main");
+        assert_eq!(rules.binary("foo", &dbgsym), "main/debug");
+        assert_eq!(rules.binary("bar", &non_free), "non-free/debug");
+        assert_eq!(rules.binary("baz", &ddeb), "main/debug");
+
+        // Without debug components, debug symbols are still kept apart
+        rules.debug = false;
+        assert_eq!(rules.binary("foo", &libfoo), "main");
+        assert_eq!(rules.binary("foo", &dbgsym), "debug");
+        assert_eq!(rules.binary("bar", &non_free), "debug");
+        assert_eq!(rules.binary("baz", &ddeb), "debug");
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pop-ci-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    const DSC: &str = "Format: 3.0 (native)
+Source: foo
+Binary: foo, foo-dbgsym
+Architecture: any
+Version: 1.0
+Maintainer: Foo <foo@example.com>
+Build-Depends: debhelper-compat (= 13),
+ libbar-dev
+Checksums-Sha256:
+ 0000000000000000000000000000000000000000000000000000000000000000 100 foo_1.0.tar.xz
+Files:
+ 00000000000000000000000000000000 100 foo_1.0.tar.xz
+";
+
+    fn release_info() -> ReleaseInfo {
+        ReleaseInfo {
+            origin: "pop-os-staging-master".to_string(),
+            label: "Pop!_OS Staging master".to_string(),
+            suite: "jammy".to_string(),
+            version: "22.04".to_string(),
+            codename: "jammy".to_string(),
+            description: "Pop!_OS Staging master jammy 22.04".to_string(),
+            components: vec!["main".to_string()],
+            archs: vec!["amd64".to_string(), "i386".to_string()],
+            acquire_by_hash: true,
+            signed_by: vec!["ABCD".to_string()],
+            valid_for: Some(Duration::from_secs(24 * 60 * 60)),
+        }
+    }
+
+    /// An uncompressed deb with a control file and a single file in its data
+    fn deb_data(package: &str, version: &str) -> Vec<u8> {
+        let tar = |path: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            let mut builder = tar::Builder::new(Vec::new());
+            builder.append_data(&mut header, path, data).unwrap();
+            builder.into_inner().unwrap()
+        };
+        let control = format!(
+            "Package: {}\nVersion: {}\nArchitecture: amd64\nSection: utils\nDescription: test\n",
+            package, version
+        );
+        let copyright = format!("./usr/share/doc/{}/copyright", package);
+
+        let mut data = b"!<arch>\n".to_vec();
+        for (name, member) in [
+            ("debian-binary", b"2.0\n".to_vec()),
+            ("control.tar", tar("./control", control.as_bytes())),
+            ("data.tar", tar(&copyright, version.as_bytes())),
+        ] {
+            data.extend_from_slice(
+                format!(
+                    "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                    name,
+                    0,
+                    0,
+                    0,
+                    644,
+                    member.len()
+                )
+                .as_bytes(),
+            );
+            data.extend_from_slice(&member);
+            if member.len() % 2 == 1 {
+                data.push(b'\n');
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn index_cache_reads_changed_debs() {
+        let root = temp_dir("index-cache");
+        let pool = root.join("pool").join("jammy");
+        let dists = root.join("dists").join("jammy");
+        let cache = IndexCache::new(root.join("index"));
+        let rules = ComponentRules::default();
+        let write_deb = |repo: &str, package: &str, version: &str| {
+            let dir = pool.join(repo).join("commit");
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join(format!("{}_{}_amd64.deb", package, version)),
+                deb_data(package, version),
+            )
+            .unwrap();
+        };
+
+        write_deb("foo", "foo", "1.0");
+        write_deb("bar", "bar", "1.0");
+        generate_dists(&cache, &root, &pool, &dists, &release_info(), &rules, 0).unwrap();
+        let entries = files(&root.join("index")).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        // Mark the cached entries, which only shows up in indexes if a deb is not read again
+        for entry_path in entries.iter() {
+            let mut deb = cache.load(entry_path).unwrap();
+            deb.stanza.set("X-Cached", "yes".to_string());
+            cache.store(entry_path, &deb).unwrap();
+        }
+
+        fs::remove_dir_all(pool.join("foo")).unwrap();
+        write_deb("foo", "foo", "2.0");
+        generate_dists(&cache, &root, &pool, &dists, &release_info(), &rules, 0).unwrap();
+        assert_eq!(files(&root.join("index")).unwrap().len(), 3);
+
+        let packages =
+            fs::read_to_string(dists.join("main").join("binary-amd64").join("Packages")).unwrap();
+        let stanzas: Vec<Stanza> = packages.split("\n\n").map(Stanza::parse).collect();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(stanzas.len(), 2);
+        assert_eq!(stanzas[0].get("Package"), Some("bar"));
+        assert_eq!(stanzas[0].get("X-Cached"), Some("yes"));
+        assert_eq!(
+            stanzas[0].get("Filename"),
+            Some("pool/jammy/bar/commit/bar_1.0_amd64.deb")
+        );
+        assert_eq!(stanzas[1].get("Package"), Some("foo"));
+        assert_eq!(stanzas[1].get("Version"), Some("2.0"));
+        assert_eq!(stanzas[1].get("X-Cached"), None);
+        assert_eq!(
+            stanzas[1].get("Filename"),
+            Some("pool/jammy/foo/commit/foo_2.0_amd64.deb")
+        );
+    }
+
+    #[test]
+    fn keep_by_hash_within_grace() {
+        let root = temp_dir("keep-by-hash");
+        let previous = root.join("previous");
+        let dists = root.join("dists");
+        let grace = Duration::from_secs(24 * 60 * 60);
+        let write_index = |dists: &Path, data: &str| {
+            let dir = dists.join("main").join("binary-amd64");
+            fs::create_dir_all(&dir).unwrap();
+            // Indexes are linked by hash, so they are replaced instead of written to
+            if dir.join("Packages").exists() {
+                fs::remove_file(dir.join("Packages")).unwrap();
+            }
+            fs::write(dir.join("Packages"), data).unwrap();
+            write_by_hash(&dir).unwrap();
+        };
+        let by_hash = |dists: &Path, data: &str| {
+            dists
+                .join("main")
+                .join("binary-amd64")
+                .join(BY_HASH)
+                .join("SHA256")
+                .join(sha256(data.as_bytes()))
+        };
+
+        // The previous generation still has an index replaced within the grace period, and one
+        // replaced long before it
+        write_index(&previous, "oldest");
+        write_index(&previous, "older");
+        write_index(&previous, "old");
+        for data in ["oldest", "old"] {
+            fs::File::options()
+                .write(true)
+                .open(by_hash(&previous, data))
+                .unwrap()
+                .set_modified(SystemTime::now() - 2 * grace)
+                .unwrap();
+        }
+        write_index(&dists, "new");
+
+        assert_eq!(keep_by_hash(&previous, &dists, grace).unwrap(), 2);
+        assert!(by_hash(&dists, "new").is_file());
+        assert!(by_hash(&dists, "old").is_file());
+        assert!(by_hash(&dists, "older").is_file());
+        assert!(!by_hash(&dists, "oldest").exists());
+
+        // The index replaced now starts its grace period, even if its file is older
+        let age = fs::metadata(by_hash(&dists, "old"))
+            .unwrap()
+            .modified()
+            .unwrap()
+            .elapsed()
+            .unwrap_or_default();
+        assert!(age < grace);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn stanza_round_trip() {
+        let data = "Package: foo
+Version: 1.0
+Description: short
+ long description
+ .
+ more
+Empty:
+Files:
+ abc 1 foo.dsc
+ def 2 foo.tar.xz
+";
+        let stanza = Stanza::parse(data);
+        assert_eq!(stanza.get("Version"), Some("1.0"));
+        assert_eq!(
+            stanza.get("Description"),
+            Some("short\n long description\n .\n more")
+        );
+        assert_eq!(stanza.get("Empty"), Some(""));
+        assert_eq!(
+            stanza.get("Files"),
+            Some("\n abc 1 foo.dsc\n def 2 foo.tar.xz")
+        );
+        assert_eq!(stanza.to_string(), data);
+
+        // Only the first paragraph is parsed
+        let stanza = Stanza::parse(&format!("\n{}\nPackage: bar\n", data));
+        assert_eq!(stanza.get("Package"), Some("foo"));
+        assert_eq!(stanza.to_string(), data);
+    }
+
+    #[test]
+    fn stanza_parse_clearsigned() {
+        let signed = format!(
+            "-----BEGIN PGP SIGNED MESSAGE-----
+Hash: SHA512
+
+{}
+-----BEGIN PGP SIGNATURE-----
+
+iQIzBAEBCgAdFiEE
+=abcd
+-----END PGP SIGNATURE-----
+",
+            DSC
+        );
+        let stanza = Stanza::parse(&signed);
+        assert_eq!(stanza.get("Hash"), None);
+        assert_eq!(stanza.get("Source"), Some("foo"));
+        assert_eq!(stanza.to_string(), DSC);
+    }
+
+    #[test]
+    fn source_stanza_lists_dsc() {
+        let root = temp_dir("source-stanza");
+        let dsc_dir = root.join("pool").join("jammy").join("foo");
+        fs::create_dir_all(&dsc_dir).unwrap();
+        let dsc_path = dsc_dir.join("foo_1.0.dsc");
+        fs::write(&dsc_path, DSC).unwrap();
+        let checksums = Checksums::new(DSC.as_bytes());
+
+        let stanza = source_stanza(&root, &dsc_path).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(stanza.0[0], ("Package".to_string(), "foo".to_string()));
+        assert_eq!(stanza.get("Source"), None);
+        assert_eq!(stanza.get("Directory"), Some("pool/jammy/foo"));
+        assert_eq!(
+            stanza.get("Files").unwrap(),
+            format!(
+                "\n {} {} foo_1.0.dsc\n 00000000000000000000000000000000 100 foo_1.0.tar.xz",
+                checksums.md5, checksums.size
+            )
+        );
+        assert_eq!(
+            stanza.get("Checksums-Sha256").unwrap(),
+            format!(
+                "\n {} {} foo_1.0.dsc\n {} 100 foo_1.0.tar.xz",
+                checksums.sha256,
+                checksums.size,
+                "0".repeat(64)
+            )
+        );
+        // Fields missing from the dsc are not added
+        assert_eq!(stanza.get("Checksums-Sha1"), None);
+    }
+
+    #[test]
+    fn release_lists_indexes() {
+        let dists = temp_dir("release");
+        let binary_dir = dists.join("main").join("binary-amd64");
+        fs::create_dir_all(binary_dir.join(BY_HASH).join("SHA256")).unwrap();
+        fs::write(binary_dir.join("Packages"), "Package: foo\n").unwrap();
+        fs::write(binary_dir.join(BY_HASH).join("SHA256").join("abc"), "old").unwrap();
+        fs::write(dists.join("Release"), "old release").unwrap();
+        fs::write(dists.join("InRelease"), "old release").unwrap();
+
+        let release = release(&dists, &release_info(), 0).unwrap();
+        fs::remove_dir_all(&dists).unwrap();
+
+        let checksums = Checksums::new(b"Package: foo\n");
+        let stanza = Stanza::parse(&release);
+        assert_eq!(stanza.get("Suite"), Some("jammy"));
+        assert_eq!(stanza.get("Date"), Some("Thu, 01 Jan 1970 00:00:00 UTC"));
+        assert_eq!(
+            stanza.get("Valid-Until"),
+            Some("Fri, 02 Jan 1970 00:00:00 UTC")
+        );
+        assert_eq!(stanza.get("Architectures"), Some("amd64 i386"));
+        assert_eq!(stanza.get("Components"), Some("main"));
+        assert_eq!(stanza.get("Signed-By"), Some("ABCD"));
+        assert_eq!(stanza.get("Acquire-By-Hash"), Some("yes"));
+        // Only the index is listed, not the Release files or by-hash copies
+        assert_eq!(
+            stanza.get("SHA256").unwrap(),
+            format!(
+                "\n {} {:>16} main/binary-amd64/Packages",
+                checksums.sha256, checksums.size
+            )
+        );
+        assert_eq!(
+            stanza.get("MD5Sum").unwrap(),
+            format!(
+                "\n {} {:>16} main/binary-amd64/Packages",
+                checksums.md5, checksums.size
+            )
+        );
+    }
+}
@@ -1,5 +1,26 @@
+pub mod builder;
 pub mod cache;
+pub mod changelog;
+pub mod chroot;
 pub mod config;
+pub mod control;
+pub mod deb;
+pub mod debuginfod;
 pub mod git;
+pub mod graph;
+pub mod index;
+pub mod lifecycle;
+pub mod lintian;
+pub mod offline;
+pub mod pool;
+pub mod provenance;
+pub mod publish;
+pub mod qa;
 pub mod repo;
+pub mod report;
+pub mod sbom;
+pub mod serve;
+pub mod signing;
+pub mod snapshot;
 pub mod util;
+pub mod verify;
@@ -0,0 +1,364 @@
+use json::JsonValue;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::config::Config;
+
+/// What is known about a pocket across runs
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PocketState {
+    /// When the packages of the pocket last changed
+    pub updated: u64,
+    /// When the pocket last had packages
+    pub seen: u64,
+    /// When the pocket stopped having packages, usually because its branches were deleted
+    pub absent_since: Option<u64>,
+    /// When the pocket was archived and stopped being published
+    pub archived: Option<u64>,
+    /// Suite, repo and commit of each package, to detect changes
+    pub commits: Vec<String>,
+    /// Snapshot the pocket was rolled back to. Runs leave the pocket as it is until the rollback
+    /// is cleared or its commits change.
+    pub pinned: Option<String>,
+}
+
+impl PocketState {
+    fn from_json(value: &JsonValue) -> Self {
+        Self {
+            updated: value["updated"].as_u64().unwrap_or(0),
+            seen: value["seen"].as_u64().unwrap_or(0),
+            absent_since: value["absent_since"].as_u64(),
+            archived: value["archived"].as_u64(),
+            commits: value["commits"]
+                .members()
+                .filter_map(|commit| commit.as_str().map(|commit| commit.to_string()))
+                .collect(),
+            pinned: value["pinned"].as_str().map(|pinned| pinned.to_string()),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut value = JsonValue::new_object();
+        value["updated"] = self.updated.into();
+        value["seen"] = self.seen.into();
+        value["absent_since"] = self.absent_since.into();
+        value["archived"] = self.archived.into();
+        value["commits"] = self.commits.clone().into();
+        value["pinned"] = self.pinned.clone().into();
+        value
+    }
+
+    pub fn status(&self) -> &'static str {
+        if self.archived.is_some() {
+            "archived"
+        } else if self.absent_since.is_some() {
+            "grace"
+        } else {
+            "active"
+        }
+    }
+}
+
+/// Tracks when pockets change, so that stale pockets expire and pockets of deleted branches
+/// stay published for a grace period before they are archived
+pub struct PocketLifecycle {
+    path: PathBuf,
+    pockets: BTreeMap<String, PocketState>,
+}
+
+impl PocketLifecycle {
+    /// Load the state of pockets, or start without any if it does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut pockets = BTreeMap::new();
+        match fs::read_to_string(&path) {
+            Ok(data) => {
+                let value = json::parse(&data)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                for (name, state) in value.entries() {
+                    pockets.insert(name.to_string(), PocketState::from_json(state));
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        Ok(Self { path, pockets })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut value = JsonValue::new_object();
+        for (name, state) in self.pockets.iter() {
+            value[name.as_str()] = state.to_json();
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial_path = self.path.with_extension("partial");
+        fs::write(&partial_path, value.pretty(2))?;
+        fs::rename(partial_path, &self.path)
+    }
+
+    pub fn pockets(&self) -> &BTreeMap<String, PocketState> {
+        &self.pockets
+    }
+
+    /// Start tracking a pocket that already exists, as if it was just updated
+    pub fn track(&mut self, pocket: &str, now: u64) {
+        self.pockets
+            .entry(pocket.to_string())
+            .or_insert_with(|| PocketState {
+                updated: now,
+                seen: now,
+                ..PocketState::default()
+            });
+    }
+
+    /// Keep a pocket at a snapshot it was rolled back to, until its commits change
+    pub fn pin(&mut self, pocket: &str, snapshot: &str, now: u64) {
+        self.track(pocket, now);
+        if let Some(state) = self.pockets.get_mut(pocket) {
+            state.pinned = Some(snapshot.to_string());
+        }
+    }
+
+    /// Let runs update a rolled back pocket again. Returns the snapshot it was pinned to.
+    pub fn unpin(&mut self, pocket: &str) -> Option<String> {
+        self.pockets
+            .get_mut(pocket)
+            .and_then(|state| state.pinned.take())
+    }
+
+    /// The snapshot a pocket is pinned to, if it was rolled back
+    pub fn pinned(&self, pocket: &str) -> Option<&str> {
+        self.pockets
+            .get(pocket)
+            .and_then(|state| state.pinned.as_deref())
+    }
+
+    /// Pockets that are published, including pockets in their grace period
+    pub fn is_published(&self, pocket: &str) -> bool {
+        self.pockets
+            .get(pocket)
+            .is_some_and(|state| state.archived.is_none())
+    }
+
+    /// Update pockets from the commits of the pockets that have packages in this run. Returns
+    /// the pockets that expired or ended their grace period, and have to be archived.
+    pub fn update(
+        &mut self,
+        present: &BTreeMap<String, Vec<String>>,
+        now: u64,
+        config: &Config,
+    ) -> Vec<String> {
+        let mut archive = Vec::new();
+
+        for (pocket, commits) in present.iter() {
+            let state = self
+                .pockets
+                .entry(pocket.clone())
+                .or_insert_with(|| PocketState {
+                    updated: now,
+                    ..PocketState::default()
+                });
+            if &state.commits != commits {
+                // Changed pockets are published again, even if they had expired, and newer
+                // commits replace a rollback
+                state.updated = now;
+                state.commits = commits.clone();
+                state.archived = None;
+                state.pinned = None;
+            }
+            state.seen = now;
+            state.absent_since = None;
+
+            let expired = config.pocket_expires(pocket)
+                && config.lifecycle.expire_after.is_some_and(|expire_after| {
+                    now >= state.updated.saturating_add(expire_after.as_secs())
+                });
+            if expired && state.archived.is_none() {
+                state.archived = Some(now);
+                archive.push(pocket.clone());
+            }
+        }
+
+        for (pocket, state) in self.pockets.iter_mut() {
+            if present.contains_key(pocket) {
+                continue;
+            }
+            let absent_since = *state.absent_since.get_or_insert(now);
+            if state.archived.is_none()
+                && now >= absent_since.saturating_add(config.lifecycle.grace.as_secs())
+            {
+                state.archived = Some(now);
+                archive.push(pocket.clone());
+            }
+        }
+
+        archive
+    }
+
+    /// Forget pockets that have been archived for longer than archive_for. Returns the pockets
+    /// whose archives have to be removed.
+    pub fn prune(&mut self, now: u64, config: &Config) -> Vec<String> {
+        let archive_for = config.lifecycle.archive_for.as_secs();
+        let mut removed = Vec::new();
+        self.pockets.retain(|pocket, state| {
+            let keep = state
+                .archived
+                .is_none_or(|archived| now < archived.saturating_add(archive_for));
+            if !keep {
+                removed.push(pocket.clone());
+            }
+            keep
+        });
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn lifecycle() -> PocketLifecycle {
+        PocketLifecycle {
+            path: PathBuf::new(),
+            pockets: BTreeMap::new(),
+        }
+    }
+
+    fn present(pockets: &[(&str, &str)]) -> BTreeMap<String, Vec<String>> {
+        pockets
+            .iter()
+            .map(|(pocket, commit)| (pocket.to_string(), vec![commit.to_string()]))
+            .collect()
+    }
+
+    #[test]
+    fn expire_unchanged_pockets() {
+        let mut config = Config::default();
+        config.lifecycle.expire_after = Some(Duration::from_secs(10 * DAY));
+        let mut lifecycle = lifecycle();
+        let pockets = present(&[("master", "jammy/foo/a"), ("feature", "jammy/bar/a")]);
+        assert!(lifecycle.update(&pockets, 0, &config).is_empty());
+
+        // Running again without changes does not reset the expiry
+        assert!(lifecycle.update(&pockets, 9 * DAY, &config).is_empty());
+        assert!(lifecycle.is_published("feature"));
+
+        // The master pocket never expires
+        assert_eq!(
+            lifecycle.update(&pockets, 10 * DAY, &config),
+            vec!["feature"]
+        );
+        assert!(!lifecycle.is_published("feature"));
+        assert!(lifecycle.is_published("master"));
+        assert_eq!(lifecycle.pockets()["feature"].status(), "archived");
+
+        // Archived pockets are only archived once
+        assert!(lifecycle.update(&pockets, 11 * DAY, &config).is_empty());
+    }
+
+    #[test]
+    fn absent_pockets_kept_for_grace() {
+        let config = Config::default();
+        let grace = config.lifecycle.grace.as_secs();
+        let mut lifecycle = lifecycle();
+        lifecycle.update(&present(&[("feature", "jammy/foo/a")]), 0, &config);
+
+        let none = BTreeMap::new();
+        assert!(lifecycle.update(&none, DAY, &config).is_empty());
+        assert_eq!(lifecycle.pockets()["feature"].absent_since, Some(DAY));
+        assert_eq!(lifecycle.pockets()["feature"].status(), "grace");
+        assert!(lifecycle.is_published("feature"));
+
+        assert!(lifecycle.update(&none, DAY + grace - 1, &config).is_empty());
+        assert_eq!(
+            lifecycle.update(&none, DAY + grace, &config),
+            vec!["feature"]
+        );
+        assert!(!lifecycle.is_published("feature"));
+
+        // A pocket that comes back within its grace period is not archived
+        lifecycle.update(&present(&[("other", "jammy/foo/a")]), 0, &config);
+        lifecycle.update(&none, DAY, &config);
+        lifecycle.update(&present(&[("other", "jammy/foo/a")]), 2 * DAY, &config);
+        assert_eq!(lifecycle.pockets()["other"].absent_since, None);
+        assert_eq!(lifecycle.pockets()["other"].status(), "active");
+    }
+
+    #[test]
+    fn new_commits_publish_archived_pockets() {
+        let mut config = Config::default();
+        config.lifecycle.expire_after = Some(Duration::from_secs(DAY));
+        let mut lifecycle = lifecycle();
+        let old = present(&[("feature", "jammy/foo/a")]);
+        lifecycle.update(&old, 0, &config);
+        assert_eq!(lifecycle.update(&old, DAY, &config), vec!["feature"]);
+
+        let new = present(&[("feature", "jammy/foo/b")]);
+        assert!(lifecycle.update(&new, 2 * DAY, &config).is_empty());
+        assert!(lifecycle.is_published("feature"));
+        assert_eq!(lifecycle.pockets()["feature"].updated, 2 * DAY);
+    }
+
+    #[test]
+    fn prune_after_archive_for() {
+        let config = Config::default();
+        let grace = config.lifecycle.grace.as_secs();
+        let archive_for = config.lifecycle.archive_for.as_secs();
+        let mut lifecycle = lifecycle();
+        lifecycle.update(
+            &present(&[("master", "jammy/foo/a"), ("feature", "jammy/bar/a")]),
+            0,
+            &config,
+        );
+        let master = present(&[("master", "jammy/foo/a")]);
+        lifecycle.update(&master, DAY, &config);
+        let archived = DAY + grace;
+        assert_eq!(
+            lifecycle.update(&master, archived, &config),
+            vec!["feature"]
+        );
+
+        assert!(lifecycle
+            .prune(archived + archive_for - 1, &config)
+            .is_empty());
+        assert!(lifecycle.pockets().contains_key("feature"));
+        assert_eq!(
+            lifecycle.prune(archived + archive_for, &config),
+            vec!["feature"]
+        );
+        assert!(!lifecycle.pockets().contains_key("feature"));
+        assert!(lifecycle.pockets().contains_key("master"));
+    }
+
+    #[test]
+    fn pin_kept_until_commits_change() {
+        let config = Config::default();
+        let mut lifecycle = lifecycle();
+        let mut present = BTreeMap::new();
+        present.insert("master".to_string(), vec!["jammy/foo/a".to_string()]);
+        lifecycle.update(&present, 1, &config);
+
+        lifecycle.pin("master", "20250101T000000Z", 2);
+        lifecycle.update(&present, 3, &config);
+        assert_eq!(lifecycle.pinned("master"), Some("20250101T000000Z"));
+
+        present.insert("master".to_string(), vec!["jammy/foo/b".to_string()]);
+        lifecycle.update(&present, 4, &config);
+        assert_eq!(lifecycle.pinned("master"), None);
+
+        lifecycle.pin("master", "20250101T000000Z", 5);
+        assert_eq!(
+            lifecycle.unpin("master"),
+            Some("20250101T000000Z".to_string())
+        );
+        assert_eq!(lifecycle.pinned("master"), None);
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::BTreeSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    git::GitCommit,
+    repo::{Arch, Pocket, Suite},
+};
+
+/// A single tag emitted by lintian
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct LintianTag {
+    /// E for error, W for warning, I for info, P for pedantic, X for experimental
+    pub severity: char,
+    pub package: String,
+    pub tag: String,
+    pub info: String,
+}
+
+impl LintianTag {
+    /// Parse a tag line, like "E: package: tag-name some info"
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut chars = line.chars();
+        let severity = chars.next()?;
+        if !"EWIPX".contains(severity) || chars.next()? != ':' || chars.next()? != ' ' {
+            return None;
+        }
+
+        let (package, rest) = line[3..].split_once(": ")?;
+        let (tag, info) = match rest.split_once(' ') {
+            Some((tag, info)) => (tag, info.trim()),
+            None => (rest.trim(), ""),
+        };
+        if tag.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            severity,
+            package: package.trim().to_string(),
+            tag: tag.to_string(),
+            info: info.to_string(),
+        })
+    }
+
+    /// What identifies a tag across builds. The info is left out, as it often has paths or
+    /// versions that change between builds.
+    pub fn key(&self) -> (char, &str, &str) {
+        (self.severity, &self.package, &self.tag)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == 'E'
+    }
+}
+
+impl fmt::Display for LintianTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.package, self.tag)?;
+        if !self.info.is_empty() {
+            write!(f, " {}", self.info)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse all tags from lintian output, ignoring notes and overridden tags
+pub fn parse_tags(output: &str) -> BTreeSet<LintianTag> {
+    output.lines().filter_map(LintianTag::parse).collect()
+}
+
+pub fn read_tags<P: AsRef<Path>>(path: P) -> io::Result<BTreeSet<LintianTag>> {
+    Ok(parse_tags(&fs::read_to_string(path)?))
+}
+
+pub fn write_tags<P: AsRef<Path>>(path: P, tags: &BTreeSet<LintianTag>) -> io::Result<()> {
+    let mut data = String::new();
+    for tag in tags.iter() {
+        data.push_str(&tag.to_string());
+        data.push('\n');
+    }
+    fs::write(path, data)
+}
+
+/// Tags from the previous lintian run of each repo in a pocket, used to find new tags
+pub struct LintianHistory {
+    path: PathBuf,
+}
+
+impl LintianHistory {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn entry_path(&self, pocket: &Pocket, suite: &Suite, repo_name: &str, arch: &Arch) -> PathBuf {
+        self.path
+            .join(pocket.id())
+            .join(suite.id())
+            .join(format!("{}_{}", repo_name, arch.id()))
+    }
+
+    /// The commit and tags of the previous lintian run in a pocket
+    pub fn previous(
+        &self,
+        pocket: &Pocket,
+        suite: &Suite,
+        repo_name: &str,
+        arch: &Arch,
+    ) -> Option<(GitCommit, BTreeSet<LintianTag>)> {
+        let data = fs::read_to_string(self.entry_path(pocket, suite, repo_name, arch)).ok()?;
+        let (commit_id, tags) = data.split_once('\n').unwrap_or((&data, ""));
+        Some((GitCommit::new(commit_id.trim()), parse_tags(tags)))
+    }
+
+    pub fn record(
+        &self,
+        pocket: &Pocket,
+        suite: &Suite,
+        repo_name: &str,
+        arch: &Arch,
+        commit: &GitCommit,
+        tags: &BTreeSet<LintianTag>,
+    ) -> io::Result<()> {
+        let path = self.entry_path(pocket, suite, repo_name, arch);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut data = format!("{}\n", commit.id());
+        for tag in tags.iter() {
+            data.push_str(&tag.to_string());
+            data.push('\n');
+        }
+        let partial_path = path.with_file_name(format!(
+            "partial.{}",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+        fs::write(&partial_path, data)?;
+        fs::rename(partial_path, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::RepoInfo;
+
+    #[test]
+    fn parse_tag_lines() {
+        let tag = LintianTag::parse("W: foo: some-tag usr/bin/foo (line 3)").unwrap();
+        assert_eq!(tag.severity, 'W');
+        assert_eq!(tag.package, "foo");
+        assert_eq!(tag.tag, "some-tag");
+        assert_eq!(tag.info, "usr/bin/foo (line 3)");
+        assert_eq!(tag.to_string(), "W: foo: some-tag usr/bin/foo (line 3)");
+
+        let tag = LintianTag::parse("E: foo source: source-tag").unwrap();
+        assert!(tag.is_error());
+        assert_eq!(tag.package, "foo source");
+        assert_eq!(tag.tag, "source-tag");
+        assert_eq!(tag.info, "");
+        assert_eq!(tag.to_string(), "E: foo source: source-tag");
+
+        assert_eq!(LintianTag::parse("W: foo: "), None);
+        assert_eq!(LintianTag::parse("W:foo: tag"), None);
+    }
+
+    #[test]
+    fn parse_tags_skips_notes_and_overrides() {
+        let tags = parse_tags(
+            "N: Processing binary package foo
+N:
+O: foo: overridden-tag info
+W: foo: some-tag
+I: foo: info-tag
+",
+        );
+        let names: Vec<&str> = tags.iter().map(|tag| tag.tag.as_str()).collect();
+        assert_eq!(names, vec!["info-tag", "some-tag"]);
+    }
+
+    #[test]
+    fn key_ignores_info() {
+        let old = LintianTag::parse("W: foo: some-tag usr/lib/foo/1.0/bar").unwrap();
+        let new = LintianTag::parse("W: foo: some-tag usr/lib/foo/1.1/bar").unwrap();
+        assert_ne!(old, new);
+        assert_eq!(old.key(), new.key());
+        let other = LintianTag::parse("E: foo: some-tag usr/lib/foo/1.1/bar").unwrap();
+        assert_ne!(old.key(), other.key());
+    }
+
+    #[test]
+    fn history_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("pop-ci-lintian-history-{}", std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        let history = LintianHistory::new(&path);
+        let pocket = Pocket::new("master");
+        let suite = Suite::new("jammy").unwrap();
+        let arch = &RepoInfo::archs(&suite, false)[0];
+        assert_eq!(history.previous(&pocket, &suite, "foo", arch), None);
+
+        let commit = GitCommit::new("0123456789abcdef");
+        let tags = parse_tags("E: foo source: source-tag\nW: foo: some-tag some info\n");
+        history
+            .record(&pocket, &suite, "foo", arch, &commit, &tags)
+            .unwrap();
+        assert_eq!(
+            history.previous(&pocket, &suite, "foo", arch),
+            Some((commit.clone(), tags))
+        );
+        assert_eq!(
+            history.previous(&Pocket::new("other"), &suite, "foo", arch),
+            None
+        );
+
+        // Recording again replaces the previous run
+        let commit = GitCommit::new("fedcba9876543210");
+        history
+            .record(&pocket, &suite, "foo", arch, &commit, &BTreeSet::new())
+            .unwrap();
+        assert_eq!(
+            history.previous(&pocket, &suite, "foo", arch),
+            Some((commit, BTreeSet::new()))
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,10 +1,30 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use pop_ci::{
+    builder::{self, BuildJob, OfflineRepos},
     cache::Cache,
-    config::{DEV_ONLY_REPOS, DEV_REPOS},
+    changelog::{self, Change, Changelog},
+    chroot::{ChrootCommand, Chroots},
+    config::{BuilderConfig, Config, DEV_ONLY_REPOS, DEV_REPOS},
+    control::Control,
+    debuginfod,
     git::{GitBranch, GitCommit, GitRemote, GitRepo},
+    graph,
+    index::{self, ComponentRules, IndexCache, ReleaseInfo},
+    lifecycle::PocketLifecycle,
+    lintian::{self, LintianHistory, LintianTag},
+    offline::{self, OfflineMirror},
+    pool::BuilderPool,
+    provenance::{self, BuildOrigin, Provenance},
+    publish,
+    qa::{QaResult, QaStage},
     repo::{Arch, Package, Pocket, RepoInfo, Suite, SuiteDistro},
-    util::{check_output, check_status},
+    report::Report,
+    sbom::Sbom,
+    serve::Server,
+    signing::Signer,
+    snapshot,
+    util::{self, check_output, check_status},
+    verify::{self, Reproducibility},
 };
 use std::{
     collections::{BTreeMap, BTreeSet},
@@ -14,16 +34,17 @@ use std::{
     path::{Path, PathBuf},
     process, str,
     sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+static DEFAULT_CONFIG: &str = "scripts/pop-ci.json";
+
 macro_rules! bold {
     ($arg:tt) => {
         concat!("\x1B[1m", $arg, "\x1B[0m")
     };
 }
 
-static ARM64_RSYNC: Mutex<()> = Mutex::new(());
-
 //TODO: limit jobs?
 async fn async_fetch_repos(repos: &BTreeMap<String, PathBuf>, remote: &GitRemote) {
     use futures::stream::StreamExt;
@@ -88,11 +109,15 @@ fn github_status_inner(
         .and_then(check_status)
 }
 
+type SuitePackages = BTreeMap<Suite, BTreeMap<String, (GitCommit, Package)>>;
+
 #[derive(Default)]
 struct CiContext {
     logs: BTreeMap<String, (PathBuf, bool)>,
     pocket_logs: BTreeMap<Pocket, BTreeMap<String, (PathBuf, bool)>>,
-    pocket_packages: BTreeMap<Pocket, BTreeMap<Suite, BTreeMap<String, (GitCommit, Package)>>>,
+    pocket_packages: BTreeMap<Pocket, SuitePackages>,
+    /// Repos per pocket and suite that keep their published commit because a gating QA stage failed
+    gated_packages: BTreeMap<Pocket, BTreeMap<Suite, BTreeSet<String>>>,
 }
 
 #[derive(Default)]
@@ -107,103 +132,1525 @@ struct RepoContext {
     builds: BTreeMap<GitCommit, RepoBuild>,
 }
 
+/// State shared by all suite builds in a run
+struct RunContext<'a> {
+    chroots: &'a Chroots,
+    config: &'a Config,
+    lintian_history: LintianHistory,
+    local_repo_opt: Option<&'a LocalRepo>,
+    offline_opt: Option<OfflineRepos<'a>>,
+    pool: &'a BuilderPool,
+    /// Signs provenance of builds
+    signer: &'a Signer,
+}
+
 #[derive(Clone)]
 struct BinaryContext<'a> {
     arch: Arch,
-    arm64_opt: Option<&'a str>,
+    chroots: &'a Chroots,
     dsc_path: PathBuf,
-    repo_info: RepoInfo,
+    extra_debs: Vec<PathBuf>,
+    local_repo_opt: Option<&'a LocalRepo>,
+    offline_opt: Option<OfflineRepos<'a>>,
+    origin: BuildOrigin,
+    pockets: BTreeSet<Pocket>,
+    pool: &'a BuilderPool,
+    dev: bool,
+    signer: &'a Signer,
     source: PathBuf,
     suite: Suite,
 }
 
-fn binary_build<'a>(ctx: &BinaryContext<'a>, path: &Path) -> io::Result<()> {
+fn binary_build(ctx: &BinaryContext<'_>, path: &Path) -> io::Result<()> {
     fs::create_dir(path)?;
 
-    let script = format!(
-        r#"#!/usr/bin/env bash
-
-set -e
-
-mkdir -p '{path}'
-cd '{path}'
-sbuild \
-    '--quiet' \
-    '{arch_all}' \
-    '--arch={arch}' \
-    '--dist={suite}' \
-    '--extra-repository=deb {ubuntu_mirror} {suite}-updates main restricted universe multiverse' \
-    '--extra-repository=deb {ubuntu_mirror} {suite}-security main restricted universe multiverse' \
-    '--extra-repository=deb {release} {suite} main' \
-    '--extra-repository=deb {staging} {suite} main' \
-    '--extra-repository-key={key}' \
-    '--no-apt-distupgrade' \
-    '--no-run-autopkgtest' \
-    '--no-run-lintian' \
-    '--no-run-piuparts' \
-    '{dsc}'
-"#,
-        arch_all = if ctx.arch.build_all() {
-            "--arch-all"
+    // Freshly built dependencies are made available to the builder as extra packages
+    let extra_dir = path.with_file_name(format!("extra.{}", ctx.arch.id()));
+    if extra_dir.is_dir() {
+        fs::remove_dir_all(&extra_dir)?;
+    }
+    if !ctx.extra_debs.is_empty() {
+        fs::create_dir(&extra_dir)?;
+        for deb_path in ctx.extra_debs.iter() {
+            let deb_name = deb_path.file_name().unwrap();
+            if fs::hard_link(deb_path, extra_dir.join(deb_name)).is_err() {
+                fs::copy(deb_path, extra_dir.join(deb_name))?;
+            }
+        }
+        // Builders use the extra packages as a flat repository
+        index::write_flat_packages(&extra_dir)?;
+    }
+
+    let slot = ctx.pool.acquire(&ctx.suite, &ctx.arch)?;
+    let host = slot.host();
+    eprintln!(
+        "{}: building {} on {}",
+        ctx.dsc_path.file_name().unwrap().to_string_lossy(),
+        ctx.arch.id(),
+        host.name()
+    );
+
+    let dsc_path = host.map_path(&ctx.dsc_path);
+    let remote_path = host.map_path(path);
+    // Hosts may override the mirrors and repositories used
+    let repo_info = RepoInfo::new(&ctx.suite, ctx.dev, &host.mirrors)?;
+    // Remote hosts are given a copy of the key at the path its absolute path maps to
+    let key_path = &repo_info.key;
+    let key = host.map_path(key_path);
+    let remote_extra_dir = host.map_path(&extra_dir);
+    let ubuntu_mirror = host
+        .mirrors
+        .ubuntu(ctx.suite.id(), ctx.arch.id())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no ubuntu mirror for {} {} on {}",
+                    ctx.suite.id(),
+                    ctx.arch.id(),
+                    host.name()
+                ),
+            )
+        })?;
+    let repositories = vec![
+        format!(
+            "deb {} {}-updates main restricted universe multiverse",
+            ubuntu_mirror,
+            ctx.suite.id()
+        ),
+        format!(
+            "deb {} {}-security main restricted universe multiverse",
+            ubuntu_mirror,
+            ctx.suite.id()
+        ),
+        format!("deb {} {} main", repo_info.release, ctx.suite.id()),
+        format!("deb {} {} main", repo_info.staging, ctx.suite.id()),
+    ];
+    if let Some(offline) = ctx.offline_opt {
+        // Builders without any network read the local copy from disk
+        if host.is_remote() && host.backend().mounted_mirror_url().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "offline {} builds need the offline mirror, which {} does not have",
+                    host.backend().name(),
+                    host.name()
+                ),
+            ));
+        }
+        let urls = repositories
+            .iter()
+            .filter_map(|repository| repository.split(' ').nth(1))
+            .chain(Some(ubuntu_mirror));
+        for url in urls {
+            if !offline::is_local_url(url, &[offline.url]) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("offline build refuses non-local repository {}", url),
+                ));
+            }
+        }
+    }
+    // Pockets generated by previous runs are trusted local repositories. Builds that can not
+    // reach the local server have them mounted, or use the URL configured for remote builders.
+    let mut local_repo_dir = None;
+    let trusted_repositories = match ctx.local_repo_opt {
+        Some(local_repo) => match host.backend().mounted_repo_url() {
+            Some(url) if !host.is_remote() => {
+                local_repo_dir = Some(local_repo.path.as_path());
+                local_repo.sources(&ctx.pockets, &ctx.suite, url)
+            }
+            _ if local_repo.remote || !host.is_remote() => {
+                local_repo.sources(&ctx.pockets, &ctx.suite, &local_repo.url)
+            }
+            _ => {
+                eprintln!(
+                    "{}: {} can not reach local repositories without --local-repo-url",
+                    ctx.dsc_path.file_name().unwrap().to_string_lossy(),
+                    host.name()
+                );
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    };
+    let script = host.backend().build_script(&BuildJob {
+        arch: &ctx.arch,
+        suite: &ctx.suite,
+        dsc: &dsc_path,
+        path: &remote_path,
+        mirror: ubuntu_mirror,
+        repositories,
+        key: &key,
+        trusted_repositories,
+        local_repo_dir,
+        extra_dir: if ctx.extra_debs.is_empty() {
+            None
         } else {
-            "--no-arch-all"
+            Some(&remote_extra_dir)
         },
-        arch = ctx.arch.id(),
-        suite = ctx.suite.id(),
-        ubuntu_mirror = ctx.arch.ubuntu_mirror(ctx.suite.id()),
-        release = ctx.repo_info.release,
-        staging = ctx.repo_info.staging,
-        key = ctx.repo_info.key.display(),
-        path = path.display(),
-        dsc = ctx.dsc_path.display()
+        offline: ctx.offline_opt,
+    });
+
+    let res = if host.is_remote() {
+        // Source is shared between architectures which may build on this host at the same time
+        slot.push(&ctx.source)?;
+        slot.push_file(key_path)?;
+        if extra_dir.is_dir() {
+            slot.push(&extra_dir)?;
+        }
+
+        let res = host.command(&script).status().and_then(check_status);
+
+        slot.pull(path)?;
+
+        res
+    } else {
+        host.command(&script).status().and_then(check_status)
+    };
+
+    if extra_dir.is_dir() {
+        fs::remove_dir_all(&extra_dir)?;
+    }
+
+    res?;
+
+    let manifest_path = ctx.chroots.manifest_path(host, &ctx.suite, &ctx.arch);
+    let build_name = format!(
+        "{}_{}_{}_{}",
+        ctx.origin.repo,
+        ctx.origin.commit,
+        ctx.suite.id(),
+        ctx.arch.id()
     );
 
-    if ctx.arch.is_arm() {
-        let arm64 = ctx.arm64_opt.unwrap(); // checked above
+    let sbom = Sbom::new(
+        &ctx.dsc_path,
+        &manifest_path,
+        path,
+        format!(
+            "urn:pop-ci:spdx:{}:{}:{}:{}",
+            ctx.origin.repo,
+            ctx.origin.commit,
+            ctx.suite.id(),
+            ctx.arch.id()
+        ),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs()),
+    )?;
+    fs::write(
+        path.join(format!("{}.spdx.json", build_name)),
+        sbom.to_json().pretty(2),
+    )?;
+
+    // Provenance is signed locally, after remote builds have been pulled
+    let provenance = Provenance {
+        origin: ctx.origin.clone(),
+        suite: ctx.suite.id().to_string(),
+        arch: ctx.arch.id().to_string(),
+        builder: host.backend().name().to_string(),
+        host: host.name().to_string(),
+        environment: {
+            if manifest_path.is_file() {
+                Some(util::sha256_file(&manifest_path)?)
+            } else {
+                None
+            }
+        },
+        source: (
+            ctx.dsc_path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            util::sha256_file(&ctx.dsc_path)?,
+        ),
+        artifacts: Provenance::artifacts(path)?,
+    };
+    provenance.write(path, ctx.signer)?;
 
-        //TODO: allow arm64 builder to have different filesystem layout
-        {
-            // arm64 source is shared between arm64 and armhf which do builds at the same time
-            let _guard = ARM64_RSYNC.lock().unwrap();
-            process::Command::new("rsync")
-                .arg("--archive")
-                .arg("--compress")
-                .arg("--delay-updates")
-                .arg("--delete")
-                .arg("--mkpath")
-                .arg("--rsh=ssh")
-                .arg(format!("{}/", ctx.source.display()))
-                .arg(format!("{}:{}/", arm64, ctx.source.display()))
-                .status()
-                .and_then(check_status)?;
-        }
-
-        let res = process::Command::new("ssh")
-            .arg(&arm64)
-            .arg("--")
-            .arg(script)
-            .status()
-            .and_then(check_status);
-
-        process::Command::new("rsync")
-            .arg("--archive")
-            .arg("--compress")
-            .arg("--delay-updates")
-            .arg("--delete")
-            .arg("--mkpath")
-            .arg("--rsh=ssh")
-            .arg(format!("{}:{}/", arm64, path.display()))
-            .arg(format!("{}/", path.display()))
-            .status()
-            .and_then(check_status)?;
+    Ok(())
+}
+
+/// Pocket repositories generated by the apt step, served to builders during the run
+struct LocalRepo {
+    path: PathBuf,
+    url: String,
+    remote: bool,
+}
+
+impl LocalRepo {
+    /// Sources of the pockets for a suite, at a URL serving the local repository
+    fn sources(&self, pockets: &BTreeSet<Pocket>, suite: &Suite, url: &str) -> Vec<String> {
+        let mut sources = Vec::new();
+        for pocket in pockets.iter() {
+            let release = self
+                .path
+                .join(pocket.id())
+                .join("dists")
+                .join(suite.id())
+                .join("Release");
+            // Builds can depend on packages in any component, but not on debug symbols
+            let components = match fs::read_to_string(&release) {
+                Ok(data) => Control::parse(&data)
+                    .get("Components")
+                    .unwrap_or("main")
+                    .split_whitespace()
+                    .filter(|component| *component != "debug" && !component.ends_with("/debug"))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                Err(_) => continue,
+            };
+            sources.push(format!(
+                "deb [trusted=yes] {}/{} {} {}",
+                url,
+                pocket.id(),
+                suite.id(),
+                components
+            ));
+        }
+        sources
+    }
+}
+
+/// Port of an http URL, which is 80 if it is not given. Returns None for other URLs.
+fn url_port(url: &str) -> Option<u16> {
+    let authority = url
+        .strip_prefix("http://")?
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    // IPv6 addresses are in brackets, and have colons of their own
+    let port = match authority.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']')?.1 {
+            "" => None,
+            after => Some(after.strip_prefix(':')?),
+        },
+        None => authority.split_once(':').map(|(_, port)| port),
+    };
+    match port {
+        Some(port) => port.parse().ok(),
+        None => Some(80),
+    }
+}
+
+/// Check a --local-repo-url argument, which builders use with apt
+fn local_repo_url_valid(url: String) -> Result<(), String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(format!("{} is not an http or https URL", url))
+    }
+}
+
+/// Build environments prepared at once on a host without a limit on concurrent builds
+const PREPARE_JOBS: usize = 4;
+
+/// Check and create missing build environments, in parallel up to the slots of each host, and
+/// only schedule builds to hosts with a ready build environment
+fn prepare_environments(
+    pool: &mut BuilderPool,
+    chroots: &Chroots,
+    environments: &BTreeSet<(Suite, Arch)>,
+) {
+    let queues: Vec<_> = pool
+        .hosts()
+        .map(|host| {
+            let queue: Vec<_> = environments
+                .iter()
+                .filter(|(_, arch)| host.supports(arch))
+                .collect();
+            let jobs = match host.slots {
+                0 => PREPARE_JOBS,
+                slots => slots,
+            };
+            (host, jobs.min(queue.len()), Mutex::new(queue.into_iter()))
+        })
+        .collect();
+    let mut readiness = crossbeam::thread::scope(|s| {
+        let mut threads = Vec::new();
+        for (host, jobs, queue) in queues.iter() {
+            for _ in 0..*jobs {
+                threads.push(s.spawn(move |_| {
+                    let mut results = Vec::new();
+                    loop {
+                        let next = queue.lock().unwrap().next();
+                        let (suite, arch) = match next {
+                            Some(some) => some,
+                            None => break,
+                        };
+                        let ready = chroots.prepare(host, suite, arch);
+                        results.push((host.name().to_string(), suite, arch, ready));
+                    }
+                    results
+                }));
+            }
+        }
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>()
+    })
+    .unwrap();
+    readiness.sort_by(|a, b| (a.1, a.2).cmp(&(b.1, b.2)));
+
+    for host in pool.hosts() {
+        for (name, suite, arch, ready) in readiness.iter() {
+            if name == host.name() {
+                eprintln!(
+                    bold!("ci: builder {}: {} {}: {}"),
+                    name,
+                    suite.id(),
+                    arch.id(),
+                    ready
+                );
+            }
+        }
+    }
+    for (name, suite, arch, ready) in readiness.iter() {
+        if !ready.is_ready() {
+            pool.mark_unready(name, suite, arch);
+        }
+    }
+}
+
+fn sorted_dirs(path: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry_res in fs::read_dir(path)? {
+        let entry = entry_res?;
+        if entry.path().is_dir() {
+            dirs.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            ));
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Update the date and validity of the Release file of a dists directory and sign it again,
+/// if it expires soon, its validity changed, or force is set. Returns true if it was
+/// refreshed.
+fn refresh_dists(
+    path: &Path,
+    valid_for: Option<Duration>,
+    refresh_before: Duration,
+    signer: &Signer,
+    force: bool,
+) -> io::Result<bool> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    if !force && !index::release_needs_refresh(path, valid_for, refresh_before, now)? {
+        return Ok(false);
+    }
+    index::refresh_release(path, now, valid_for)?;
+    signer.sign_release(path)?;
+    Ok(true)
+}
+
+/// What is needed to generate and sign the dists of a repository
+struct DistsContext<'a> {
+    config: &'a Config,
+    component_rules: &'a ComponentRules,
+    index_cache: &'a IndexCache,
+    signer: &'a Signer,
+}
 
+impl DistsContext<'_> {
+    /// Generate and sign the dists of a suite from the pool of the repository at root, if
+    /// rebuild is set or they do not exist yet. Indexes of the previous generation stay
+    /// available by hash. Returns the path of the dists and whether they were generated.
+    fn build(
+        &self,
+        dists_cache: &mut Cache,
+        root: &Path,
+        suite: &Suite,
+        info: &ReleaseInfo,
+        rebuild: bool,
+    ) -> io::Result<(PathBuf, bool)> {
+        // The previous generation is moved aside, so its by-hash indexes can be kept
+        let previous_dists = dists_cache.path().join(format!("previous.{}", suite.id()));
+        if previous_dists.exists() {
+            fs::remove_dir_all(&previous_dists)?;
+        }
+        let current_dists = dists_cache.path().join(suite.id());
+        if rebuild && current_dists.is_dir() {
+            fs::rename(&current_dists, &previous_dists)?;
+        }
+
+        let res = dists_cache.build(suite.id(), rebuild, |path| {
+            fs::create_dir(path)?;
+            index::generate_dists(
+                self.index_cache,
+                root,
+                &root.join("pool").join(suite.id()),
+                path,
+                info,
+                self.component_rules,
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_secs()),
+            )?;
+            if self.config.dists.by_hash && previous_dists.is_dir() {
+                let kept =
+                    index::keep_by_hash(&previous_dists, path, self.config.dists.by_hash_grace)?;
+                eprintln!("    kept {} previous by-hash indexes", kept);
+            }
+            self.signer.sign_release(path)
+        });
+
+        if previous_dists.exists() {
+            fs::remove_dir_all(&previous_dists)?;
+        }
         res
+    }
+}
+
+/// Copy the packages of a suite in a pocket into another repository, replacing the packages
+/// promoted there before from the same repos, then regenerate and sign its dists. Promotions
+/// are recorded in promotions.json in the target repository.
+fn promote(
+    promote_matches: &ArgMatches,
+    cache_path: &Path,
+    dists_ctx: &DistsContext<'_>,
+    dev: bool,
+) -> io::Result<()> {
+    let config = dists_ctx.config;
+    let pocket = Pocket::new(promote_matches.value_of("pocket").unwrap());
+    let suite_name = promote_matches.value_of("suite").unwrap();
+    let suite = Suite::new(suite_name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown suite {}", suite_name),
+        )
+    })?;
+    let target = promote_matches.value_of("to").unwrap_or("release");
+    let target_path = match promote_matches.value_of("path") {
+        Some(path) => PathBuf::from(path),
+        None => cache_path.join("promoted").join(target),
+    };
+    let filter = |value: &str| {
+        promote_matches
+            .values_of("repo")
+            .is_none_or(|mut values| values.any(|x| x == value))
+    };
+
+    let archs = RepoInfo::archs(&suite, dev);
+    let (origin, label) = if dev {
+        (
+            format!("system76-ubuntu-{}", target),
+            format!("System76 Ubuntu {}", target),
+        )
     } else {
-        process::Command::new("sh")
-            .arg("-c")
-            .arg(script)
-            .status()
-            .and_then(check_status)
+        (format!("pop-os-{}", target), format!("Pop!_OS {}", target))
+    };
+    let info = ReleaseInfo {
+        description: format!("{} {} {}", label, suite.id(), suite.version()),
+        origin,
+        label,
+        suite: suite.id().to_string(),
+        version: suite.version().to_string(),
+        codename: suite.id().to_string(),
+        components: vec![dists_ctx.component_rules.default.clone()],
+        archs: archs.iter().map(|arch| arch.id().to_string()).collect(),
+        acquire_by_hash: config.dists.by_hash,
+        signed_by: config.signing.signed_by.clone(),
+        valid_for: config.valid_for(target),
+    };
+
+    let source_pool = cache_path
+        .join("apt")
+        .join(pocket.id())
+        .join("pool")
+        .join(suite.id());
+    if !source_pool.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no packages for {}", pocket.id(), suite.id()),
+        ));
+    }
+    let target_pool = target_path.join("pool").join(suite.id());
+    fs::create_dir_all(&target_pool)?;
+    let mut report = Report::load(target_path.join("promotions.json"))?;
+
+    let mut promoted = false;
+    for (repo_name, repo_path) in sorted_dirs(&source_pool)? {
+        if !filter(&repo_name) {
+            continue;
+        }
+        for (commit, commit_path) in sorted_dirs(&repo_path)? {
+            let target_repo = target_pool.join(&repo_name);
+            let target_commit = target_repo.join(&commit);
+            let previous = report.get(suite.id(), &repo_name)["commit"]
+                .as_str()
+                .map(|previous| previous.to_string());
+            if target_commit.is_dir() && previous.as_deref() == Some(commit.as_str()) {
+                eprintln!(
+                    bold!("ci: {}: {} already promoted to {}"),
+                    repo_name, commit, target
+                );
+                continue;
+            }
+            eprintln!(
+                bold!("ci: promoting {}: {} from {} to {}"),
+                repo_name,
+                commit,
+                pocket.id(),
+                target
+            );
+
+            // A promotion that was interrupted before it was recorded is completed
+            if !target_commit.is_dir() {
+                // Copy to a partial directory first, so an interrupted copy is not published
+                let partial_path = target_repo.join(format!("partial.{}", commit));
+                if partial_path.exists() {
+                    fs::remove_dir_all(&partial_path)?;
+                }
+                fs::create_dir_all(&partial_path)?;
+                for entry_res in fs::read_dir(&commit_path)? {
+                    let entry = entry_res?;
+                    eprintln!("  {}", entry.file_name().to_string_lossy());
+                    fs::copy(entry.path(), partial_path.join(entry.file_name()))?;
+                }
+
+                // Only one commit of each repo is kept in the target
+                for (_, old_path) in sorted_dirs(&target_repo)? {
+                    if old_path != partial_path {
+                        fs::remove_dir_all(&old_path)?;
+                    }
+                }
+                fs::rename(&partial_path, &target_commit)?;
+            }
+
+            let mut files = Vec::new();
+            for entry_res in fs::read_dir(&target_commit)? {
+                files.push(entry_res?.file_name().to_string_lossy().into_owned());
+            }
+            files.sort();
+
+            let mut entry = json::JsonValue::new_object();
+            entry["commit"] = commit.as_str().into();
+            entry["pocket"] = pocket.id().into();
+            entry["previous"] = previous.into();
+            entry["files"] = files.into();
+            entry["time"] = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs())
+                .into();
+            report.set(suite.id(), &repo_name, entry);
+            promoted = true;
+        }
+    }
+
+    let mut dists_cache = Cache::new(target_path.join("dists"), |_| true)?;
+    let (dists_path, dists_rebuilt) =
+        dists_ctx.build(&mut dists_cache, &target_path, &suite, &info, promoted)?;
+    if !dists_rebuilt {
+        refresh_dists(
+            &dists_path,
+            info.valid_for,
+            config.dists.refresh_before,
+            dists_ctx.signer,
+            false,
+        )?;
+    }
+
+    // Promotions are only recorded once the dists listing them are signed
+    report.save()
+}
+
+/// Publish the apt repositories in the cache to the configured staging target. Only suites
+/// whose Release files changed since they were last published are uploaded, with snapshots of
+/// pockets when they changed, and suites and pockets that are no longer in the cache are
+/// removed from the target.
+fn publish_apt(config: &Config, cache_path: &Path, dev: bool) {
+    let target = config
+        .publish
+        .target(dev)
+        .expect("no publish target configured");
+    let apt_path = cache_path.join("apt");
+    let pockets = if apt_path.is_dir() {
+        sorted_dirs(&apt_path).expect("failed to read apt cache")
+    } else {
+        Vec::new()
+    };
+    // Suites are published separately, and change whenever their Release files change
+    let fingerprint = |pocket_path: &Path| -> BTreeMap<String, json::JsonValue> {
+        publish::suite_fingerprints(pocket_path)
+            .expect("failed to read pocket Release files")
+            .into_iter()
+            .map(|(suite_name, sums)| (suite_name, sums.into()))
+            .collect()
+    };
+    let snapshot_names = |pocket_path: &Path| -> json::JsonValue {
+        snapshot::list(pocket_path)
+            .expect("failed to list snapshots")
+            .into()
+    };
+    let view = cache_path.join("pockets").join("publish");
+
+    // What was last published is recorded for each target
+    let section = publish::publisher(target).name();
+    let mut record = Report::load(cache_path.join("pockets").join("published.json"))
+        .expect("failed to load publish record");
+    let published = match record.keys(&section) {
+        Some(some) => some,
+        None => {
+            // Without a record, everything is published and stale files are removed
+            eprintln!(bold!("publish: all pockets"));
+            publish::publish(publish::publisher(target).as_ref(), &apt_path)
+                .expect("failed to publish apt repositories");
+            for (pocket_name, pocket_path) in pockets.iter() {
+                let mut pocket_record = json::JsonValue::from(fingerprint(pocket_path));
+                pocket_record[snapshot::SNAPSHOTS] = snapshot_names(pocket_path);
+                record.set(&section, pocket_name, pocket_record);
+            }
+            record.save().expect("failed to save publish record");
+            return;
+        }
+    };
+
+    for (pocket_name, pocket_path) in pockets.iter() {
+        let pocket_target = target.join(pocket_name);
+        let mut pocket_record = record.get(&section, pocket_name).clone();
+        if !pocket_record.has_key(snapshot::SNAPSHOTS) {
+            // Not published yet, or recorded before suites were published separately
+            pocket_record = json::JsonValue::new_object();
+        }
+
+        let suites = fingerprint(pocket_path);
+        for (suite_name, suite_fingerprint) in suites.iter() {
+            if &pocket_record[suite_name.as_str()] == suite_fingerprint {
+                eprintln!(
+                    bold!("publish: pocket {} suite {} unchanged"),
+                    pocket_name, suite_name
+                );
+                continue;
+            }
+            eprintln!(
+                bold!("publish: pocket {} suite {}"),
+                pocket_name, suite_name
+            );
+            publish::publish_suite(&pocket_target, pocket_path, suite_name, &view)
+                .expect("failed to publish apt suite");
+            pocket_record[suite_name.as_str()] = suite_fingerprint.clone();
+            record.set(&section, pocket_name, pocket_record.clone());
+            record.save().expect("failed to save publish record");
+        }
+
+        let recorded_suites: Vec<String> = pocket_record
+            .entries()
+            .map(|(key, _)| key.to_string())
+            .filter(|key| key != snapshot::SNAPSHOTS)
+            .collect();
+        for suite_name in recorded_suites {
+            if suites.contains_key(&suite_name) {
+                continue;
+            }
+            eprintln!(
+                bold!("publish: removing pocket {} suite {}"),
+                pocket_name, suite_name
+            );
+            for dir in ["dists", "pool"] {
+                publish::publisher(&pocket_target.join(&format!("{}/{}", dir, suite_name)))
+                    .remove()
+                    .expect("failed to remove apt suite");
+            }
+            pocket_record.remove(&suite_name);
+            record.set(&section, pocket_name, pocket_record.clone());
+            record.save().expect("failed to save publish record");
+        }
+
+        // Snapshots are not part of any suite
+        let snapshots = snapshot_names(pocket_path);
+        if pocket_record[snapshot::SNAPSHOTS] != snapshots {
+            eprintln!(bold!("publish: pocket {} snapshots"), pocket_name);
+            let snapshots_publisher = publish::publisher(&pocket_target.join(snapshot::SNAPSHOTS));
+            let snapshots_path = pocket_path.join(snapshot::SNAPSHOTS);
+            if snapshots_path.is_dir() {
+                publish::publish(snapshots_publisher.as_ref(), &snapshots_path)
+                    .expect("failed to publish snapshots");
+            } else {
+                snapshots_publisher
+                    .remove()
+                    .expect("failed to remove snapshots");
+            }
+            pocket_record[snapshot::SNAPSHOTS] = snapshots;
+            record.set(&section, pocket_name, pocket_record);
+            record.save().expect("failed to save publish record");
+        }
+    }
+
+    for pocket_name in published.iter() {
+        if pockets.iter().any(|(name, _)| name == pocket_name) {
+            continue;
+        }
+        eprintln!(bold!("publish: removing pocket {}"), pocket_name);
+        publish::publisher(&target.join(pocket_name))
+            .remove()
+            .expect("failed to remove apt repository");
+        record.remove(&section, pocket_name);
+        record.save().expect("failed to save publish record");
+    }
+}
+
+/// Post the package changes of a run to a chat webhook
+fn post_changelog(webhook: &str, text: &str) -> io::Result<()> {
+    let mut data = BTreeMap::<&str, &str>::new();
+    data.insert("text", text);
+
+    process::Command::new("curl")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--header")
+        .arg("Content-Type: application/json")
+        .arg("--data-raw")
+        .arg(json::stringify(data))
+        .arg("--request")
+        .arg("POST")
+        .arg(webhook)
+        .stdout(process::Stdio::null())
+        .status()
+        .and_then(check_status)
+}
+
+/// Rebuild cached binary builds and compare the results to the originals, recording the
+/// reproducibility of each build in the verify report. Returns true if all builds reproduced.
+fn verify_builds(
+    verify_matches: &ArgMatches,
+    cache_path: &Path,
+    run_ctx: &RunContext<'_>,
+    dev: bool,
+) -> io::Result<bool> {
+    let local_repo_opt = run_ctx.local_repo_opt;
+    let filter = |name: &str, value: &str| {
+        verify_matches
+            .values_of(name)
+            .is_none_or(|mut values| values.any(|x| x == value))
+    };
+
+    let verify_path = cache_path.join("verify");
+    fs::create_dir_all(&verify_path)?;
+    let mut report = Report::load(verify_path.join("report.json"))?;
+
+    let mut all_reproducible = true;
+    for (repo_name, repo_path) in sorted_dirs(&cache_path.join("git"))? {
+        if !filter("repo", &repo_name) {
+            continue;
+        }
+        for (commit, commit_path) in sorted_dirs(&repo_path)? {
+            if !filter("commit", &commit) {
+                continue;
+            }
+            for (suite_name, suite_path) in sorted_dirs(&commit_path)? {
+                let suite = match Suite::new(&suite_name) {
+                    Some(some) => some,
+                    None => continue,
+                };
+                if !filter("suite", suite.id()) {
+                    continue;
+                }
+
+                let source = suite_path.join("source");
+                let mut dsc_paths = Vec::new();
+                if source.is_dir() {
+                    for entry_res in fs::read_dir(&source)? {
+                        let entry = entry_res?;
+                        if entry.file_name().to_string_lossy().ends_with(".dsc") {
+                            dsc_paths.push(entry.path());
+                        }
+                    }
+                }
+                if dsc_paths.len() != 1 {
+                    // Source build failed or is incomplete
+                    continue;
+                }
+
+                // Rebuild using the pockets the build was published to
+                let mut pockets = BTreeSet::new();
+                if let Some(local_repo) = local_repo_opt {
+                    for (pocket_name, pocket_path) in sorted_dirs(&local_repo.path)? {
+                        let pool_path = pocket_path
+                            .join("pool")
+                            .join(suite.id())
+                            .join(&repo_name)
+                            .join(&commit);
+                        if pool_path.is_dir() {
+                            pockets.insert(Pocket::new(&pocket_name));
+                        }
+                    }
+                }
+                for arch in RepoInfo::archs(&suite, dev).iter() {
+                    if !filter("arch", arch.id()) {
+                        continue;
+                    }
+                    let original = suite_path.join(arch.id());
+                    if !original.is_dir() {
+                        continue;
+                    }
+
+                    let key = format!("{}/{}/{}/{}", repo_name, commit, suite.id(), arch.id());
+                    eprintln!(bold!("ci: verifying {}"), key);
+
+                    let rebuilt = verify_path.join(format!(
+                        "{}_{}_{}_{}",
+                        repo_name,
+                        commit,
+                        suite.id(),
+                        arch.id()
+                    ));
+                    if rebuilt.is_dir() {
+                        fs::remove_dir_all(&rebuilt)?;
+                    }
+                    let binary_ctx = BinaryContext {
+                        arch: arch.clone(),
+                        chroots: run_ctx.chroots,
+                        dsc_path: dsc_paths[0].clone(),
+                        extra_debs: Vec::new(),
+                        local_repo_opt,
+                        offline_opt: run_ctx.offline_opt,
+                        origin: BuildOrigin {
+                            repo: repo_name.clone(),
+                            commit: commit.clone(),
+                            branches: Vec::new(),
+                        },
+                        pockets: pockets.clone(),
+                        pool: run_ctx.pool,
+                        dev,
+                        signer: run_ctx.signer,
+                        source: source.clone(),
+                        suite: suite.clone(),
+                    };
+                    let (status, differences) = match binary_build(&binary_ctx, &rebuilt)
+                        .and_then(|()| verify::compare_builds(&original, &rebuilt))
+                    {
+                        Ok(differences) if differences.is_empty() => {
+                            (Reproducibility::Reproducible, differences)
+                        }
+                        Ok(differences) => (Reproducibility::Unreproducible, differences),
+                        Err(err) => (Reproducibility::Failed, vec![err.to_string()]),
+                    };
+
+                    eprintln!(bold!("ci: {}: {}"), key, status);
+                    for difference in differences.iter() {
+                        eprintln!("  {}", difference);
+                    }
+                    if status != Reproducibility::Reproducible {
+                        all_reproducible = false;
+                    }
+
+                    let mut entry = json::JsonValue::new_object();
+                    entry["status"] = status.id().into();
+                    entry["differences"] = differences.into();
+                    entry["time"] = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |duration| duration.as_secs())
+                        .into();
+                    report.set("reproducibility", &key, entry);
+                    report.save()?;
+                }
+            }
+        }
+    }
+    Ok(all_reproducible)
+}
+
+/// Names used to report on the build of a suite for a commit
+#[derive(Clone)]
+struct SuiteInfo {
+    dev: bool,
+    repo_name: String,
+    commit: GitCommit,
+    commit_name: String,
+    branches: Vec<String>,
+    suite: Suite,
+    suite_name: String,
+}
+
+impl SuiteInfo {
+    fn github_status(&self, step: &str, status: &str) {
+        self.github_status_detail(step, status, "");
+    }
+
+    /// Report a GitHub status with details appended to the description
+    fn github_status_detail(&self, step: &str, status: &str, detail: &str) {
+        let target_url = match env::var("BUILD_URL") {
+            Ok(some) => some,
+            Err(_) => return,
+        };
+
+        eprintln!(
+            bold!("{}: {}: {}: {} github status {}"),
+            self.repo_name, self.commit_name, self.suite_name, step, status
+        );
+
+        let (context, mut description) = if self.dev {
+            (
+                format!("ubuntu/staging/{}/{}", self.suite.id(), step),
+                format!("Ubuntu Staging {} {}", self.suite.id(), step),
+            )
+        } else {
+            (
+                format!("pop-os/staging/{}/{}", self.suite.id(), step),
+                format!("Pop!_OS Staging {} {}", self.suite.id(), step),
+            )
+        };
+        if !detail.is_empty() {
+            write!(description, ": {}", detail).unwrap();
+        }
+
+        match github_status_inner(
+            &self.repo_name,
+            &self.commit,
+            &context,
+            &description,
+            status,
+            &target_url,
+        ) {
+            Ok(()) => (),
+            Err(err) => eprintln!(
+                bold!("{}: {}: {}: {} github status {} failed: {}"),
+                self.repo_name, self.commit_name, self.suite_name, step, status, err
+            ),
+        }
+    }
+}
+
+/// A suite of a commit with a built source package, waiting for its binary builds
+struct SuiteBuild {
+    info: SuiteInfo,
+    pockets: BTreeSet<Pocket>,
+    suite_cache: Cache,
+    dsc: Control,
+    dsc_path: PathBuf,
+    package: Package,
+    source: PathBuf,
+    source_rebuilt: bool,
+    binary_archs: Vec<Arch>,
+}
+
+impl SuiteBuild {
+    /// Report that the binary builds were skipped because packages they depend on failed
+    fn dependencies_failed(&self, deps_failed: &[String], ci_ctx_mtx: &Mutex<CiContext>) {
+        let info = &self.info;
+        let message = format!("dependencies failed: {}", deps_failed.join(", "));
+        eprintln!(
+            bold!("{}: {}: {}: {}"),
+            info.repo_name, info.commit_name, info.suite_name, message
+        );
+        for arch in self.binary_archs.iter() {
+            info.github_status_detail(&format!("binary-{}", arch.id()), "failure", &message);
+        }
+
+        let log_path = self.suite_cache.path().join("dependencies.log");
+        if let Err(err) = fs::write(&log_path, format!("{}\n", message)) {
+            eprintln!(
+                bold!("{}: {}: {}: failed to write dependencies log: {}"),
+                info.repo_name, info.commit_name, info.suite_name, err
+            );
+            return;
+        }
+        let log_name = format!(
+            "{}_{}_{}_dependencies.log",
+            info.repo_name,
+            info.commit.id(),
+            info.suite.id()
+        );
+        let mut ci_ctx = ci_ctx_mtx.lock().unwrap();
+        ci_ctx
+            .logs
+            .insert(log_name.clone(), (log_path.clone(), true));
+        for pocket in self.pockets.iter() {
+            ci_ctx
+                .pocket_logs
+                .entry(pocket.clone())
+                .or_default()
+                .insert(log_name.clone(), (log_path.clone(), true));
+        }
+    }
+
+    /// Run binary builds, returning the built debs if all architectures succeeded and no gating
+    /// QA stage failed
+    fn run(
+        mut self,
+        extra_debs: &[PathBuf],
+        run_ctx: &RunContext<'_>,
+        ci_ctx_mtx: &Mutex<CiContext>,
+    ) -> Option<Vec<PathBuf>> {
+        let info = &self.info;
+        let local_repo_opt = run_ctx.local_repo_opt;
+
+        let mut binary_builds = BTreeMap::new();
+        for arch in self.binary_archs.iter() {
+            let binary_ctx = BinaryContext {
+                arch: arch.clone(),
+                chroots: run_ctx.chroots,
+                dsc_path: self.dsc_path.clone(),
+                extra_debs: extra_debs
+                    .iter()
+                    .filter(|deb_path| {
+                        let deb_name = deb_path.file_name().unwrap().to_string_lossy();
+                        deb_name.ends_with(&format!("_{}.deb", arch.id()))
+                            || deb_name.ends_with("_all.deb")
+                    })
+                    .cloned()
+                    .collect(),
+                local_repo_opt,
+                offline_opt: run_ctx.offline_opt,
+                origin: BuildOrigin {
+                    repo: info.repo_name.clone(),
+                    commit: info.commit.id().to_string(),
+                    branches: info.branches.clone(),
+                },
+                pockets: self.pockets.clone(),
+                pool: run_ctx.pool,
+                dev: info.dev,
+                signer: run_ctx.signer,
+                source: self.source.clone(),
+                suite: info.suite.clone(),
+            };
+            binary_builds.insert(arch.id().to_string(), move |path: &Path| {
+                eprintln!(
+                    bold!("{}: {}: {}: {}: binary building"),
+                    info.repo_name,
+                    info.commit_name,
+                    info.suite_name,
+                    binary_ctx.arch.id()
+                );
+                info.github_status(&format!("binary-{}", binary_ctx.arch.id()), "pending");
+                match binary_build(&binary_ctx, path) {
+                    Ok(()) => {
+                        eprintln!(
+                            bold!("{}: {}: {}: {}: binary built"),
+                            info.repo_name,
+                            info.commit_name,
+                            info.suite_name,
+                            binary_ctx.arch.id()
+                        );
+                        info.github_status(&format!("binary-{}", binary_ctx.arch.id()), "success");
+                        Ok(())
+                    }
+                    Err(err) => {
+                        eprintln!(
+                            bold!("{}: {}: {}: {}: binary failed: {}"),
+                            info.repo_name,
+                            info.commit_name,
+                            info.suite_name,
+                            binary_ctx.arch.id(),
+                            err
+                        );
+                        info.github_status(&format!("binary-{}", binary_ctx.arch.id()), "failure");
+                        Err(err)
+                    }
+                }
+            });
+        }
+
+        let binary_results = self
+            .suite_cache
+            .build_parallel(binary_builds, self.source_rebuilt);
+
+        let mut binaries_failed = false;
+        for (arch_id, binary_result) in binary_results.iter() {
+            match binary_result {
+                Ok((binary, binary_rebuilt)) => {
+                    if *binary_rebuilt {
+                        self.package.rebuilt = true;
+                    }
+
+                    for entry_res in
+                        fs::read_dir(binary).expect("failed to read suite binary directory")
+                    {
+                        let entry = entry_res.expect("failed to read suite binary entry");
+                        let file_name = entry
+                            .file_name()
+                            .into_string()
+                            .expect("suite binary filename is not utf-8");
+                        if file_name.ends_with(".deb") {
+                            assert_eq!(self.package.debs.insert(file_name, entry.path()), None);
+                        } else if file_name.ends_with(".ddeb") {
+                            assert_eq!(self.package.ddebs.insert(file_name, entry.path()), None);
+                        } else if provenance::is_attestation(&file_name) {
+                            assert_eq!(
+                                self.package.attestations.insert(file_name, entry.path()),
+                                None
+                            );
+                        }
+                    }
+                }
+                Err(_err) => {
+                    binaries_failed = true;
+
+                    let partial_binary_dir =
+                        self.suite_cache.path().join(format!("partial.{}", arch_id));
+                    if partial_binary_dir.is_dir() {
+                        for entry_res in fs::read_dir(&partial_binary_dir)
+                            .expect("failed to read partial binary directory")
+                        {
+                            let entry = entry_res.expect("failed to read partial binary entry");
+                            let file_name = entry
+                                .file_name()
+                                .into_string()
+                                .expect("partial binary filename is not utf-8");
+                            if file_name.ends_with(&format!("_{}.build", arch_id)) {
+                                let binary_log_name = format!(
+                                    "{}_{}_{}_{}.log",
+                                    info.repo_name,
+                                    info.commit.id(),
+                                    info.suite.id(),
+                                    arch_id
+                                );
+                                let mut ci_ctx = ci_ctx_mtx.lock().unwrap();
+                                assert_eq!(
+                                    ci_ctx
+                                        .logs
+                                        .insert(binary_log_name.clone(), (entry.path(), true)),
+                                    None
+                                );
+                                for pocket in self.pockets.iter() {
+                                    assert_eq!(
+                                        ci_ctx
+                                            .pocket_logs
+                                            .entry(pocket.clone())
+                                            .or_default()
+                                            .insert(binary_log_name.clone(), (entry.path(), true)),
+                                        None
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if binaries_failed {
+            return None;
+        }
+
+        // Pockets that will not include this package because a gating QA stage failed
+        let mut gated_pockets = BTreeSet::new();
+        for (arch_id, binary_result) in binary_results.iter() {
+            let binary = match binary_result {
+                Ok((binary, _)) => binary,
+                Err(_) => continue,
+            };
+            let arch = self
+                .binary_archs
+                .iter()
+                .find(|arch| arch.id() == arch_id)
+                .unwrap();
+
+            for stage in QaStage::ALL.iter() {
+                let stage_pockets: Vec<_> = self
+                    .pockets
+                    .iter()
+                    .map(|pocket| {
+                        (
+                            pocket,
+                            run_ctx
+                                .config
+                                .qa_stage(stage.id(), &info.repo_name, pocket.id()),
+                        )
+                    })
+                    .filter(|(_, stage_config)| stage_config.enabled == Some(true))
+                    .collect();
+                if stage_pockets.is_empty() {
+                    continue;
+                }
+
+                let (result, log_path, log_rebuilt) = match self.qa(*stage, arch, binary, run_ctx) {
+                    Ok(ok) => ok,
+                    Err(err) => {
+                        eprintln!(
+                            bold!("{}: {}: {}: {}: {} failed to run: {}"),
+                            info.repo_name,
+                            info.commit_name,
+                            info.suite_name,
+                            arch.id(),
+                            stage,
+                            err
+                        );
+                        info.github_status_detail(
+                            &format!("{}-{}", stage, arch.id()),
+                            "error",
+                            &err.to_string(),
+                        );
+                        // A gating stage that can not run fails closed
+                        for (pocket, stage_config) in stage_pockets.iter() {
+                            if stage_config.gate == Some(true) {
+                                eprintln!(
+                                    bold!(
+                                        "{}: {}: {}: {}: {} did not run, excluding from pocket {}"
+                                    ),
+                                    info.repo_name,
+                                    info.commit_name,
+                                    info.suite_name,
+                                    arch.id(),
+                                    stage,
+                                    pocket.id()
+                                );
+                                gated_pockets.insert((*pocket).clone());
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                let qa_log_name = format!(
+                    "{}_{}_{}_{}_{}.log",
+                    info.repo_name,
+                    info.commit.id(),
+                    info.suite.id(),
+                    arch.id(),
+                    stage
+                );
+                let mut ci_ctx = ci_ctx_mtx.lock().unwrap();
+                assert_eq!(
+                    ci_ctx
+                        .logs
+                        .insert(qa_log_name.clone(), (log_path.clone(), log_rebuilt)),
+                    None
+                );
+                for (pocket, stage_config) in stage_pockets.iter() {
+                    assert_eq!(
+                        ci_ctx
+                            .pocket_logs
+                            .entry((*pocket).clone())
+                            .or_default()
+                            .insert(qa_log_name.clone(), (log_path.clone(), log_rebuilt)),
+                        None
+                    );
+                    if result == QaResult::Fail && stage_config.gate == Some(true) {
+                        eprintln!(
+                            bold!("{}: {}: {}: {}: {} failed, excluding from pocket {}"),
+                            info.repo_name,
+                            info.commit_name,
+                            info.suite_name,
+                            arch.id(),
+                            stage,
+                            pocket.id()
+                        );
+                        gated_pockets.insert((*pocket).clone());
+                    }
+                }
+            }
+        }
+
+        let mut ci_ctx = ci_ctx_mtx.lock().unwrap();
+        for pocket in self.pockets.iter() {
+            if gated_pockets.contains(pocket) {
+                ci_ctx
+                    .gated_packages
+                    .entry(pocket.clone())
+                    .or_default()
+                    .entry(info.suite.clone())
+                    .or_default()
+                    .insert(info.repo_name.clone());
+                continue;
+            }
+            assert_eq!(
+                ci_ctx
+                    .pocket_packages
+                    .entry(pocket.clone())
+                    .or_default()
+                    .entry(info.suite.clone())
+                    .or_default()
+                    .insert(
+                        info.repo_name.clone(),
+                        (info.commit.clone(), self.package.clone())
+                    ),
+                None
+            );
+        }
+
+        if !gated_pockets.is_empty() {
+            // Dependents must not be built against a package that is held back
+            return None;
+        }
+
+        Some(self.package.debs.values().cloned().collect())
+    }
+
+    /// Run a QA stage on the results of a binary build, unless it already ran for this build.
+    /// Returns the result, the log path, and whether the stage was run.
+    fn qa(
+        &self,
+        stage: QaStage,
+        arch: &Arch,
+        binary: &Path,
+        run_ctx: &RunContext<'_>,
+    ) -> io::Result<(QaResult, PathBuf, bool)> {
+        let info = &self.info;
+
+        // Results are stored with the binary build, so they are discarded when it is rebuilt
+        let qa_dir = binary.join("qa");
+        let log_path = qa_dir.join(format!("{}.log", stage));
+        let result_path = qa_dir.join(format!("{}.result", stage));
+        if let Ok(result_id) = fs::read_to_string(&result_path) {
+            if let Some(result) = QaResult::new(result_id.trim()) {
+                return Ok((result, log_path, false));
+            }
+        }
+
+        let mut changes_opt = None;
+        for entry_res in fs::read_dir(binary)? {
+            let entry = entry_res?;
+            if entry.file_name().to_string_lossy().ends_with(".changes") {
+                changes_opt = Some(entry.path());
+            }
+        }
+        let changes = changes_opt.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "binary build has no .changes file")
+        })?;
+
+        let step = format!("{}-{}", stage, arch.id());
+        eprintln!(
+            bold!("{}: {}: {}: {}: {} running"),
+            info.repo_name,
+            info.commit_name,
+            info.suite_name,
+            arch.id(),
+            stage
+        );
+        info.github_status(&step, "pending");
+
+        let slot = run_ctx.pool.acquire(&info.suite, arch)?;
+        let host = slot.host();
+        slot.push(binary)?;
+        let script = stage.script(
+            host.backend(),
+            &info.suite,
+            arch,
+            &host.map_path(binary),
+            &host.map_path(&self.dsc_path),
+            &host.map_path(&changes),
+        );
+        let output = host.command(&script).output()?;
+        drop(slot);
+
+        let mut log = output.stdout;
+        log.extend_from_slice(&output.stderr);
+        let mut result = stage.result(output.status.code(), &String::from_utf8_lossy(&log));
+
+        fs::create_dir_all(&qa_dir)?;
+        // lintian exits with 1 when tags are found, other exit codes mean it did not run
+        let lintian_ran = output
+            .status
+            .code()
+            .is_some_and(|code| code == 0 || code == 1);
+        if stage == QaStage::Lintian && lintian_ran {
+            // Only tags that are new to a pocket determine the result
+            let tags = lintian::parse_tags(&String::from_utf8_lossy(&log));
+            lintian::write_tags(qa_dir.join("lintian.tags"), &tags)?;
+            result = self.lintian_regressions(arch, &tags, &run_ctx.lintian_history, &mut log)?;
+        }
+        fs::write(&log_path, &log)?;
+        fs::write(&result_path, result.id())?;
+
+        eprintln!(
+            bold!("{}: {}: {}: {}: {} {}"),
+            info.repo_name,
+            info.commit_name,
+            info.suite_name,
+            arch.id(),
+            stage,
+            result
+        );
+        info.github_status_detail(&step, result.github_state(), result.id());
+
+        Ok((result, log_path, true))
+    }
+
+    /// Compare lintian tags to the previous build in each pocket, recording the new tags in the
+    /// log. Fails if any pocket has a new error tag.
+    fn lintian_regressions(
+        &self,
+        arch: &Arch,
+        tags: &BTreeSet<LintianTag>,
+        lintian_history: &LintianHistory,
+        log: &mut Vec<u8>,
+    ) -> io::Result<QaResult> {
+        let info = &self.info;
+        let mut result = QaResult::Pass;
+        for pocket in self.pockets.iter() {
+            // New pockets are compared to master, as that is where their branches come from
+            let previous_opt = lintian_history
+                .previous(pocket, &info.suite, &info.repo_name, arch)
+                .map(|previous| (pocket.clone(), previous))
+                .or_else(|| {
+                    let master = Pocket::new("master");
+                    lintian_history
+                        .previous(&master, &info.suite, &info.repo_name, arch)
+                        .map(|previous| (master, previous))
+                });
+
+            let mut summary = String::new();
+            let new_tags: Vec<&LintianTag> = match &previous_opt {
+                Some((previous_pocket, (previous_commit, previous_tags))) => {
+                    writeln!(
+                        summary,
+                        "\nNew lintian tags in pocket {} compared to {} in pocket {}:",
+                        pocket.id(),
+                        previous_commit.id(),
+                        previous_pocket.id()
+                    )
+                    .unwrap();
+                    let previous_keys: BTreeSet<_> =
+                        previous_tags.iter().map(LintianTag::key).collect();
+                    tags.iter()
+                        .filter(|tag| !previous_keys.contains(&tag.key()))
+                        .collect()
+                }
+                None => {
+                    writeln!(
+                        summary,
+                        "\nNew lintian tags in pocket {} with no previous build:",
+                        pocket.id()
+                    )
+                    .unwrap();
+                    tags.iter().collect()
+                }
+            };
+            for tag in new_tags.iter() {
+                writeln!(summary, "{}", tag).unwrap();
+            }
+            log.extend_from_slice(summary.as_bytes());
+
+            if !new_tags.is_empty() {
+                eprintln!(
+                    bold!("{}: {}: {}: {}: {} new lintian tags in pocket {}"),
+                    info.repo_name,
+                    info.commit_name,
+                    info.suite_name,
+                    arch.id(),
+                    new_tags.len(),
+                    pocket.id()
+                );
+            }
+            let pocket_result = if new_tags.iter().any(|tag| tag.is_error()) {
+                QaResult::Fail
+            } else if !new_tags.is_empty() {
+                QaResult::Warn
+            } else {
+                QaResult::Pass
+            };
+            result = result.max(pocket_result);
+
+            lintian_history.record(
+                pocket,
+                &info.suite,
+                &info.repo_name,
+                arch,
+                &info.commit,
+                tags,
+            )?;
+        }
+        Ok(result)
     }
 }
 
@@ -222,12 +1669,19 @@ fn main() {
         .arg(
             Arg::with_name("publish")
                 .long("publish")
-                .help("Publish to apt-origin.pop-os.org after build"),
+                .help("Publish to the configured publish target after build"),
         )
         .arg(
             Arg::with_name("sbuild-update")
                 .long("sbuild-update")
-                .help("Update sbuild chroots"),
+                .help("Update sbuild chroots, or the build environments of other builders, of suites being built"),
+        )
+        .arg(
+            Arg::with_name("builder")
+                .long("builder")
+                .takes_value(true)
+                .possible_values(builder::NAMES)
+                .help("Build backend of hosts without a builder in the config (default sbuild)"),
         )
         .arg(
             Arg::with_name("retry")
@@ -239,10 +1693,208 @@ fn main() {
             Arg::with_name("arm64")
                 .long("arm64")
                 .takes_value(true)
-                .help("ARM64 builder"),
+                .help("ARM64 builder, in addition to the builders in the config"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .help("Config file (default scripts/pop-ci.json if it exists)"),
+        )
+        .arg(
+            Arg::with_name("local-repo")
+                .long("local-repo")
+                .takes_value(true)
+                .help("Address to serve pocket repositories to builders on (default 127.0.0.1:0, or all addresses on the port of --local-repo-url)"),
+        )
+        .arg(
+            Arg::with_name("local-repo-url")
+                .long("local-repo-url")
+                .takes_value(true)
+                .validator(local_repo_url_valid)
+                .help("URL builders use for pocket repositories, required for remote builders"),
+        )
+        .arg(
+            Arg::with_name("no-local-repo")
+                .long("no-local-repo")
+                .help("Do not serve pocket repositories to builders"),
+        )
+        .arg(
+            Arg::with_name("offline")
+                .long("offline")
+                .help("Build using the local copy of repositories made by the mirror command, without network access"),
+        )
+        .subcommand(
+            SubCommand::with_name("mirror")
+                .about("Copy the repositories builds use, for offline builds"),
+        )
+        .subcommand(
+            SubCommand::with_name("chroot")
+                .about("Manage the build environments of builders")
+                .arg(
+                    Arg::with_name("command")
+                        .required(true)
+                        .possible_values(
+                            &ChrootCommand::ALL
+                                .iter()
+                                .map(|command| command.id())
+                                .collect::<Vec<_>>(),
+                        )
+                        .help("Operation to run on each build environment"),
+                )
+                .arg(
+                    Arg::with_name("suite")
+                        .long("suite")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only use these suites"),
+                )
+                .arg(
+                    Arg::with_name("arch")
+                        .long("arch")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only use these architectures"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only use these builders"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pockets")
+                .about("List pockets with their state and last update"),
+        )
+        .subcommand(
+            SubCommand::with_name("promote")
+                .about("Copy the packages of a pocket into another repository")
+                .arg(
+                    Arg::with_name("pocket")
+                        .long("pocket")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Pocket to promote packages from"),
+                )
+                .arg(
+                    Arg::with_name("suite")
+                        .long("suite")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Suite to promote packages of"),
+                )
+                .arg(
+                    Arg::with_name("repo")
+                        .long("repo")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only promote packages of these repositories"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .help("Name of the target repository, release by default"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("Directory of the target repository, in the cache by default"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("refresh")
+                .about("Sign Release files again before they expire, without building packages")
+                .arg(
+                    Arg::with_name("pocket")
+                        .long("pocket")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only refresh these pockets"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Refresh Release files even if they do not expire soon"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about(
+                    "Restore a pocket to a snapshot, keeping it there until new commits are built for the pocket or the rollback is cleared",
+                )
+                .arg(
+                    Arg::with_name("pocket")
+                        .long("pocket")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Pocket to roll back"),
+                )
+                .arg(
+                    Arg::with_name("snapshot")
+                        .long("snapshot")
+                        .takes_value(true)
+                        .help("Snapshot to restore, the one before the newest by default"),
+                )
+                .arg(
+                    Arg::with_name("clear")
+                        .long("clear")
+                        .conflicts_with("snapshot")
+                        .help("Clear the rollback of the pocket, so that runs update it again"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Rebuild cached builds and check that they are reproducible")
+                .arg(
+                    Arg::with_name("repo")
+                        .long("repo")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only verify these repositories"),
+                )
+                .arg(
+                    Arg::with_name("commit")
+                        .long("commit")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only verify these commits"),
+                )
+                .arg(
+                    Arg::with_name("suite")
+                        .long("suite")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only verify these suites"),
+                )
+                .arg(
+                    Arg::with_name("arch")
+                        .long("arch")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Only verify these architectures"),
+                ),
         )
         .get_matches();
 
+    // The local repository is served on the port of its URL, unless an address is given
+    if let (None, Some(url), false) = (
+        matches.value_of("local-repo"),
+        matches.value_of("local-repo-url"),
+        matches.is_present("no-local-repo"),
+    ) {
+        if url_port(url).is_none() {
+            eprintln!(
+                bold!("ci: --local-repo-url {} is not an http URL with a valid port, use --local-repo to set the address to serve on"),
+                url
+            );
+            process::exit(1);
+        }
+    }
+
     let dev = matches.is_present("dev");
     let launchpad = matches.is_present("launchpad");
     let publish = matches.is_present("publish");
@@ -253,96 +1905,361 @@ fn main() {
             retry.push(retry_key.to_string());
         }
     }
-    let arm64_opt = matches.value_of("arm64");
-
-    let debemail = env::var("DEBEMAIL").expect("DEBEMAIL not set");
-    let debfullname = env::var("DEBFULLNAME").expect("DEBFULLNAME not set");
 
-    for suite in Suite::ALL.iter() {
-        let repo_info = RepoInfo::new(suite, dev);
-        for arch in repo_info.archs.iter() {
-            if arch.is_arm() && arm64_opt.is_none() {
-                continue;
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(path).expect("failed to load config"),
+        None if Path::new(DEFAULT_CONFIG).is_file() => {
+            Config::load(DEFAULT_CONFIG).expect("failed to load config")
+        }
+        None => Config::default(),
+    };
+
+    let offline_mirror = OfflineMirror::new(&config.offline.path);
+    if matches.subcommand_matches("mirror").is_some() {
+        for (url, source) in offline::sources(&config.mirrors, dev) {
+            eprintln!(
+                bold!("ci: mirroring {} to {}"),
+                url,
+                offline_mirror.path().display()
+            );
+            offline_mirror
+                .sync(
+                    &url,
+                    &source,
+                    config.offline.snapshot.as_deref(),
+                    &config.chroot.keyring,
+                )
+                .expect("failed to mirror repository");
+        }
+        return;
+    }
+
+    // Offline builds use the local copy of every repository, served to builders over HTTP or
+    // mounted into builders without network
+    let offline_opt = if matches.is_present("offline") {
+        let path = fs::canonicalize(offline_mirror.path()).expect("failed to find offline mirror");
+        let server = Server::spawn(offline_mirror.path(), "127.0.0.1:0")
+            .expect("failed to serve offline mirror");
+        let url = config.offline.url.clone().unwrap_or_else(|| server.url());
+        eprintln!(
+            bold!("ci: offline, serving {} on {}"),
+            offline_mirror.path().display(),
+            server.addr()
+        );
+        OfflineMirror::rewrite(&mut config.mirrors, &url);
+        for builder in config.builders.iter_mut() {
+            OfflineMirror::rewrite(&mut builder.mirrors, &url);
+        }
+        Some((url, path))
+    } else {
+        None
+    };
+
+    let mut builders = config.builders.clone();
+    if let Some(arm64) = matches.value_of("arm64") {
+        builders.push(BuilderConfig {
+            host: Some(arm64.to_string()),
+            arches: vec!["arm64".to_string(), "armhf".to_string()],
+            slots: 0,
+            workdir: None,
+            mirrors: config.mirrors.clone(),
+            builder: None,
+        });
+    }
+    // Hosts without a builder in the config use the builder of the run
+    let default_builder = matches.value_of("builder").unwrap_or("sbuild");
+    for host in builders.iter_mut() {
+        host.builder
+            .get_or_insert_with(|| default_builder.to_string());
+    }
+    let mut pool = BuilderPool::new(builders);
+    pool.health_check();
+    for host in pool.hosts() {
+        eprintln!(
+            bold!("ci: builder {}: {} with {} ({} slots)"),
+            host.name(),
+            host.arches.join(" "),
+            host.backend().name(),
+            if host.slots == 0 {
+                "unlimited".to_string()
+            } else {
+                host.slots.to_string()
+            }
+        );
+    }
+
+    let cache_path = if dev { "_build/ci-dev" } else { "_build/ci" };
+    let cache = Cache::new(cache_path, |name| {
+        name == "git"
+            || name == "apt"
+            || name == "chroot"
+            || name == "lintian"
+            || name == "log"
+            || name == "verify"
+            || name == "index"
+            || name == "pockets"
+            || name == "promoted"
+    })
+    .expect("failed to open build cache");
+
+    let pockets_path = cache.path().join("pockets");
+    if matches.subcommand_matches("pockets").is_some() {
+        let lifecycle = PocketLifecycle::load(pockets_path.join("state.json"))
+            .expect("failed to load pocket state");
+        for (pocket_name, state) in lifecycle.pockets().iter() {
+            let mut line = format!(
+                "{}: {}, updated {}",
+                pocket_name,
+                state.status(),
+                util::rfc3339(state.updated)
+            );
+            if let Some(absent_since) = state.absent_since {
+                write!(line, ", absent since {}", util::rfc3339(absent_since)).unwrap();
+            }
+            if let Some(archived) = state.archived {
+                write!(line, ", archived {}", util::rfc3339(archived)).unwrap();
+            }
+            if let Some(pinned) = &state.pinned {
+                write!(line, ", rolled back to {}", pinned).unwrap();
+            }
+            println!("{}", line);
+        }
+        return;
+    }
+
+    let chroots = Chroots::new(config.chroot.clone(), cache.path().join("chroot"));
+
+    if let Some(chroot_matches) = matches.subcommand_matches("chroot") {
+        let command = ChrootCommand::new(chroot_matches.value_of("command").unwrap()).unwrap();
+        let filter = |name: &str, value: &str| {
+            chroot_matches
+                .values_of(name)
+                .is_none_or(|mut values| values.any(|x| x == value))
+        };
+
+        let mut failed = false;
+        for suite in Suite::ALL.iter() {
+            if !filter("suite", suite.id()) {
+                continue;
+            }
+            for arch in RepoInfo::archs(suite, dev).iter() {
+                if !filter("arch", arch.id()) {
+                    continue;
+                }
+                for host in pool.hosts_for(arch) {
+                    if !filter("host", host.name()) {
+                        continue;
+                    }
+                    if let Err(err) = chroots.command(command, host, suite, arch) {
+                        eprintln!(
+                            bold!("ci: {}: {}: {}: chroot {} failed: {}"),
+                            host.name(),
+                            suite.id(),
+                            arch.id(),
+                            command,
+                            err
+                        );
+                        failed = true;
+                    }
+                }
+            }
+        }
+        if failed {
+            process::exit(1);
+        }
+        return;
+    }
+
+    let debemail = env::var("DEBEMAIL").expect("DEBEMAIL not set");
+    let debfullname = env::var("DEBFULLNAME").expect("DEBFULLNAME not set");
+    let signer = Signer::new(&config.signing, &debemail);
+
+    if let Some(refresh_matches) = matches.subcommand_matches("refresh") {
+        let apt_path = cache.path().join("apt");
+        for (pocket_name, pocket_path) in sorted_dirs(&apt_path).expect("failed to read apt cache")
+        {
+            if refresh_matches
+                .values_of("pocket")
+                .is_some_and(|mut values| !values.any(|x| x == pocket_name))
+            {
+                continue;
+            }
+            let dists_path = pocket_path.join("dists");
+            if !dists_path.is_dir() {
+                continue;
+            }
+            for (suite_name, suite_path) in
+                sorted_dirs(&dists_path).expect("failed to read dists cache")
+            {
+                if Suite::new(&suite_name).is_none() {
+                    // Skip previous and partial generations
+                    continue;
+                }
+                let refreshed = refresh_dists(
+                    &suite_path,
+                    config.valid_for(&pocket_name),
+                    config.dists.refresh_before,
+                    &signer,
+                    refresh_matches.is_present("force"),
+                )
+                .expect("failed to refresh suite dists");
+                if refreshed {
+                    eprintln!(bold!("ci: refreshed {} {}"), pocket_name, suite_name);
+                }
+            }
+        }
+        if publish {
+            publish_apt(&config, cache.path(), dev);
+        }
+        return;
+    }
+
+    if let Some(rollback_matches) = matches.subcommand_matches("rollback") {
+        let pocket_name = rollback_matches.value_of("pocket").unwrap();
+        let mut lifecycle = PocketLifecycle::load(pockets_path.join("state.json"))
+            .expect("failed to load pocket state");
+        if rollback_matches.is_present("clear") {
+            match lifecycle.unpin(pocket_name) {
+                Some(snapshot) => eprintln!(
+                    bold!("ci: cleared rollback of {} to {}"),
+                    pocket_name, snapshot
+                ),
+                None => eprintln!(bold!("ci: {} is not rolled back"), pocket_name),
             }
+            lifecycle.save().expect("failed to save pocket state");
+            return;
+        }
 
-            {
-                let script = format!(
-                    r#"#!/usr/bin/env bash
-
-CHROOT="/srv/chroot/{suite}-{arch}-sbuild"
-if [ ! -d "$CHROOT" ]
-then
-    set -ex
-
-    sudo auto-apt-proxy sbuild-createchroot \
-        '--include=gnupg,auto-apt-proxy' \
-        '--components=main,restricted,universe,multiverse' \
-        '--arch={arch}' \
-        '{suite}' \
-        "$CHROOT" \
-        '{ubuntu_mirror}'
-fi
-"#,
-                    arch = arch.id(),
-                    suite = suite.id(),
-                    ubuntu_mirror = arch.ubuntu_mirror(suite.id())
-                );
+        let pocket_path = cache.path().join("apt").join(pocket_name);
+        let snapshots = snapshot::list(&pocket_path).expect("failed to list snapshots");
+        // The newest snapshot is the current state, so the one before it is restored by default
+        let name = match rollback_matches.value_of("snapshot") {
+            Some(name) => name.to_string(),
+            None if snapshots.len() >= 2 => snapshots[snapshots.len() - 2].clone(),
+            None => {
+                eprintln!(bold!("ci: {} has no previous snapshot"), pocket_name);
+                process::exit(1);
+            }
+        };
+        if !snapshots.contains(&name) {
+            eprintln!(
+                bold!("ci: {} has no snapshot {}, available: {}"),
+                pocket_name,
+                name,
+                snapshots.join(" ")
+            );
+            process::exit(1);
+        }
 
-                if arch.is_arm() {
-                    let arm64 = arm64_opt.unwrap(); // checked above
-                    process::Command::new("ssh")
-                        .arg(arm64)
-                        .arg("--")
-                        .arg(script)
-                        .status()
-                        .and_then(check_status)
-                } else {
-                    process::Command::new("sh")
-                        .arg("-c")
-                        .arg(script)
-                        .status()
-                        .and_then(check_status)
-                }
-                .expect("failed to create sbuild chroot");
+        eprintln!(bold!("ci: rolling back {} to {}"), pocket_name, name);
+        snapshot::restore(&pocket_path, &name).expect("failed to restore snapshot");
+        // Runs would rebuild the pocket from the commits of its branches, so they leave it alone
+        // until those change
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        lifecycle.pin(pocket_name, &name, now);
+        lifecycle.save().expect("failed to save pocket state");
+        // The restored Release files are dated again, so clients do not see an older Release
+        for (suite_name, suite_path) in
+            sorted_dirs(&pocket_path.join("dists")).expect("failed to read dists")
+        {
+            if Suite::new(&suite_name).is_none() {
+                continue;
             }
+            refresh_dists(
+                &suite_path,
+                config.valid_for(pocket_name),
+                config.dists.refresh_before,
+                &signer,
+                true,
+            )
+            .expect("failed to refresh suite dists");
+        }
+        if publish {
+            publish_apt(&config, cache.path(), dev);
+        }
+        return;
+    }
 
-            if sbuild_update {
-                let script = format!(
-                    r#"#!/usr/bin/env bash
-
-set -ex
-
-sudo sbuild-update \
-    '--update' \
-    '--dist-upgrade' \
-    '--clean' \
-    '--autoclean' \
-    '--autoremove' \
-    '--arch={arch}' \
-    '{suite}' \
-"#,
-                    arch = arch.id(),
-                    suite = suite.id()
-                );
+    if let Some(promote_matches) = matches.subcommand_matches("promote") {
+        let index_cache = IndexCache::new(cache.path().join("index"));
+        let component_rules = config.component_rules();
+        promote(
+            promote_matches,
+            cache.path(),
+            &DistsContext {
+                config: &config,
+                component_rules: &component_rules,
+                index_cache: &index_cache,
+                signer: &signer,
+            },
+            dev,
+        )
+        .expect("failed to promote packages");
+        return;
+    }
 
-                if arch.is_arm() {
-                    let arm64 = arm64_opt.unwrap(); // checked above
-                    process::Command::new("ssh")
-                        .arg(arm64)
-                        .arg("--")
-                        .arg(script)
-                        .status()
-                        .and_then(check_status)
-                } else {
-                    process::Command::new("sh")
-                        .arg("-c")
-                        .arg(script)
-                        .status()
-                        .and_then(check_status)
-                }
-                .expect("failed to update sbuild chroot");
+    let local_repo_opt = if matches.is_present("no-local-repo") {
+        None
+    } else {
+        let path = cache.path().join("apt");
+        if !path.is_dir() {
+            fs::create_dir(&path).expect("failed to create apt cache");
+        }
+        // Builders that are given a URL reach the server over the network
+        let bind = match (
+            matches.value_of("local-repo"),
+            matches.value_of("local-repo-url"),
+        ) {
+            (Some(bind), _) => bind.to_string(),
+            (None, Some(url)) => format!(
+                "0.0.0.0:{}",
+                url_port(url).expect("--local-repo-url has no port")
+            ),
+            (None, None) => "127.0.0.1:0".to_string(),
+        };
+        let server = Server::spawn(&path, &bind).expect("failed to serve local repository");
+        eprintln!(bold!("ci: serving local repository on {}"), server.addr());
+        Some(LocalRepo {
+            path,
+            url: matches
+                .value_of("local-repo-url")
+                .map_or_else(|| server.url(), |url| url.trim_end_matches('/').to_string()),
+            remote: matches.is_present("local-repo-url"),
+        })
+    };
+
+    if let Some(verify_matches) = matches.subcommand_matches("verify") {
+        let mut environments = BTreeSet::new();
+        for suite in Suite::ALL.iter() {
+            for arch in RepoInfo::archs(suite, dev).iter() {
+                environments.insert((suite.clone(), arch.clone()));
             }
         }
+        prepare_environments(&mut pool, &chroots, &environments);
+
+        let all_reproducible = verify_builds(
+            verify_matches,
+            cache.path(),
+            &RunContext {
+                chroots: &chroots,
+                config: &config,
+                lintian_history: LintianHistory::new(cache.path().join("lintian")),
+                local_repo_opt: local_repo_opt.as_ref(),
+                offline_opt: offline_opt
+                    .as_ref()
+                    .map(|(url, path)| OfflineRepos { url, path }),
+                pool: &pool,
+                signer: &signer,
+            },
+            dev,
+        )
+        .expect("failed to verify builds");
+        if !all_reproducible {
+            process::exit(1);
+        }
+        return;
     }
 
     let mut repos = BTreeMap::new();
@@ -383,20 +2300,14 @@ sudo sbuild-update \
         async_std::task::block_on(async_fetch_repos(&repos, &remote));
     }
 
-    let cache_path = if dev { "_build/ci-dev" } else { "_build/ci" };
-    let cache = Cache::new(cache_path, |name| {
-        name == "git" || name == "apt" || name == "log"
-    })
-    .expect("failed to open build cache");
-
     let git_cache = cache
         .child("git", |name| repos.contains_key(name))
         .expect("failed to open git cache");
 
-    let ci_ctx_mtx = Arc::new(Mutex::new(CiContext::default()));
+    // Branches are mapped to pockets and suites first, so that only the build environments of
+    // suites with builds are prepared
+    let mut repo_ctxs = Vec::new();
     for (repo_name, repo_path) in repos.iter() {
-        eprintln!(bold!("{}"), repo_name);
-
         let repo = GitRepo::new(repo_path).expect("failed to open git repo");
         let heads = repo
             .heads(&remote)
@@ -458,8 +2369,28 @@ sudo sbuild-update \
                 .insert(pocket.clone());
         }
 
+        repo_ctxs.push((repo_name, repo, repo_ctx));
+    }
+
+    let mut environments = BTreeSet::new();
+    for (_, _, repo_ctx) in repo_ctxs.iter() {
+        for build in repo_ctx.builds.values() {
+            for suite in build.suites.keys() {
+                for arch in RepoInfo::archs(suite, dev).iter() {
+                    environments.insert((suite.clone(), arch.clone()));
+                }
+            }
+        }
+    }
+    prepare_environments(&mut pool, &chroots, &environments);
+
+    let ci_ctx_mtx = Arc::new(Mutex::new(CiContext::default()));
+    let mut suite_builds = Vec::new();
+    for &(repo_name, ref repo, ref repo_ctx) in repo_ctxs.iter() {
+        eprintln!(bold!("{}"), repo_name);
+
         let repo_cache = git_cache
-            .child(&repo_name, |name| {
+            .child(repo_name, |name| {
                 repo_ctx.builds.contains_key(&GitCommit::new(name))
             })
             .expect("failed to open repo cache");
@@ -479,7 +2410,7 @@ sudo sbuild-update \
             eprintln!(bold!("{}: {}"), repo_name, commit_name);
 
             if !repo
-                .file_exists(&commit, "debian/changelog")
+                .file_exists(commit, "debian/changelog")
                 .expect("failed to check for debian/changelog")
             {
                 eprintln!(bold!("{}: {}: no debian changelog"), repo_name, commit_name);
@@ -489,12 +2420,12 @@ sudo sbuild-update \
             let mut commit_cache = repo_cache
                 .child(commit.id(), |name| {
                     name == "archive.tar.gz"
-                        || Suite::new(name).map_or(false, |suite| build.suites.contains_key(&suite))
+                        || Suite::new(name).is_some_and(|suite| build.suites.contains_key(&suite))
                 })
                 .expect("failed to open commit cache");
 
             let (archive_tar, archive_rebuilt) = commit_cache
-                .build("archive.tar.gz", false, |path| repo.archive(&commit, path))
+                .build("archive.tar.gz", false, |path| repo.archive(commit, path))
                 .expect("failed to build git archive");
 
             let commit_timestamp = {
@@ -531,17 +2462,16 @@ sudo sbuild-update \
                 str::from_utf8(&output.stdout).unwrap().trim().to_owned()
             };
 
-            let mut suite_builds = BTreeMap::new();
             for (suite, pockets) in build.suites.iter() {
                 let suite_name = format!("{} ({})", suite.id(), suite.version());
 
                 eprintln!(bold!("{}: {}: {}"), repo_name, commit_name, suite_name);
 
-                let repo_info = RepoInfo::new(suite, dev);
+                let archs = RepoInfo::archs(suite, dev);
 
                 let mut suite_cache = commit_cache
                     .child(suite.id(), |name| {
-                        name == "source" || repo_info.archs.iter().any(|arch| arch.id() == name)
+                        name == "source" || archs.iter().any(|arch| arch.id() == name)
                     })
                     .expect("failed to open suite cache");
 
@@ -594,56 +2524,26 @@ sudo sbuild-update \
                     continue;
                 }
 
-                let github_status = {
-                    let commit_name = commit_name.clone();
-                    let suite_name = suite_name.clone();
-                    move |step: &str, status: &str| {
-                        let target_url = match env::var("BUILD_URL") {
-                            Ok(some) => some,
-                            Err(_) => return,
-                        };
-
-                        eprintln!(
-                            bold!("{}: {}: {}: {} github status {}"),
-                            repo_name, commit_name, suite_name, step, status
-                        );
-
-                        let (context, description) = if dev {
-                            (
-                                format!("ubuntu/staging/{}/{}", suite.id(), step),
-                                format!("Ubuntu Staging {} {}", suite.id(), step),
-                            )
-                        } else {
-                            (
-                                format!("pop-os/staging/{}/{}", suite.id(), step),
-                                format!("Pop!_OS Staging {} {}", suite.id(), step),
-                            )
-                        };
-
-                        match github_status_inner(
-                            &repo_name,
-                            &commit,
-                            &context,
-                            &description,
-                            status,
-                            &target_url,
-                        ) {
-                            Ok(()) => (),
-                            Err(err) => eprintln!(
-                                bold!("{}: {}: {}: {} github status {} failed: {}"),
-                                repo_name, commit_name, suite_name, step, status, err
-                            ),
-                        }
-                    }
+                let info = SuiteInfo {
+                    dev,
+                    repo_name: repo_name.clone(),
+                    commit: commit.clone(),
+                    commit_name: commit_name.clone(),
+                    branches: build
+                        .branches
+                        .iter()
+                        .map(|branch| branch.id().to_string())
+                        .collect(),
+                    suite: suite.clone(),
+                    suite_name: suite_name.clone(),
                 };
-
                 let source_res = suite_cache.build("source", archive_rebuilt, |path| {
                     eprintln!(
                         bold!("{}: {}: {}: source building"),
                         repo_name, commit_name, suite_name
                     );
-                    github_status("source", "pending");
-                    fs::create_dir(&path)?;
+                    info.github_status("source", "pending");
+                    fs::create_dir(path)?;
 
                     let archive = path.join("archive");
                     fs::create_dir(&archive)?;
@@ -791,7 +2691,7 @@ sudo sbuild-update \
                             repo_name, commit_name, suite_name
                         );
                         if ok.1 {
-                            github_status("source", "success");
+                            info.github_status("source", "success");
                         }
                         ok
                     }
@@ -800,7 +2700,7 @@ sudo sbuild-update \
                             bold!("{}: {}: {}: source failed: {}"),
                             repo_name, commit_name, suite_name, err
                         );
-                        github_status("source", "failure");
+                        info.github_status("source", "failure");
 
                         let partial_source_dir = suite_cache.path().join("partial.source");
                         if partial_source_dir.is_dir() {
@@ -848,6 +2748,8 @@ sudo sbuild-update \
                     tars: BTreeMap::new(),
                     archs: Vec::new(),
                     debs: BTreeMap::new(),
+                    ddebs: BTreeMap::new(),
+                    attestations: BTreeMap::new(),
                 };
 
                 for entry_res in
@@ -892,15 +2794,13 @@ sudo sbuild-update \
                 }
                 let (_dsc_name, dsc_path) = package.dscs.iter().next().unwrap();
 
-                let dsc = fs::read_to_string(&dsc_path).expect("failed to read .dsc file");
+                let dsc = fs::read_to_string(dsc_path).expect("failed to read .dsc file");
                 for line in dsc.lines() {
                     if line.starts_with("Architecture: ") {
-                        for arch in repo_info.archs.iter() {
-                            if arch.is_arm() {
-                                // Skip arm64 builds if there is no arm64 builder
-                                if arm64_opt.is_none() {
-                                    continue;
-                                }
+                        for arch in archs.iter() {
+                            // Skip builds if there is no ready builder for this suite and arch
+                            if !pool.supports_suite(suite, arch) {
+                                continue;
                             }
 
                             for part in line.split(' ') {
@@ -908,7 +2808,7 @@ sudo sbuild-update \
                                     || part == "any"
                                     || (part == "all" && arch.build_all())
                                     || (part == "linux-any" && arch.build_linux_any())
-                                    || (part == &format!("linux-{}", arch.id()))
+                                    || (part == format!("linux-{}", arch.id()))
                                 {
                                     package.archs.push(arch.clone());
                                     break;
@@ -918,11 +2818,11 @@ sudo sbuild-update \
                     }
                 }
 
-                let mut binary_builds = BTreeMap::new();
+                let mut binary_archs = Vec::new();
                 for arch in package.archs.iter() {
                     let mut binary_retry = source_retry;
                     for retry_key in &[format!("arch:{}", arch.id())] {
-                        if retry.contains(&retry_key) {
+                        if retry.contains(retry_key) {
                             binary_retry = true;
                             break;
                         }
@@ -957,7 +2857,7 @@ sudo sbuild-update \
                                 ci_ctx
                                     .pocket_logs
                                     .entry(pocket.clone())
-                                    .or_insert(BTreeMap::new())
+                                    .or_default()
                                     .insert(
                                         binary_log_name.clone(),
                                         (binary_log_path.clone(), false)
@@ -968,215 +2868,366 @@ sudo sbuild-update \
                         continue;
                     }
 
-                    let commit_name = commit_name.clone();
-                    let github_status = github_status.clone();
-                    let repo_name = repo_name.clone();
-                    let suite_name = suite_name.clone();
-                    let binary_ctx = BinaryContext {
-                        arch: arch.clone(),
-                        arm64_opt,
-                        dsc_path: dsc_path.clone(),
-                        repo_info: repo_info.clone(),
-                        source: source.clone(),
-                        suite: suite.clone(),
-                    };
-                    binary_builds.insert(arch.id().to_string(), move |path: &Path| {
-                        eprintln!(
-                            bold!("{}: {}: {}: {}: binary building"),
-                            repo_name,
-                            commit_name,
-                            suite_name,
-                            binary_ctx.arch.id()
-                        );
-                        github_status(&format!("binary-{}", binary_ctx.arch.id()), "pending");
-                        match binary_build(&binary_ctx, path) {
-                            Ok(()) => {
-                                eprintln!(
-                                    bold!("{}: {}: {}: {}: binary built"),
-                                    repo_name,
-                                    commit_name,
-                                    suite_name,
-                                    binary_ctx.arch.id()
-                                );
-                                github_status(
-                                    &format!("binary-{}", binary_ctx.arch.id()),
-                                    "success",
-                                );
-                                Ok(())
-                            }
-                            Err(err) => {
-                                eprintln!(
-                                    bold!("{}: {}: {}: {}: binary failed: {}"),
-                                    repo_name,
-                                    commit_name,
-                                    suite_name,
-                                    binary_ctx.arch.id(),
-                                    err
-                                );
-                                github_status(
-                                    &format!("binary-{}", binary_ctx.arch.id()),
-                                    "failure",
-                                );
-                                Err(err)
-                            }
-                        }
-                    });
+                    binary_archs.push(arch.clone());
                 }
 
-                let ci_ctx_mtx = ci_ctx_mtx.clone();
-                let repo_name = repo_name.clone();
-                suite_builds.insert(suite, move || {
-                    let binary_results =
-                        suite_cache.build_parallel(binary_builds.clone(), source_rebuilt);
-
-                    let mut binaries_failed = false;
-                    for (arch_id, binary_result) in binary_results.iter() {
-                        match binary_result {
-                            Ok((binary, binary_rebuilt)) => {
-                                if *binary_rebuilt {
-                                    package.rebuilt = true;
-                                }
-
-                                for entry_res in fs::read_dir(&binary)
-                                    .expect("failed to read suite binary directory")
-                                {
-                                    let entry =
-                                        entry_res.expect("failed to read suite binary entry");
-                                    let file_name = entry
-                                        .file_name()
-                                        .into_string()
-                                        .expect("suite binary filename is not utf-8");
-                                    if file_name.ends_with(".deb") {
-                                        assert_eq!(
-                                            package.debs.insert(file_name, entry.path()),
-                                            None
-                                        );
-                                    }
-                                }
-                            }
-                            Err(_err) => {
-                                binaries_failed = true;
-
-                                let partial_binary_dir =
-                                    suite_cache.path().join(format!("partial.{}", arch_id));
-                                if partial_binary_dir.is_dir() {
-                                    for entry_res in fs::read_dir(&partial_binary_dir)
-                                        .expect("failed to read partial binary directory")
-                                    {
-                                        let entry =
-                                            entry_res.expect("failed to read partial binary entry");
-                                        let file_name = entry
-                                            .file_name()
-                                            .into_string()
-                                            .expect("partial binary filename is not utf-8");
-                                        if file_name.ends_with(&format!("_{}.build", arch_id)) {
-                                            let binary_log_name = format!(
-                                                "{}_{}_{}_{}.log",
-                                                repo_name,
-                                                commit.id(),
-                                                suite.id(),
-                                                arch_id
-                                            );
-                                            let mut ci_ctx = ci_ctx_mtx.lock().unwrap();
-                                            assert_eq!(
-                                                ci_ctx.logs.insert(
-                                                    binary_log_name.clone(),
-                                                    (entry.path(), true)
-                                                ),
-                                                None
-                                            );
-                                            for pocket in pockets.iter() {
-                                                assert_eq!(
-                                                    ci_ctx
-                                                        .pocket_logs
-                                                        .entry(pocket.clone())
-                                                        .or_insert(BTreeMap::new())
-                                                        .insert(
-                                                            binary_log_name.clone(),
-                                                            (entry.path(), true)
-                                                        ),
-                                                    None
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    if !binaries_failed {
-                        let mut ci_ctx = ci_ctx_mtx.lock().unwrap();
-                        for pocket in pockets.iter() {
-                            assert_eq!(
-                                ci_ctx
-                                    .pocket_packages
-                                    .entry(pocket.clone())
-                                    .or_insert(BTreeMap::new())
-                                    .entry(suite.clone())
-                                    .or_insert(BTreeMap::new())
-                                    .insert(repo_name.clone(), (commit.clone(), package.clone())),
-                                None
-                            );
-                        }
-                    }
+                suite_builds.push(SuiteBuild {
+                    info,
+                    pockets: pockets.clone(),
+                    suite_cache,
+                    dsc: Control::parse(&dsc),
+                    dsc_path: dsc_path.clone(),
+                    package,
+                    source,
+                    source_rebuilt,
+                    binary_archs,
                 });
             }
+        }
+    }
+
+    // Only build environments that will be used are updated
+    if sbuild_update {
+        let mut update_archs = BTreeSet::new();
+        for build in suite_builds.iter() {
+            for arch in build.binary_archs.iter() {
+                update_archs.insert((build.info.suite.clone(), arch.clone()));
+            }
+        }
+        for (suite, arch) in update_archs.iter() {
+            for host in pool.ready_hosts(suite, arch) {
+                eprintln!(
+                    bold!("ci: {}: updating {}"),
+                    host.name(),
+                    host.backend().environment(suite, arch)
+                );
+                // A failed update is rolled back, so builds can continue in the old environment
+                if let Err(err) = chroots.update(host, suite, arch) {
+                    eprintln!(
+                        bold!("ci: {}: failed to update {}: {}"),
+                        host.name(),
+                        host.backend().environment(suite, arch),
+                        err
+                    );
+                }
+            }
+        }
+    }
 
-            crossbeam::thread::scope(|s| {
-                let mut threads = BTreeMap::new();
+    // Binary builds are scheduled so that a package is built after the packages it depends on
+    // from the same pocket and suite, with their freshly built debs available to it. A build is
+    // published to all of its pockets, so it only uses debs of packages in every one of them.
+    let suite_binaries: Vec<Vec<String>> = suite_builds
+        .iter()
+        .map(|build| build.dsc.binaries())
+        .collect();
+    let mut suite_deps = BTreeMap::new();
+    for (i, build) in suite_builds.iter().enumerate() {
+        let build_depends = build.dsc.build_depends();
+        let mut deps = BTreeSet::new();
+        for (j, other) in suite_builds.iter().enumerate() {
+            if i == j || other.info.suite != build.info.suite {
+                continue;
+            }
+            if !suite_binaries[j]
+                .iter()
+                .any(|binary| build_depends.contains(binary))
+            {
+                continue;
+            }
+            if !build.pockets.is_subset(&other.pockets) {
+                eprintln!(
+                    bold!("{}: {}: {}: not using {}: {}, which is not in all of its pockets"),
+                    build.info.repo_name,
+                    build.info.commit_name,
+                    build.info.suite_name,
+                    other.info.repo_name,
+                    other.info.commit_name
+                );
+                continue;
+            }
+            deps.insert(j);
+        }
+        suite_deps.insert(i, deps);
+    }
 
-                for (suite, mut f) in suite_builds {
-                    threads.insert(suite, s.spawn(move |_| f()));
+    let run_ctx = &RunContext {
+        chroots: &chroots,
+        config: &config,
+        lintian_history: LintianHistory::new(cache.path().join("lintian")),
+        local_repo_opt: local_repo_opt.as_ref(),
+        offline_opt: offline_opt
+            .as_ref()
+            .map(|(url, path)| OfflineRepos { url, path }),
+        pool: &pool,
+        signer: &signer,
+    };
+    let waves = graph::waves(&suite_deps);
+    let suite_names: Vec<String> = suite_builds
+        .iter()
+        .map(|build| format!("{}: {}", build.info.repo_name, build.info.suite_name))
+        .collect();
+    let mut suite_builds: Vec<Option<SuiteBuild>> = suite_builds.into_iter().map(Some).collect();
+    let mut finished = BTreeSet::new();
+    let mut built_debs = BTreeMap::<usize, Vec<PathBuf>>::new();
+    for (wave_i, wave) in waves.iter().enumerate() {
+        eprintln!(
+            bold!("ci: building wave {} of {} ({} suites)"),
+            wave_i + 1,
+            waves.len(),
+            wave.len()
+        );
+
+        // Suites of the same commit are built in parallel
+        let mut commit_builds = BTreeMap::<(String, GitCommit), Vec<_>>::new();
+        for i in wave.iter() {
+            let build = suite_builds[*i].take().unwrap();
+
+            let mut extra_debs = Vec::new();
+            let mut deps_failed = Vec::new();
+            for dep in suite_deps[i].iter() {
+                match built_debs.get(dep) {
+                    Some(debs) => extra_debs.extend_from_slice(debs),
+                    // Dependencies that have not finished are in a cycle within this wave
+                    None if finished.contains(dep) => deps_failed.push(suite_names[*dep].clone()),
+                    None => (),
                 }
+            }
+
+            if !deps_failed.is_empty() {
+                build.dependencies_failed(&deps_failed, &ci_ctx_mtx);
+                finished.insert(*i);
+                continue;
+            }
+
+            if !suite_deps[i].is_empty() {
+                eprintln!(
+                    bold!("{}: {}: {}: depends on {}"),
+                    build.info.repo_name,
+                    build.info.commit_name,
+                    build.info.suite_name,
+                    suite_deps[i]
+                        .iter()
+                        .map(|dep| suite_names[*dep].as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
 
-                for (_suite, thread) in threads {
-                    thread.join().unwrap();
+            commit_builds
+                .entry((build.info.repo_name.clone(), build.info.commit.clone()))
+                .or_default()
+                .push((*i, build, extra_debs));
+        }
+
+        for (_, builds) in commit_builds {
+            let results = crossbeam::thread::scope(|s| {
+                let mut threads = Vec::new();
+
+                for (i, build, extra_debs) in builds {
+                    let ci_ctx_mtx = ci_ctx_mtx.clone();
+                    threads.push((
+                        i,
+                        s.spawn(move |_| build.run(&extra_debs, run_ctx, &ci_ctx_mtx)),
+                    ));
                 }
+
+                threads
+                    .into_iter()
+                    .map(|(i, thread)| (i, thread.join().unwrap()))
+                    .collect::<Vec<_>>()
             })
             .unwrap();
+
+            for (i, debs_opt) in results {
+                finished.insert(i);
+                if let Some(debs) = debs_opt {
+                    built_debs.insert(i, debs);
+                }
+            }
+        }
+    }
+
+    let mut ci_ctx = ci_ctx_mtx.lock().unwrap();
+
+    // Pockets that stop having packages stay published for a grace period, and pockets that
+    // expire or end their grace period are moved to the archive
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let apt_path = cache.path().join("apt");
+    let archive_path = pockets_path.join("archive");
+    let mut lifecycle = PocketLifecycle::load(pockets_path.join("state.json"))
+        .expect("failed to load pocket state");
+    if apt_path.is_dir() {
+        for (pocket_name, _) in sorted_dirs(&apt_path).expect("failed to read apt cache") {
+            lifecycle.track(&pocket_name, now);
+        }
+    }
+    // Packages held back by a gating QA stage keep the commit already in their pocket's pool
+    let mut kept_packages = BTreeMap::<Pocket, BTreeMap<Suite, BTreeMap<String, String>>>::new();
+    for (pocket, suite_repos) in ci_ctx.gated_packages.iter() {
+        for (suite, repo_names) in suite_repos.iter() {
+            for repo_name in repo_names.iter() {
+                let repo_pool_path = apt_path
+                    .join(pocket.id())
+                    .join("pool")
+                    .join(suite.id())
+                    .join(repo_name);
+                if !repo_pool_path.is_dir() {
+                    continue;
+                }
+                let commit_dirs = sorted_dirs(&repo_pool_path).expect("failed to read repo pool");
+                if let Some((commit_id, _)) = commit_dirs.into_iter().next() {
+                    eprintln!(
+                        bold!("ci: pocket {}: {}: {}: keeping published commit {}"),
+                        pocket.id(),
+                        suite.id(),
+                        repo_name,
+                        commit_id
+                    );
+                    kept_packages
+                        .entry(pocket.clone())
+                        .or_default()
+                        .entry(suite.clone())
+                        .or_default()
+                        .insert(repo_name.clone(), commit_id);
+                }
+            }
+        }
+    }
+    let mut present = BTreeMap::<String, Vec<String>>::new();
+    for (pocket, suite_packages) in ci_ctx.pocket_packages.iter() {
+        let commits = present.entry(pocket.id().to_string()).or_default();
+        for (suite, repo_packages) in suite_packages.iter() {
+            for (repo_name, (commit, _)) in repo_packages.iter() {
+                commits.push(format!("{}/{}/{}", suite.id(), repo_name, commit.id()));
+            }
+        }
+    }
+    for (pocket, suite_commits) in kept_packages.iter() {
+        let commits = present.entry(pocket.id().to_string()).or_default();
+        for (suite, repo_commits) in suite_commits.iter() {
+            for (repo_name, commit_id) in repo_commits.iter() {
+                commits.push(format!("{}/{}/{}", suite.id(), repo_name, commit_id));
+            }
+            // Keep the suite in the loop below so its pool and dists are not cleaned
+            ci_ctx
+                .pocket_packages
+                .entry(pocket.clone())
+                .or_default()
+                .entry(suite.clone())
+                .or_default();
+        }
+    }
+    for commits in present.values_mut() {
+        commits.sort();
+    }
+    let mut changelog = Changelog::default();
+    for pocket_name in lifecycle.update(&present, now, &config) {
+        eprintln!(bold!("ci: archiving pocket {}"), pocket_name);
+        changelog.archive(&pocket_name);
+        let pocket_archive = archive_path.join(&pocket_name);
+        if pocket_archive.exists() {
+            fs::remove_dir_all(&pocket_archive).expect("failed to remove old pocket archive");
+        }
+        if apt_path.join(&pocket_name).is_dir() {
+            fs::create_dir_all(&archive_path).expect("failed to create pocket archive");
+            fs::rename(apt_path.join(&pocket_name), &pocket_archive)
+                .expect("failed to archive pocket");
+        }
+    }
+    for pocket_name in lifecycle.prune(now, &config) {
+        eprintln!(bold!("ci: removing archive of pocket {}"), pocket_name);
+        let pocket_archive = archive_path.join(&pocket_name);
+        if pocket_archive.exists() {
+            fs::remove_dir_all(&pocket_archive).expect("failed to remove pocket archive");
+        }
+    }
+    for (pocket_name, state) in lifecycle.pockets().iter() {
+        if state.archived.is_none() && archive_path.join(pocket_name).exists() {
+            // The archive of a pocket that is published again is out of date
+            fs::remove_dir_all(archive_path.join(pocket_name))
+                .expect("failed to remove pocket archive");
+        }
+        if let (Some(pinned), None) = (&state.pinned, state.archived) {
+            eprintln!(
+                bold!("ci: pocket {} is rolled back to {}, keeping it until its commits change"),
+                pocket_name, pinned
+            );
+        }
+        if let (Some(absent_since), None) = (state.absent_since, state.archived) {
+            eprintln!(
+                bold!("ci: pocket {} has no packages since {}, keeping it published until {}"),
+                pocket_name,
+                util::rfc3339(absent_since),
+                util::rfc3339(absent_since + config.lifecycle.grace.as_secs())
+            );
         }
     }
+    lifecycle.save().expect("failed to save pocket state");
+    ci_ctx.pocket_packages.retain(|pocket, _| {
+        lifecycle.is_published(pocket.id()) && lifecycle.pinned(pocket.id()).is_none()
+    });
 
-    let ci_ctx = ci_ctx_mtx.lock().unwrap();
     let apt_cache = cache
-        .child("apt", |name| {
-            ci_ctx.pocket_packages.contains_key(&Pocket::new(name))
-        })
+        .child("apt", |name| lifecycle.is_published(name))
         .expect("failed to open apt cache");
 
+    // Stanzas of debs are kept between runs, so only changed debs are read
+    let index_cache = IndexCache::new(cache.path().join("index"));
+    let component_rules = config.component_rules();
+    let dists_ctx = DistsContext {
+        config: &config,
+        component_rules: &component_rules,
+        index_cache: &index_cache,
+        signer: &signer,
+    };
+
     for (pocket, suite_packages) in ci_ctx.pocket_packages.iter() {
         eprintln!(bold!("pocket: {}"), pocket.id());
 
         let pocket_cache = apt_cache
-            .child(pocket.id(), |name| name == "dists" || name == "pool")
+            .child(pocket.id(), |name| {
+                name == "dists" || name == "pool" || name == snapshot::SNAPSHOTS
+            })
             .expect("failed to open pocket cache");
 
         let pool_cache = pocket_cache
             .child("pool", |name| {
-                Suite::new(name).map_or(false, |suite| suite_packages.contains_key(&suite))
+                Suite::new(name).is_some_and(|suite| suite_packages.contains_key(&suite))
             })
             .expect("failed to open pool cache");
 
         let mut dists_cache = pocket_cache
             .child("dists", |name| {
-                Suite::new(name).map_or(false, |suite| suite_packages.contains_key(&suite))
+                Suite::new(name).is_some_and(|suite| suite_packages.contains_key(&suite))
             })
             .expect("failed to open dists cache");
 
-        let mut pool_rebuilt = false;
+        let mut dists_changed = false;
         for (suite, repo_packages) in suite_packages.iter() {
             eprintln!(bold!("  suite: {} ({})"), suite.id(), suite.version());
 
+            // Only the dists of suites whose pool changed are rebuilt
+            let mut pool_rebuilt = false;
+
+            let kept_repos = kept_packages
+                .get(pocket)
+                .and_then(|suite_commits| suite_commits.get(suite));
             let suite_pool_cache = pool_cache
-                .child(suite.id(), |name| repo_packages.contains_key(name))
+                .child(suite.id(), |name| {
+                    repo_packages.contains_key(name)
+                        || kept_repos.is_some_and(|repo_commits| repo_commits.contains_key(name))
+                })
                 .expect("failed to open suite pool cache");
 
             if suite_pool_cache.cleaned() {
                 pool_rebuilt = true;
             }
 
-            let repo_info = RepoInfo::new(suite, dev);
+            let archs = RepoInfo::archs(suite, dev);
+            let dput = config.mirrors.dput(suite.id(), dev);
 
             for (repo_name, (commit, package)) in repo_packages.iter() {
                 eprintln!(bold!("    package: {}: {}"), repo_name, commit.id());
@@ -1191,7 +3242,7 @@ sudo sbuild-update \
 
                 let (_, repo_pool_rebuilt) = repo_pool_cache
                     .build(commit.id(), package.rebuilt, |path| {
-                        fs::create_dir(&path)?;
+                        fs::create_dir(path)?;
 
                         for (dsc_name, dsc_path) in package.dscs.iter() {
                             eprintln!("      dsc: {}", dsc_name);
@@ -1208,6 +3259,20 @@ sudo sbuild-update \
                             fs::copy(deb_path, path.join(deb_name))?;
                         }
 
+                        for (ddeb_name, ddeb_path) in package.ddebs.iter() {
+                            eprintln!("      ddeb: {}", ddeb_name);
+                            fs::copy(ddeb_path, path.join(ddeb_name))?;
+                            if let Some(debuginfod_path) = &config.debuginfod.path {
+                                let exported = debuginfod::export(ddeb_path, debuginfod_path)?;
+                                eprintln!("        exported {} debug files", exported);
+                            }
+                        }
+
+                        for (attestation_name, attestation_path) in package.attestations.iter() {
+                            eprintln!("      attestation: {}", attestation_name);
+                            fs::copy(attestation_path, path.join(attestation_name))?;
+                        }
+
                         Ok(())
                     })
                     .expect("failed to build commit cache");
@@ -1218,7 +3283,7 @@ sudo sbuild-update \
 
                 if pocket.id() == "master" && launchpad {
                     for (changes_name, changes_path) in package.changes.iter() {
-                        let dput = match repo_info.dput {
+                        let dput = match dput {
                             Some(some) => some,
                             None => continue,
                         };
@@ -1240,7 +3305,7 @@ sudo sbuild-update \
                         eprintln!(bold!("      launchpad upload to {}"), dput);
                         let dput_res = process::Command::new("dput")
                             .arg(dput)
-                            .arg(&changes_path)
+                            .arg(changes_path)
                             .status()
                             .and_then(check_status);
                         match dput_res {
@@ -1258,211 +3323,101 @@ sudo sbuild-update \
                 }
             }
 
-            dists_cache
-                .build(suite.id(), pool_rebuilt, |path| {
-                    fs::create_dir(&path)?;
-
-                    let pool_relative = Path::new("pool").join(suite.id());
-                    let main_dir = path.join("main");
-                    fs::create_dir(&main_dir)?;
-
-                    {
-                        let source_dir = main_dir.join("source");
-                        fs::create_dir(&source_dir)?;
-
-                        let source_output = process::Command::new("apt-ftparchive")
-                            .arg("-qq")
-                            .arg("sources")
-                            .arg(&pool_relative)
-                            .current_dir(&pocket_cache.path())
-                            .stdout(process::Stdio::piped())
-                            .spawn()?
-                            .wait_with_output()
-                            .and_then(check_output)?;
-
-                        let source_file = source_dir.join("Sources");
-                        fs::write(&source_file, &source_output.stdout)?;
-                        process::Command::new("gzip")
-                            .arg("--keep")
-                            .arg(&source_file)
-                            .status()
-                            .and_then(check_status)?;
-
-                        let mut release = String::new();
-                        writeln!(release, "Archive: {}", suite.id()).unwrap();
-                        writeln!(release, "Version: {}", suite.version()).unwrap();
-                        writeln!(release, "Component: main").unwrap();
-                        writeln!(release, "Origin: {}", pocket.origin(dev)).unwrap();
-                        writeln!(release, "Label: {}", pocket.label(dev)).unwrap();
-                        writeln!(release, "Architecture: source").unwrap();
-
-                        let release_file = source_dir.join("Release");
-                        fs::write(&release_file, release)?;
-                    }
-
-                    let mut archs_string = String::new();
-                    for arch in repo_info.archs.iter() {
-                        let binary_dir = main_dir.join(format!("binary-{}", arch.id()));
-                        fs::create_dir(&binary_dir)?;
-
-                        let packages_output = process::Command::new("apt-ftparchive")
-                            .arg("-qq")
-                            .arg("--arch")
-                            .arg(arch.id())
-                            .arg("packages")
-                            .arg(&pool_relative)
-                            .current_dir(&pocket_cache.path())
-                            .stdout(process::Stdio::piped())
-                            .spawn()?
-                            .wait_with_output()
-                            .and_then(check_output)?;
-
-                        let packages_file = binary_dir.join("Packages");
-                        fs::write(&packages_file, &packages_output.stdout)?;
-                        process::Command::new("gzip")
-                            .arg("--keep")
-                            .arg(&packages_file)
-                            .status()
-                            .and_then(check_status)?;
-
-                        let mut release = String::new();
-                        writeln!(release, "Archive: {}", suite.id()).unwrap();
-                        writeln!(release, "Version: {}", suite.version()).unwrap();
-                        writeln!(release, "Component: main").unwrap();
-                        writeln!(release, "Origin: {}", pocket.origin(dev)).unwrap();
-                        writeln!(release, "Label: {}", pocket.label(dev)).unwrap();
-                        writeln!(release, "Architecture: {}", arch.id()).unwrap();
-
-                        let release_file = binary_dir.join("Release");
-                        fs::write(&release_file, release)?;
-
-                        if !archs_string.is_empty() {
-                            archs_string.push(' ');
-                        }
-                        archs_string.push_str(arch.id());
-                    }
+            let info = ReleaseInfo {
+                origin: pocket.origin(dev),
+                label: pocket.label(dev),
+                suite: suite.id().to_string(),
+                version: suite.version().to_string(),
+                codename: suite.id().to_string(),
+                description: format!("{} {} {}", pocket.label(dev), suite.id(), suite.version()),
+                components: vec![component_rules.default.clone()],
+                archs: archs.iter().map(|arch| arch.id().to_string()).collect(),
+                acquire_by_hash: config.dists.by_hash,
+                signed_by: config.signing.signed_by.clone(),
+                valid_for: config.valid_for(pocket.id()),
+            };
+            let old_versions = changelog::source_versions(&dists_cache.path().join(suite.id()))
+                .expect("failed to read suite sources");
+            let (dists_path, dists_rebuilt) = dists_ctx
+                .build(
+                    &mut dists_cache,
+                    pocket_cache.path(),
+                    suite,
+                    &info,
+                    pool_rebuilt,
+                )
+                .expect("failed to build suite dists cache");
+            if dists_rebuilt {
+                dists_changed = true;
+                let new_versions =
+                    changelog::source_versions(&dists_path).expect("failed to read suite sources");
+                let changes = Change::diff(&old_versions, &new_versions)
+                    .expect("failed to compare package versions");
+                for change in changes.iter() {
+                    eprintln!(bold!("    {}"), change);
+                }
+                changelog.add(pocket.id(), suite.id(), changes);
+            } else {
+                let refreshed = refresh_dists(
+                    &dists_path,
+                    config.valid_for(pocket.id()),
+                    config.dists.refresh_before,
+                    &signer,
+                    false,
+                )
+                .expect("failed to refresh suite dists");
+                if refreshed {
+                    eprintln!(bold!("    refreshed Release"));
+                }
+            }
+        }
 
-                    let release_output = process::Command::new("apt-ftparchive")
-                        .arg("-o")
-                        .arg(format!(
-                            "APT::FTPArchive::Release::Origin={}",
-                            pocket.origin(dev)
-                        ))
-                        .arg("-o")
-                        .arg(format!(
-                            "APT::FTPArchive::Release::Label={}",
-                            pocket.label(dev)
-                        ))
-                        .arg("-o")
-                        .arg(format!("APT::FTPArchive::Release::Suite={}", suite.id()))
-                        .arg("-o")
-                        .arg(format!(
-                            "APT::FTPArchive::Release::Version={}",
-                            suite.version()
-                        ))
-                        .arg("-o")
-                        .arg(format!("APT::FTPArchive::Release::Codename={}", suite.id()))
-                        .arg("-o")
-                        .arg(format!(
-                            "APT::FTPArchive::Release::Architectures={}",
-                            archs_string
-                        ))
-                        .arg("-o")
-                        .arg("APT::FTPArchive::Release::Components=main")
-                        .arg("-o")
-                        .arg(format!(
-                            "APT::FTPArchive::Release::Description={} {} {}",
-                            pocket.label(dev),
-                            suite.id(),
-                            suite.version(),
-                        ))
-                        .arg("release")
-                        .arg(".")
-                        .current_dir(&path)
-                        .stdout(process::Stdio::piped())
-                        .spawn()?
-                        .wait_with_output()
-                        .and_then(check_output)?;
-                    let release_file = path.join("Release");
-                    fs::write(&release_file, &release_output.stdout)?;
-
-                    process::Command::new("gpg")
-                        .arg("--clearsign")
-                        .arg("--local-user")
-                        .arg(&debemail)
-                        .arg("--batch")
-                        .arg("--yes")
-                        .arg("--digest-algo")
-                        .arg("sha512")
-                        .arg("-o")
-                        .arg(path.join("InRelease"))
-                        .arg(&release_file)
-                        .status()
-                        .and_then(check_status)?;
+        if dists_changed && config.snapshots.keep > 0 {
+            let name = snapshot::name(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_secs()),
+            );
+            eprintln!(bold!("  snapshot: {}"), name);
+            snapshot::create(pocket_cache.path(), &name).expect("failed to snapshot pocket");
+            snapshot::prune(pocket_cache.path(), config.snapshots.keep)
+                .expect("failed to prune pocket snapshots");
+        }
+    }
 
-                    process::Command::new("gpg")
-                        .arg("-abs")
-                        .arg("--local-user")
-                        .arg(&debemail)
-                        .arg("--batch")
-                        .arg("--yes")
-                        .arg("--digest-algo")
-                        .arg("sha512")
-                        .arg("-o")
-                        .arg(path.join("Release.gpg"))
-                        .arg(&release_file)
-                        .status()
-                        .and_then(check_status)?;
+    match index_cache.prune(Duration::from_secs(30 * 24 * 60 * 60)) {
+        Ok(0) => (),
+        Ok(removed) => eprintln!(bold!("ci: removed {} unused index cache entries"), removed),
+        Err(err) => eprintln!(bold!("ci: failed to prune index cache: {}"), err),
+    }
 
-                    Ok(())
-                })
-                .expect("failed to build suite dists cache");
-        }
+    // The changes of each run are kept with its logs
+    let changelog_path = pockets_path.join("changelog.json");
+    fs::write(&changelog_path, changelog.to_json().pretty(2)).expect("failed to write changelog");
+    ci_ctx
+        .logs
+        .insert("changelog.json".to_string(), (changelog_path, true));
+    if changelog.is_empty() {
+        eprintln!(bold!("ci: no package changes"));
+    } else {
+        eprint!("{}", changelog);
     }
 
     if publish {
-        let mut rsync_args = vec![
-            "--recursive",
-            "--times",
-            "--links",
-            "--safe-links",
-            "--hard-links",
-            "--stats",
-        ];
-
-        if dev {
-            rsync_args.push("--rsh=ssh");
-            rsync_args.push("./_build/ci-dev/apt/");
-            rsync_args.push("ubuntu@apt-origin.pop-os.org:/var/www/html/staging-ubuntu/");
-        } else {
-            rsync_args.push("--rsh=ssh");
-            rsync_args.push("./_build/ci/apt/");
-            rsync_args.push("ubuntu@apt-origin.pop-os.org:/var/www/html/staging/");
-        }
-
-        // Publish new package data (without changing release data)
-        process::Command::new("rsync")
-            .arg("--exclude")
-            .arg("Packages*")
-            .arg("--exclude")
-            .arg("Sources*")
-            .arg("--exclude")
-            .arg("Release*")
-            .arg("--exclude")
-            .arg("InRelease")
-            .args(&rsync_args)
-            .status()
-            .and_then(check_status)
-            .expect("failed to publish new package data");
-
-        // Publish new release data and delete old package data
-        process::Command::new("rsync")
-            .arg("--delete")
-            .arg("--delete-after")
-            .args(&rsync_args)
-            .status()
-            .and_then(check_status)
-            .expect("failed to publish new release data");
+        publish_apt(&config, cache.path(), dev);
+    }
+
+    if let Some(webhook) = &config.changelog.webhook {
+        if !changelog.is_empty() {
+            let text = format!(
+                "{} package changes:\n{}",
+                if dev { "dev" } else { "pop" },
+                changelog
+            );
+            if let Err(err) = post_changelog(webhook, &text) {
+                eprintln!(bold!("ci: failed to post changelog: {}"), err);
+            }
+        }
     }
 
     let mut log_cache = cache
@@ -0,0 +1,177 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    config::MirrorConfig,
+    repo::{RepoInfo, Suite, SuiteDistro},
+    util::check_status,
+};
+
+/// An apt repository that offline builds need a copy of
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MirrorSource {
+    pub dists: BTreeSet<String>,
+    pub sections: BTreeSet<String>,
+    pub archs: BTreeSet<String>,
+    /// Verify the repository with the Ubuntu archive keyring. Pop repositories are verified by
+    /// apt during builds, using their configured keys.
+    pub ubuntu: bool,
+}
+
+/// The apt repositories used by builds, by URL
+pub fn sources(mirrors: &MirrorConfig, dev: bool) -> BTreeMap<String, MirrorSource> {
+    let mut sources = BTreeMap::<String, MirrorSource>::new();
+    for suite in Suite::ALL.iter() {
+        match suite.distro() {
+            SuiteDistro::All => (),
+            SuiteDistro::Pop => {
+                if dev {
+                    continue;
+                }
+            }
+            SuiteDistro::Ubuntu => {
+                if !dev {
+                    continue;
+                }
+            }
+        }
+
+        for arch in RepoInfo::archs(suite, dev).iter() {
+            if let Some(ubuntu) = mirrors.ubuntu(suite.id(), arch.id()) {
+                let source = sources.entry(ubuntu.to_string()).or_default();
+                source.ubuntu = true;
+                for pocket in ["", "-updates", "-security"] {
+                    source.dists.insert(format!("{}{}", suite.id(), pocket));
+                }
+                for section in ["main", "restricted", "universe", "multiverse"] {
+                    source.sections.insert(section.to_string());
+                }
+                source.archs.insert(arch.id().to_string());
+            }
+
+            for url in mirrors
+                .release(suite.id(), dev)
+                .into_iter()
+                .chain(mirrors.staging(suite.id(), dev))
+            {
+                let source = sources.entry(url.to_string()).or_default();
+                source.dists.insert(suite.id().to_string());
+                source.sections.insert("main".to_string());
+                source.archs.insert(arch.id().to_string());
+            }
+        }
+    }
+    sources
+}
+
+/// A local copy of the apt repositories used by builds, with each repository stored by the
+/// host and path of its URL
+pub struct OfflineMirror {
+    path: PathBuf,
+}
+
+impl OfflineMirror {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn relative(url: &str) -> &str {
+        url.split_once("://")
+            .map_or(url, |(_, rest)| rest)
+            .trim_end_matches('/')
+    }
+
+    /// URL of the local copy of a repository, when the mirror is served at base_url
+    pub fn local_url(base_url: &str, url: &str) -> String {
+        format!("{}/{}", base_url.trim_end_matches('/'), Self::relative(url))
+    }
+
+    /// Replace the repositories in mirrors with their local copies
+    pub fn rewrite(mirrors: &mut MirrorConfig, base_url: &str) {
+        for map in [
+            &mut mirrors.ubuntu,
+            &mut mirrors.release,
+            &mut mirrors.staging,
+        ] {
+            for url in map.values_mut() {
+                *url = Self::local_url(base_url, url);
+            }
+        }
+    }
+
+    /// Download a repository with debmirror. If snapshot is set, Ubuntu repositories are
+    /// downloaded from snapshot.ubuntu.com at that timestamp, but stored under their own URL.
+    pub fn sync(
+        &self,
+        url: &str,
+        source: &MirrorSource,
+        snapshot: Option<&str>,
+        keyring: &Path,
+    ) -> io::Result<()> {
+        let mut download_url = url.to_string();
+        if let (true, Some(timestamp)) = (source.ubuntu, snapshot) {
+            let archive = if url.ends_with("/ubuntu-ports") {
+                "ubuntu-ports"
+            } else {
+                "ubuntu"
+            };
+            download_url = format!("https://snapshot.ubuntu.com/{}/{}", archive, timestamp);
+        }
+
+        let (method, rest) = download_url.split_once("://").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid repository URL {}", download_url),
+            )
+        })?;
+        let (host, root) = rest.split_once('/').unwrap_or((rest, ""));
+
+        let target = self.path.join(Self::relative(url));
+        fs::create_dir_all(&target)?;
+
+        let join = |set: &BTreeSet<String>| set.iter().cloned().collect::<Vec<_>>().join(",");
+        let mut command = process::Command::new("debmirror");
+        command
+            .arg(format!("--method={}", method))
+            .arg(format!("--host={}", host))
+            .arg(format!("--root={}", root))
+            .arg(format!("--dist={}", join(&source.dists)))
+            .arg(format!("--section={}", join(&source.sections)))
+            .arg(format!("--arch={}", join(&source.archs)))
+            .arg("--nosource")
+            .arg("--rsync-extra=none")
+            .arg("--diff=none")
+            .arg("--progress");
+        if source.ubuntu {
+            command.arg(format!("--keyring={}", keyring.display()));
+        } else {
+            command.arg("--no-check-gpg");
+        }
+        command.arg(&target).status().and_then(check_status)
+    }
+}
+
+/// True if a URL refers to this machine, or to one of the allowed URL prefixes
+pub fn is_local_url(url: &str, allowed: &[&str]) -> bool {
+    if url.starts_with("file:") || allowed.iter().any(|prefix| url.starts_with(prefix)) {
+        return true;
+    }
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let host = rest.split('/').next().unwrap_or("");
+    let host = match host.rsplit_once(':') {
+        // Keep IPv6 addresses like [::1] intact when there is no port
+        Some((host, port)) if !port.contains(']') => host,
+        _ => host,
+    };
+    matches!(host, "localhost" | "[::1]") || host.starts_with("127.")
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::BTreeSet,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::{Condvar, Mutex},
+};
+
+use crate::{
+    builder::{self, Builder},
+    config::BuilderConfig,
+    repo::{Arch, Suite},
+    util::check_status,
+};
+
+struct PoolHost {
+    config: BuilderConfig,
+    // Suites and architectures this host has no usable build environment for
+    unready: BTreeSet<(Suite, Arch)>,
+    // Directories shared between builds on the same host are synced one at a time
+    rsync: Mutex<()>,
+}
+
+/// The builders available to this run, with a limited number of slots on each
+pub struct BuilderPool {
+    hosts: Vec<PoolHost>,
+    used: Mutex<Vec<usize>>,
+    condvar: Condvar,
+}
+
+impl BuilderPool {
+    pub fn new(builders: Vec<BuilderConfig>) -> Self {
+        let used = vec![0; builders.len()];
+        Self {
+            hosts: builders
+                .into_iter()
+                .map(|config| PoolHost {
+                    config,
+                    unready: BTreeSet::new(),
+                    rsync: Mutex::new(()),
+                })
+                .collect(),
+            used: Mutex::new(used),
+            condvar: Condvar::new(),
+        }
+    }
+
+    pub fn hosts(&self) -> impl Iterator<Item = &BuilderConfig> {
+        self.hosts.iter().map(|host| &host.config)
+    }
+
+    pub fn hosts_for<'a>(&'a self, arch: &'a Arch) -> impl Iterator<Item = &'a BuilderConfig> {
+        self.hosts().filter(move |host| host.supports(arch))
+    }
+
+    pub fn supports(&self, arch: &Arch) -> bool {
+        self.hosts_for(arch).next().is_some()
+    }
+
+    /// Hosts that support arch and have a usable build environment for suite
+    pub fn ready_hosts<'a>(
+        &'a self,
+        suite: &'a Suite,
+        arch: &'a Arch,
+    ) -> impl Iterator<Item = &'a BuilderConfig> {
+        self.hosts
+            .iter()
+            .filter(move |host| host.ready(suite, arch))
+            .map(|host| &host.config)
+    }
+
+    pub fn supports_suite(&self, suite: &Suite, arch: &Arch) -> bool {
+        self.ready_hosts(suite, arch).next().is_some()
+    }
+
+    /// Stop scheduling builds for suite and arch to a host
+    pub fn mark_unready(&mut self, name: &str, suite: &Suite, arch: &Arch) {
+        for host in self.hosts.iter_mut() {
+            if host.config.name() == name {
+                host.unready.insert((suite.clone(), arch.clone()));
+            }
+        }
+    }
+
+    /// Remove hosts that cannot be reached or are missing required tools
+    pub fn health_check(&mut self) {
+        self.hosts.retain(|host| match host.config.health_check() {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!(
+                    "BuilderPool: removing builder {}: {}",
+                    host.config.name(),
+                    err
+                );
+                false
+            }
+        });
+        *self.used.lock().unwrap() = vec![0; self.hosts.len()];
+    }
+
+    /// Wait for a free slot on a host that can build suite for arch, preferring the least busy
+    /// host
+    pub fn acquire(&self, suite: &Suite, arch: &Arch) -> io::Result<BuilderSlot<'_>> {
+        if !self.supports_suite(suite, arch) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no builder for {} {}", suite.id(), arch.id()),
+            ));
+        }
+
+        let mut used = self.used.lock().unwrap();
+        loop {
+            let mut best: Option<(usize, f64)> = None;
+            for (i, host) in self.hosts.iter().enumerate() {
+                if !host.ready(suite, arch) {
+                    continue;
+                }
+                let load = if host.config.slots == 0 {
+                    0.0
+                } else if used[i] < host.config.slots {
+                    used[i] as f64 / host.config.slots as f64
+                } else {
+                    continue;
+                };
+                if best.is_none_or(|(_, best_load)| load < best_load) {
+                    best = Some((i, load));
+                }
+            }
+
+            if let Some((i, _)) = best {
+                used[i] += 1;
+                return Ok(BuilderSlot { pool: self, i });
+            }
+
+            used = self.condvar.wait(used).unwrap();
+        }
+    }
+}
+
+impl PoolHost {
+    fn ready(&self, suite: &Suite, arch: &Arch) -> bool {
+        self.config.supports(arch) && !self.unready.contains(&(suite.clone(), arch.clone()))
+    }
+}
+
+impl BuilderConfig {
+    pub fn name(&self) -> &str {
+        self.host.as_deref().unwrap_or("local")
+    }
+
+    /// Build backend used on this host
+    pub fn backend(&self) -> &'static dyn Builder {
+        builder::builder(self.builder.as_deref().unwrap_or("sbuild")).expect("unknown builder")
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.host.is_some()
+    }
+
+    pub fn supports(&self, arch: &Arch) -> bool {
+        self.arches.iter().any(|id| id == arch.id())
+    }
+
+    /// Path on this host that corresponds to a local path
+    pub fn map_path(&self, path: &Path) -> PathBuf {
+        match (&self.host, &self.workdir) {
+            (Some(_), Some(workdir)) => match env::current_dir()
+                .ok()
+                .and_then(|cwd| fs::canonicalize(cwd).ok())
+                .and_then(|cwd| path.strip_prefix(cwd).ok().map(|x| x.to_path_buf()))
+            {
+                Some(relative) => workdir.join(relative),
+                None => path.to_path_buf(),
+            },
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Command running a script on this host
+    pub fn command(&self, script: &str) -> process::Command {
+        match &self.host {
+            Some(host) => {
+                let mut command = process::Command::new("ssh");
+                command.arg(host).arg("--").arg(script);
+                command
+            }
+            None => {
+                let mut command = process::Command::new("sh");
+                command.arg("-c").arg(script);
+                command
+            }
+        }
+    }
+
+    pub fn health_check(&self) -> io::Result<()> {
+        let mut script = format!("set -e; {}", self.backend().health_script());
+        let host = match &self.host {
+            Some(some) => some,
+            None => {
+                return self
+                    .command(&script)
+                    .stdin(process::Stdio::null())
+                    .status()
+                    .and_then(check_status)
+            }
+        };
+
+        script.push_str("; command -v rsync > /dev/null");
+        if let Some(workdir) = &self.workdir {
+            script.push_str(&format!("; mkdir -p '{}'", workdir.display()));
+        }
+        process::Command::new("ssh")
+            .arg("-o")
+            .arg("BatchMode=yes")
+            .arg("-o")
+            .arg("ConnectTimeout=30")
+            .arg(host)
+            .arg("--")
+            .arg(script)
+            .stdin(process::Stdio::null())
+            .status()
+            .and_then(check_status)
+    }
+
+    fn rsync(&self, from: String, to: String) -> io::Result<()> {
+        process::Command::new("rsync")
+            .arg("--archive")
+            .arg("--compress")
+            .arg("--delay-updates")
+            .arg("--delete")
+            .arg("--mkpath")
+            .arg("--rsh=ssh")
+            .arg(from)
+            .arg(to)
+            .status()
+            .and_then(check_status)
+    }
+}
+
+/// A slot on a builder, released when dropped
+pub struct BuilderSlot<'a> {
+    pool: &'a BuilderPool,
+    i: usize,
+}
+
+impl BuilderSlot<'_> {
+    pub fn host(&self) -> &BuilderConfig {
+        &self.pool.hosts[self.i].config
+    }
+
+    /// Copy a local directory to the host, if it is remote
+    pub fn push(&self, path: &Path) -> io::Result<()> {
+        let host = self.host();
+        if let Some(name) = &host.host {
+            let _guard = self.pool.hosts[self.i].rsync.lock().unwrap();
+            host.rsync(
+                format!("{}/", path.display()),
+                format!("{}:{}/", name, host.map_path(path).display()),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Copy a local file to the host, if it is remote
+    pub fn push_file(&self, path: &Path) -> io::Result<()> {
+        let host = self.host();
+        if let Some(name) = &host.host {
+            let _guard = self.pool.hosts[self.i].rsync.lock().unwrap();
+            host.rsync(
+                path.display().to_string(),
+                format!("{}:{}", name, host.map_path(path).display()),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Copy a directory from the host to the local path, if it is remote
+    pub fn pull(&self, path: &Path) -> io::Result<()> {
+        let host = self.host();
+        if let Some(name) = &host.host {
+            host.rsync(
+                format!("{}:{}/", name, host.map_path(path).display()),
+                format!("{}/", path.display()),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BuilderSlot<'_> {
+    fn drop(&mut self) {
+        let mut used = self.pool.used.lock().unwrap();
+        used[self.i] -= 1;
+        self.pool.condvar.notify_all();
+    }
+}
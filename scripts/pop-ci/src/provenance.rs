@@ -0,0 +1,111 @@
+use json::JsonValue;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{signing::Signer, util::sha256_file};
+
+/// Files of a binary build that provenance is recorded for
+pub fn is_artifact(file_name: &str) -> bool {
+    file_name.ends_with(".deb")
+        || file_name.ends_with(".ddeb")
+        || file_name.ends_with(".buildinfo")
+        || file_name.ends_with(".spdx.json")
+}
+
+/// Files of a binary build that are published next to its packages
+pub fn is_attestation(file_name: &str) -> bool {
+    file_name.ends_with(".buildinfo")
+        || file_name.ends_with(".spdx.json")
+        || file_name.ends_with(".provenance.json")
+        || file_name.ends_with(".provenance.json.asc")
+}
+
+/// The git commit a build was made from
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BuildOrigin {
+    pub repo: String,
+    pub commit: String,
+    pub branches: Vec<String>,
+}
+
+/// How a binary build was produced, so that users of the repositories can audit where a
+/// package came from
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Provenance {
+    pub origin: BuildOrigin,
+    pub suite: String,
+    pub arch: String,
+    pub builder: String,
+    pub host: String,
+    /// SHA256 of the package manifest of the build environment, if one was recorded
+    pub environment: Option<String>,
+    /// SHA256 of the source package description
+    pub source: (String, String),
+    /// SHA256 of each file produced by the build, by file name
+    pub artifacts: BTreeMap<String, String>,
+}
+
+impl Provenance {
+    /// SHA256 of each artifact in a binary build directory, by file name
+    pub fn artifacts(path: &Path) -> io::Result<BTreeMap<String, String>> {
+        let mut artifacts = BTreeMap::new();
+        for entry_res in fs::read_dir(path)? {
+            let entry = entry_res?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if is_artifact(&file_name) {
+                artifacts.insert(file_name, sha256_file(entry.path())?);
+            }
+        }
+        Ok(artifacts)
+    }
+
+    pub fn file_name(&self) -> String {
+        format!(
+            "{}_{}_{}_{}.provenance.json",
+            self.origin.repo, self.origin.commit, self.suite, self.arch
+        )
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut artifacts = JsonValue::new_object();
+        for (name, sha256) in self.artifacts.iter() {
+            artifacts[name.as_str()] = sha256.as_str().into();
+        }
+
+        let mut value = JsonValue::new_object();
+        value["pop_ci"]["version"] = env!("CARGO_PKG_VERSION").into();
+        value["source"]["repo"] = self.origin.repo.as_str().into();
+        value["source"]["commit"] = self.origin.commit.as_str().into();
+        value["source"]["branches"] = self.origin.branches.clone().into();
+        value["source"]["dsc"]["name"] = self.source.0.as_str().into();
+        value["source"]["dsc"]["sha256"] = self.source.1.as_str().into();
+        value["build"]["suite"] = self.suite.as_str().into();
+        value["build"]["arch"] = self.arch.as_str().into();
+        value["build"]["builder"] = self.builder.as_str().into();
+        value["build"]["host"] = self.host.as_str().into();
+        value["build"]["environment"]["manifest_sha256"] = match &self.environment {
+            Some(sha256) => sha256.as_str().into(),
+            None => JsonValue::Null,
+        };
+        value["artifacts"] = artifacts;
+        value
+    }
+
+    /// Write the provenance to a directory, with a verified detached signature by signer.
+    /// Returns the path of the provenance document.
+    pub fn write(&self, path: &Path, signer: &Signer) -> io::Result<PathBuf> {
+        let provenance_path = path.join(self.file_name());
+        fs::write(&provenance_path, self.to_json().pretty(2))?;
+
+        let mut signature_name = provenance_path.file_name().unwrap().to_os_string();
+        signature_name.push(".asc");
+        let signature_path = path.join(signature_name);
+        signer.detach_sign(&provenance_path, &signature_path)?;
+        signer.verify_detached(&provenance_path, &signature_path)?;
+
+        Ok(provenance_path)
+    }
+}
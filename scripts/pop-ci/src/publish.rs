@@ -0,0 +1,754 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    config::PublishTarget,
+    index,
+    snapshot::{link_tree, SNAPSHOTS},
+    util::{check_output, check_status, sha256, sha256_file},
+};
+
+/// Steps of publishing, in order, so that clients never see a Release file listing indexes
+/// that are not uploaded yet, or indexes listing packages that are not uploaded yet
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Stage {
+    /// Packages, attestations, and indexes stored by hash
+    Pool,
+    /// Indexes of dists
+    Indexes,
+    /// Release, Release.gpg and InRelease files
+    Release,
+}
+
+impl Stage {
+    pub const ALL: &'static [Self] = &[Self::Pool, Self::Indexes, Self::Release];
+
+    /// The stage a file is uploaded in, from its path relative to the published root
+    pub fn of(path: &str) -> Self {
+        let parts: Vec<&str> = path.split('/').collect();
+        let name = parts.last().copied().unwrap_or_default();
+        if name == "Release" || name == "Release.gpg" || name == "InRelease" {
+            Self::Release
+        } else if parts.contains(&"dists") && !parts.contains(&"by-hash") {
+            Self::Indexes
+        } else {
+            Self::Pool
+        }
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Pool => "pool",
+            Self::Indexes => "indexes",
+            Self::Release => "release",
+        }
+    }
+}
+
+/// Release files of suites that are checked after publishing. Release files of snapshots do
+/// not change after they are first published, so they are not checked.
+fn is_checked(path: &str) -> bool {
+    let parts: Vec<&str> = path.split('/').collect();
+    let name = parts.last().copied().unwrap_or_default();
+    (name == "Release" || name == "InRelease")
+        && parts.len() >= 3
+        && parts[parts.len() - 3] == "dists"
+        && !parts.contains(&SNAPSHOTS)
+}
+
+/// SHA256 of the Release files of a local tree that are checked after publishing, which
+/// change whenever anything else in the tree is published again
+pub fn fingerprint(local: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut sums = BTreeMap::new();
+    for path in local_files(local)? {
+        if is_checked(&path) {
+            let sum = sha256_file(local.join(&path))?;
+            sums.insert(path, sum);
+        }
+    }
+    Ok(sums)
+}
+
+/// Release file SHA256 of each suite of a local pocket, so suites can be published separately
+pub fn suite_fingerprints(pocket: &Path) -> io::Result<BTreeMap<String, BTreeMap<String, String>>> {
+    let sums = fingerprint(pocket)?;
+    let mut suites = BTreeMap::new();
+    let dists = pocket.join("dists");
+    if dists.is_dir() {
+        for entry_res in fs::read_dir(&dists)? {
+            let entry = entry_res?;
+            if !entry.path().is_dir() {
+                continue;
+            }
+            let suite_name = entry.file_name().to_string_lossy().into_owned();
+            let prefix = format!("dists/{}/", suite_name);
+            let suite_sums = sums
+                .iter()
+                .filter(|(path, _)| path.starts_with(&prefix))
+                .map(|(path, sum)| (path.clone(), sum.clone()))
+                .collect();
+            suites.insert(suite_name, suite_sums);
+        }
+    }
+    Ok(suites)
+}
+
+/// Files of a local tree, relative to it
+fn local_files(local: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for path in index::files(local)? {
+        files.push(index::relative_path(local, &path)?);
+    }
+    Ok(files)
+}
+
+/// Quote a string for a POSIX shell
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// A destination apt repositories are published to
+pub trait Publisher {
+    /// Description of the destination, for display
+    fn name(&self) -> String;
+
+    /// Upload the files of a local tree that belong to a stage or an earlier stage, replacing
+    /// files that changed
+    fn upload(&self, local: &Path, stage: Stage) -> io::Result<()>;
+
+    /// Remove files from the destination that are not in the local tree
+    fn delete(&self, local: &Path) -> io::Result<()>;
+
+    /// Remove the destination and everything in it
+    fn remove(&self) -> io::Result<()>;
+
+    /// SHA256 of files of the destination, by path relative to its root. Missing files are
+    /// left out.
+    fn sha256(&self, paths: &[String]) -> io::Result<BTreeMap<String, String>>;
+}
+
+/// Find the publisher of a target
+pub fn publisher(target: &PublishTarget) -> Box<dyn Publisher> {
+    match target {
+        PublishTarget::Rsync { host, path, rsh } => Box::new(RsyncPublisher {
+            host: host.clone(),
+            path: path.clone(),
+            rsh: rsh.clone(),
+        }),
+        PublishTarget::Local { path } => Box::new(LocalPublisher { path: path.clone() }),
+        PublishTarget::S3 {
+            bucket,
+            prefix,
+            endpoint,
+            profile,
+        } => Box::new(S3Publisher {
+            bucket: bucket.clone(),
+            prefix: prefix.clone(),
+            endpoint: endpoint.clone(),
+            profile: profile.clone(),
+        }),
+    }
+}
+
+/// Publish a local tree, one stage at a time, then check that the destination serves the
+/// new Release files before removing files that are no longer published
+pub fn publish(publisher: &dyn Publisher, local: &Path) -> io::Result<()> {
+    upload_verified(publisher, local)?;
+    publisher.delete(local)
+}
+
+/// Publish the dists and pool of one suite of a pocket, leaving the other suites of the pocket
+/// alone. The files of the suite are linked into view, which is uploaded to the pocket like a
+/// pocket with only this suite, and only stale files of the suite's directories are removed.
+/// Directories the suite no longer has are removed entirely.
+pub fn publish_suite(
+    target: &PublishTarget,
+    pocket_path: &Path,
+    suite: &str,
+    view: &Path,
+) -> io::Result<()> {
+    if view.exists() {
+        fs::remove_dir_all(view)?;
+    }
+    let dirs = [format!("dists/{}", suite), format!("pool/{}", suite)];
+    fs::create_dir_all(view)?;
+    for dir in dirs.iter() {
+        if pocket_path.join(dir).is_dir() {
+            link_tree(&pocket_path.join(dir), &view.join(dir))?;
+        }
+    }
+
+    upload_verified(publisher(target).as_ref(), view)?;
+    for dir in dirs.iter() {
+        let dir_publisher = publisher(&target.join(dir));
+        if view.join(dir).is_dir() {
+            dir_publisher.delete(&view.join(dir))?;
+        } else {
+            dir_publisher.remove()?;
+        }
+    }
+    fs::remove_dir_all(view)
+}
+
+/// Upload a local tree one stage at a time, and check that the destination serves its
+/// Release files
+fn upload_verified(publisher: &dyn Publisher, local: &Path) -> io::Result<()> {
+    for stage in Stage::ALL.iter() {
+        eprintln!("publish: uploading {} to {}", stage.id(), publisher.name());
+        publisher.upload(local, *stage)?;
+    }
+
+    let checked: Vec<String> = local_files(local)?
+        .into_iter()
+        .filter(|path| is_checked(path))
+        .collect();
+    let remote = publisher.sha256(&checked)?;
+    for path in checked.iter() {
+        let expected = sha256_file(local.join(path))?;
+        match remote.get(path) {
+            Some(actual) if actual == &expected => (),
+            Some(actual) => {
+                return Err(io::Error::other(format!(
+                    "published {} has SHA256 {} instead of {}",
+                    path, actual, expected
+                )))
+            }
+            None => return Err(io::Error::other(format!("published {} is missing", path))),
+        }
+    }
+    eprintln!(
+        "publish: verified {} Release files on {}",
+        checked.len(),
+        publisher.name()
+    );
+    Ok(())
+}
+
+/// Publishes with rsync, to a host over ssh if one is set
+pub struct RsyncPublisher {
+    pub host: Option<String>,
+    pub path: String,
+    pub rsh: String,
+}
+
+impl RsyncPublisher {
+    fn rsync(&self, local: &Path, args: &[&str]) -> io::Result<()> {
+        let mut command = process::Command::new("rsync");
+        command
+            .arg("--recursive")
+            .arg("--times")
+            .arg("--links")
+            .arg("--safe-links")
+            .arg("--hard-links")
+            .arg("--stats")
+            .args(args);
+        let mut source = local.as_os_str().to_os_string();
+        source.push("/");
+        command.arg(source);
+        match &self.host {
+            Some(host) => {
+                command.arg(format!("--rsh={}", self.rsh)).arg(format!(
+                    "{}:{}/",
+                    host,
+                    self.path.trim_end_matches('/')
+                ));
+            }
+            None => {
+                command.arg(format!("{}/", self.path.trim_end_matches('/')));
+            }
+        }
+        command.status().and_then(check_status)
+    }
+}
+
+impl Publisher for RsyncPublisher {
+    fn name(&self) -> String {
+        match &self.host {
+            Some(host) => format!("{}:{}", host, self.path),
+            None => self.path.clone(),
+        }
+    }
+
+    fn upload(&self, local: &Path, stage: Stage) -> io::Result<()> {
+        // Directories are always included, so that indexes stored by hash are reached
+        let filters: &[&str] = match stage {
+            Stage::Pool => &["--include=*/", "--include=by-hash/**", "--exclude=dists/**"],
+            Stage::Indexes => &[
+                "--include=*/",
+                "--exclude=Release",
+                "--exclude=Release.gpg",
+                "--exclude=InRelease",
+            ],
+            Stage::Release => &[],
+        };
+        self.rsync(local, filters)
+    }
+
+    fn delete(&self, local: &Path) -> io::Result<()> {
+        self.rsync(local, &["--delete", "--delete-after"])
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        let host = match &self.host {
+            Some(host) => host,
+            None => {
+                return LocalPublisher {
+                    path: PathBuf::from(&self.path),
+                }
+                .remove()
+            }
+        };
+        let mut rsh = self.rsh.split_whitespace();
+        process::Command::new(rsh.next().unwrap_or("ssh"))
+            .args(rsh)
+            .arg(host)
+            .arg(format!("rm -rf -- {}", shell_quote(&self.path)))
+            .status()
+            .and_then(check_status)
+    }
+
+    fn sha256(&self, paths: &[String]) -> io::Result<BTreeMap<String, String>> {
+        let host = match &self.host {
+            Some(host) => host,
+            None => {
+                return LocalPublisher {
+                    path: PathBuf::from(&self.path),
+                }
+                .sha256(paths)
+            }
+        };
+        if paths.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+
+        // Hash every file in one connection, ignoring missing files
+        let mut script = format!("cd {} || exit 1\n", shell_quote(&self.path));
+        for path in paths.iter() {
+            let quoted = shell_quote(path);
+            script.push_str(&format!(
+                "if [ -f {0} ]; then sha256sum -- {0}; fi\n",
+                quoted
+            ));
+        }
+        let mut rsh = self.rsh.split_whitespace();
+        let output = process::Command::new(rsh.next().unwrap_or("ssh"))
+            .args(rsh)
+            .arg(host)
+            .arg(script)
+            .stdout(process::Stdio::piped())
+            .spawn()?
+            .wait_with_output()
+            .and_then(check_output)?;
+
+        let mut sums = BTreeMap::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((sum, path)) = line.split_once("  ") {
+                sums.insert(path.to_string(), sum.to_string());
+            }
+        }
+        Ok(sums)
+    }
+}
+
+/// Publishes to a local directory, replacing each changed file atomically
+pub struct LocalPublisher {
+    pub path: PathBuf,
+}
+
+impl Publisher for LocalPublisher {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn upload(&self, local: &Path, stage: Stage) -> io::Result<()> {
+        for file in local_files(local)? {
+            if Stage::of(&file) > stage {
+                continue;
+            }
+            let from = local.join(&file);
+            let to = self.path.join(&file);
+            // Modification times are kept, so unchanged files are found like rsync does
+            let metadata = fs::metadata(&from)?;
+            if let Ok(existing) = fs::metadata(&to) {
+                if existing.len() == metadata.len()
+                    && existing.modified()? == metadata.modified()?
+                {
+                    continue;
+                }
+            }
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut partial_name = to.file_name().unwrap().to_os_string();
+            partial_name.push(".partial");
+            let partial_path = to.with_file_name(partial_name);
+            fs::copy(&from, &partial_path)?;
+            fs::File::options()
+                .write(true)
+                .open(&partial_path)?
+                .set_modified(metadata.modified()?)?;
+            fs::rename(&partial_path, &to)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&self, local: &Path) -> io::Result<()> {
+        if !self.path.is_dir() {
+            return Ok(());
+        }
+        let keep: BTreeSet<String> = local_files(local)?.into_iter().collect();
+        for file in local_files(&self.path)? {
+            if !keep.contains(&file) {
+                fs::remove_file(self.path.join(&file))?;
+            }
+        }
+        remove_empty_dirs(&self.path)?;
+        Ok(())
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        if self.path.is_dir() {
+            fs::remove_dir_all(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn sha256(&self, paths: &[String]) -> io::Result<BTreeMap<String, String>> {
+        let mut sums = BTreeMap::new();
+        for path in paths.iter() {
+            let file = self.path.join(path);
+            if file.is_file() {
+                sums.insert(path.clone(), sha256_file(file)?);
+            }
+        }
+        Ok(sums)
+    }
+}
+
+/// Remove empty directories below a directory. Returns true if the directory is empty.
+fn remove_empty_dirs(path: &Path) -> io::Result<bool> {
+    let mut empty = true;
+    for entry_res in fs::read_dir(path)? {
+        let entry = entry_res?;
+        if entry.file_type()?.is_dir() && remove_empty_dirs(&entry.path())? {
+            fs::remove_dir(entry.path())?;
+        } else {
+            empty = false;
+        }
+    }
+    Ok(empty)
+}
+
+/// Publishes to S3 compatible object storage with the aws command
+pub struct S3Publisher {
+    pub bucket: String,
+    pub prefix: String,
+    pub endpoint: Option<String>,
+    pub profile: Option<String>,
+}
+
+impl S3Publisher {
+    fn url(&self, path: &str) -> String {
+        let mut url = format!("s3://{}", self.bucket);
+        for part in [self.prefix.as_str(), path].iter() {
+            if !part.is_empty() {
+                url.push('/');
+                url.push_str(part);
+            }
+        }
+        url
+    }
+
+    fn aws(&self) -> process::Command {
+        let mut command = process::Command::new("aws");
+        if let Some(endpoint) = &self.endpoint {
+            command.arg("--endpoint-url").arg(endpoint);
+        }
+        if let Some(profile) = &self.profile {
+            command.arg("--profile").arg(profile);
+        }
+        command.arg("s3");
+        command
+    }
+
+    fn sync(&self, local: &Path, args: &[&str]) -> io::Result<()> {
+        self.aws()
+            .arg("sync")
+            .arg("--no-progress")
+            .arg("--no-follow-symlinks")
+            .args(args)
+            .arg(local)
+            .arg(self.url(""))
+            .status()
+            .and_then(check_status)
+    }
+}
+
+impl Publisher for S3Publisher {
+    fn name(&self) -> String {
+        self.url("")
+    }
+
+    fn upload(&self, local: &Path, stage: Stage) -> io::Result<()> {
+        // Later filters take precedence, and wildcards match across directories
+        let filters: &[&str] = match stage {
+            Stage::Pool => &[
+                "--exclude",
+                "dists/*",
+                "--exclude",
+                "*/dists/*",
+                "--include",
+                "*/by-hash/*",
+            ],
+            Stage::Indexes => &["--exclude", "*Release", "--exclude", "*Release.gpg"],
+            Stage::Release => &[],
+        };
+        self.sync(local, filters)
+    }
+
+    fn delete(&self, local: &Path) -> io::Result<()> {
+        self.sync(local, &["--delete"])
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        // The trailing slash keeps pockets whose names start with this one
+        self.aws()
+            .arg("rm")
+            .arg("--recursive")
+            .arg("--only-show-errors")
+            .arg(format!("{}/", self.url("")))
+            .status()
+            .and_then(check_status)
+    }
+
+    fn sha256(&self, paths: &[String]) -> io::Result<BTreeMap<String, String>> {
+        let mut sums = BTreeMap::new();
+        for path in paths.iter() {
+            let output = self
+                .aws()
+                .arg("cp")
+                .arg("--only-show-errors")
+                .arg(self.url(path))
+                .arg("-")
+                .stdout(process::Stdio::piped())
+                .spawn()?
+                .wait_with_output()?;
+            // Objects that can not be read are reported as missing
+            if output.status.success() {
+                sums.insert(path.clone(), sha256(&output.stdout));
+            }
+        }
+        Ok(sums)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Publishes to a local directory, recording each step and checking that uploads leave the
+    /// files of later stages alone
+    struct Recorder {
+        inner: LocalPublisher,
+        log: RefCell<Vec<String>>,
+        /// Report this SHA256 for every file, like a destination serving stale files
+        wrong_sha256: Option<String>,
+    }
+
+    impl Publisher for Recorder {
+        fn name(&self) -> String {
+            self.inner.name()
+        }
+
+        fn upload(&self, local: &Path, stage: Stage) -> io::Result<()> {
+            let read = |path: &Path| -> io::Result<BTreeMap<String, Vec<u8>>> {
+                let mut files = BTreeMap::new();
+                for file in local_files(path)? {
+                    if Stage::of(&file) > stage {
+                        files.insert(file.clone(), fs::read(path.join(&file))?);
+                    }
+                }
+                Ok(files)
+            };
+            let before = read(&self.inner.path)?;
+            self.inner.upload(local, stage)?;
+            assert_eq!(
+                read(&self.inner.path)?,
+                before,
+                "files of later stages changed in stage {}",
+                stage.id()
+            );
+            self.log.borrow_mut().push(format!("upload {}", stage.id()));
+            Ok(())
+        }
+
+        fn delete(&self, local: &Path) -> io::Result<()> {
+            self.log.borrow_mut().push("delete".to_string());
+            self.inner.delete(local)
+        }
+
+        fn remove(&self) -> io::Result<()> {
+            self.inner.remove()
+        }
+
+        fn sha256(&self, paths: &[String]) -> io::Result<BTreeMap<String, String>> {
+            self.log.borrow_mut().push("sha256".to_string());
+            let mut sums = self.inner.sha256(paths)?;
+            if let Some(wrong) = &self.wrong_sha256 {
+                for sum in sums.values_mut() {
+                    *sum = wrong.clone();
+                }
+            }
+            Ok(sums)
+        }
+    }
+
+    fn write(root: &Path, path: &str, data: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn publish_local_in_stages() {
+        let root = std::env::temp_dir().join(format!("pop-ci-publish-{}", process::id()));
+        if root.exists() {
+            fs::remove_dir_all(&root).unwrap();
+        }
+        let local = root.join("local");
+        let remote = root.join("remote");
+        let recorder = |wrong_sha256: Option<&str>| Recorder {
+            inner: LocalPublisher {
+                path: remote.clone(),
+            },
+            log: RefCell::new(Vec::new()),
+            wrong_sha256: wrong_sha256.map(|sum| sum.to_string()),
+        };
+
+        write(&local, "pool/jammy/foo/foo_1_amd64.deb", "foo 1");
+        write(&local, "dists/jammy/main/binary-amd64/Packages", "foo 1");
+        write(
+            &local,
+            "dists/jammy/main/binary-amd64/by-hash/SHA256/abc",
+            "foo 1",
+        );
+        write(&local, "dists/jammy/Release", "release 1");
+        write(&local, "dists/jammy/InRelease", "inrelease 1");
+        write(&remote, "pool/jammy/foo/foo_0_amd64.deb", "foo 0");
+        write(&remote, "pool/jammy/old/old_0_amd64.deb", "old 0");
+        write(&remote, "dists/jammy/main/binary-amd64/Packages", "foo 0");
+        write(&remote, "dists/jammy/Release", "release 0");
+
+        let publisher = recorder(None);
+        publish(&publisher, &local).unwrap();
+        assert_eq!(
+            *publisher.log.borrow(),
+            vec![
+                "upload pool",
+                "upload indexes",
+                "upload release",
+                "sha256",
+                "delete"
+            ]
+        );
+        // Stale files and the directories left empty are removed
+        assert_eq!(local_files(&remote).unwrap(), local_files(&local).unwrap());
+        assert!(!remote.join("pool/jammy/old").exists());
+        assert_eq!(
+            fs::read_to_string(remote.join("dists/jammy/Release")).unwrap(),
+            "release 1"
+        );
+
+        // Stale files are kept when the published Release files can not be verified
+        write(&local, "dists/jammy/Release", "release 2");
+        write(&remote, "pool/jammy/old/old_0_amd64.deb", "old 0");
+        let publisher = recorder(Some("0000"));
+        let err = publish(&publisher, &local).unwrap_err();
+        assert!(err.to_string().contains("has SHA256 0000"), "{}", err);
+        assert!(!publisher.log.borrow().contains(&"delete".to_string()));
+        assert!(remote.join("pool/jammy/old/old_0_amd64.deb").is_file());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn publish_suite_leaves_other_suites() {
+        let root = std::env::temp_dir().join(format!("pop-ci-publish-suite-{}", process::id()));
+        if root.exists() {
+            fs::remove_dir_all(&root).unwrap();
+        }
+        let local = root.join("local");
+        let remote = root.join("remote");
+        let view = root.join("view");
+        let target = PublishTarget::Local {
+            path: remote.clone(),
+        };
+
+        write(&local, "pool/jammy/foo/foo_2_amd64.deb", "foo 2");
+        write(&local, "dists/jammy/main/binary-amd64/Packages", "foo 2");
+        write(&local, "dists/jammy/Release", "jammy 2");
+        write(&local, "dists/noble/Release", "noble 2");
+        write(&remote, "pool/jammy/foo/foo_1_amd64.deb", "foo 1");
+        write(&remote, "dists/jammy/Release", "jammy 1 old");
+        write(&remote, "pool/noble/bar/bar_1_amd64.deb", "bar 1");
+        write(&remote, "dists/noble/Release", "noble 1 old");
+
+        publish_suite(&target, &local, "jammy", &view).unwrap();
+        assert!(!view.exists());
+        assert_eq!(
+            local_files(&remote).unwrap(),
+            vec![
+                "dists/jammy/Release",
+                "dists/jammy/main/binary-amd64/Packages",
+                "dists/noble/Release",
+                "pool/jammy/foo/foo_2_amd64.deb",
+                "pool/noble/bar/bar_1_amd64.deb",
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(remote.join("dists/noble/Release")).unwrap(),
+            "noble 1 old"
+        );
+
+        // Suites without packages have their stale packages removed
+        publish_suite(&target, &local, "noble", &view).unwrap();
+        assert!(!remote.join("pool/noble").exists());
+        assert_eq!(
+            fs::read_to_string(remote.join("dists/noble/Release")).unwrap(),
+            "noble 2"
+        );
+
+        // When only one suite of the pocket changes, only its fingerprint changes
+        let before = suite_fingerprints(&local).unwrap();
+        write(&local, "pool/jammy/foo/foo_3_amd64.deb", "foo 3");
+        fs::remove_file(local.join("pool/jammy/foo/foo_2_amd64.deb")).unwrap();
+        write(&local, "dists/jammy/main/binary-amd64/Packages", "foo 3");
+        write(&local, "dists/jammy/Release", "jammy 3 new");
+        let after = suite_fingerprints(&local).unwrap();
+        assert_eq!(after.keys().collect::<Vec<_>>(), vec!["jammy", "noble"]);
+        assert_ne!(after["jammy"], before["jammy"]);
+        assert_eq!(after["noble"], before["noble"]);
+
+        publish_suite(&target, &local, "jammy", &view).unwrap();
+        assert_eq!(
+            local_files(&remote).unwrap(),
+            vec![
+                "dists/jammy/Release",
+                "dists/jammy/main/binary-amd64/Packages",
+                "dists/noble/Release",
+                "pool/jammy/foo/foo_3_amd64.deb",
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(remote.join("dists/noble/Release")).unwrap(),
+            "noble 2"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use std::{fmt, path::Path};
+
+use crate::{
+    builder::Builder,
+    repo::{Arch, Suite},
+};
+
+/// Optional checks run on the results of a binary build
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum QaStage {
+    Lintian,
+    Autopkgtest,
+    Piuparts,
+}
+
+impl QaStage {
+    pub const ALL: &'static [Self] = &[Self::Lintian, Self::Autopkgtest, Self::Piuparts];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Lintian => "lintian",
+            Self::Autopkgtest => "autopkgtest",
+            Self::Piuparts => "piuparts",
+        }
+    }
+
+    /// Script running this stage in a directory containing the results of a binary build
+    pub fn script(
+        &self,
+        builder: &dyn Builder,
+        suite: &Suite,
+        arch: &Arch,
+        path: &Path,
+        dsc: &Path,
+        changes: &Path,
+    ) -> String {
+        let command = match self {
+            Self::Lintian => format!(
+                "lintian --no-tag-display-limit --display-info '{}'",
+                changes.display()
+            ),
+            Self::Autopkgtest => format!(
+                "autopkgtest --apt-upgrade '{}' ./*.deb -- {}",
+                dsc.display(),
+                builder.autopkgtest_virt(suite, arch)
+            ),
+            Self::Piuparts => format!(
+                "sudo piuparts --no-upgrade-test '--distribution={}' {} '{}'",
+                suite.id(),
+                builder.piuparts_args(suite, arch),
+                changes.display()
+            ),
+        };
+
+        format!(
+            r#"#!/usr/bin/env bash
+
+cd '{path}' || exit 1
+{command} 2>&1
+"#,
+            path = path.display(),
+            command = command
+        )
+    }
+
+    /// Determine the result of this stage from its exit code and output
+    pub fn result(&self, code: Option<i32>, output: &str) -> QaResult {
+        match self {
+            Self::Lintian => {
+                // lintian exits with 1 when tags are found, other failures mean it did not run
+                let errored = code.is_none_or(|code| code != 0 && code != 1);
+                if errored || output.lines().any(|line| line.starts_with("E: ")) {
+                    QaResult::Fail
+                } else if output.lines().any(|line| line.starts_with("W: ")) {
+                    QaResult::Warn
+                } else {
+                    QaResult::Pass
+                }
+            }
+            Self::Autopkgtest => match code {
+                Some(0) => QaResult::Pass,
+                // Some tests were skipped, or there were no tests
+                Some(2) | Some(8) => QaResult::Warn,
+                _ => QaResult::Fail,
+            },
+            Self::Piuparts => match code {
+                Some(0) => QaResult::Pass,
+                _ => QaResult::Fail,
+            },
+        }
+    }
+}
+
+impl fmt::Display for QaStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum QaResult {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl QaResult {
+    pub fn new(id: &str) -> Option<Self> {
+        match id {
+            "pass" => Some(Self::Pass),
+            "warn" => Some(Self::Warn),
+            "fail" => Some(Self::Fail),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Pass => "pass",
+            Self::Warn => "warn",
+            Self::Fail => "fail",
+        }
+    }
+
+    /// State used for the GitHub commit status
+    pub fn github_state(&self) -> &'static str {
+        match self {
+            Self::Pass | Self::Warn => "success",
+            Self::Fail => "failure",
+        }
+    }
+}
+
+impl fmt::Display for QaResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
@@ -1,13 +1,13 @@
-use std::{collections::BTreeMap, fs, path::PathBuf};
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
 
-use crate::config::{DEV_REPOS, POP_FOCAL_REPOS};
+use crate::config::{MirrorConfig, DEV_REPOS, POP_FOCAL_REPOS};
 
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Arch(&'static str);
 
 impl Arch {
     pub fn id(&self) -> &str {
-        &self.0
+        self.0
     }
 
     pub fn build_all(&self) -> bool {
@@ -17,22 +17,6 @@ impl Arch {
     pub fn build_linux_any(&self) -> bool {
         self.id() == "amd64" || self.id() == "arm64"
     }
-
-    pub fn is_arm(&self) -> bool {
-        self.id() == "arm64" || self.id() == "armhf"
-    }
-
-    pub fn ubuntu_mirror(&self, release: &str) -> &'static str {
-        if self.id() == "amd64" || self.id() == "i386" {
-            if release == "focal" {
-                "http://us.archive.ubuntu.com/ubuntu"
-            } else {
-                "http://apt.pop-os.org/ubuntu"
-            }
-        } else {
-            "http://ports.ubuntu.com/ubuntu-ports"
-        }
-    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -43,6 +27,10 @@ pub struct Package {
     pub tars: BTreeMap<String, PathBuf>,
     pub archs: Vec<Arch>,
     pub debs: BTreeMap<String, PathBuf>,
+    /// Debug symbol packages, which are only published to debug components
+    pub ddebs: BTreeMap<String, PathBuf>,
+    /// Build information and signed provenance of the binary builds
+    pub attestations: BTreeMap<String, PathBuf>,
 }
 
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -77,61 +65,55 @@ impl Pocket {
 #[derive(Clone)]
 pub struct RepoInfo {
     pub key: PathBuf,
-    pub release: &'static str,
-    pub staging: &'static str,
-    pub dput: Option<&'static str>,
+    pub release: String,
+    pub staging: String,
+    pub dput: Option<String>,
     pub archs: &'static [Arch],
 }
 
 impl RepoInfo {
-    pub fn new(suite: &Suite, dev: bool) -> Self {
-        const ARCHS: &'static [Arch] = &[Arch("amd64"), Arch("i386"), Arch("arm64"), Arch("armhf")];
+    /// The repositories and key a suite is built with, failing if mirrors has none configured
+    /// or the key is missing
+    pub fn new(suite: &Suite, dev: bool, mirrors: &MirrorConfig) -> io::Result<Self> {
+        let not_found = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no {} configured for {}", what, suite.id()),
+            )
+        };
+
+        let key = mirrors
+            .key(suite.id(), dev)
+            .ok_or_else(|| not_found("key"))?;
+        Ok(Self {
+            key: fs::canonicalize(key).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to find key {}: {}", key.display(), err),
+                )
+            })?,
+            release: mirrors
+                .release(suite.id(), dev)
+                .ok_or_else(|| not_found("release repository"))?
+                .to_string(),
+            staging: mirrors
+                .staging(suite.id(), dev)
+                .ok_or_else(|| not_found("staging repository"))?
+                .to_string(),
+            dput: mirrors.dput(suite.id(), dev).map(|ppa| ppa.to_string()),
+            archs: Self::archs(suite, dev),
+        })
+    }
 
-        const OLD_ARCHS: &'static [Arch] = &[Arch("amd64"), Arch("i386")];
+    /// The architectures a suite is built for
+    pub fn archs(suite: &Suite, dev: bool) -> &'static [Arch] {
+        const ARCHS: &[Arch] = &[Arch("amd64"), Arch("i386"), Arch("arm64"), Arch("armhf")];
 
-        if dev {
-            // Launchpad for all Ubuntu releases
-            // Launchpad used prior to Ubuntu 22.04
-            return match suite.id() {
-                "bionic" | "focal" => Self {
-                    key: fs::canonicalize("scripts/.ppa-dev.asc")
-                        .expect("failed to find dev PPA key"),
-                    release: "http://ppa.launchpad.net/system76-dev/stable/ubuntu",
-                    staging: "http://ppa.launchpad.net/system76-dev/pre-stable/ubuntu",
-                    dput: Some("ppa:system76-dev/pre-stable"),
-                    archs: match suite.id() {
-                        "bionic" => OLD_ARCHS,
-                        _ => ARCHS,
-                    },
-                },
-                // apt.pop-os.org for Ubuntu 22.04 and later
-                _ => Self {
-                    key: fs::canonicalize("scripts/.iso.asc").expect("failed to find ISO key"),
-                    release: "http://apt.pop-os.org/release-ubuntu",
-                    staging: "http://apt.pop-os.org/staging-ubuntu/master",
-                    dput: Some("ppa:system76-dev/pre-stable"),
-                    archs: ARCHS,
-                },
-            };
-        }
+        const OLD_ARCHS: &[Arch] = &[Arch("amd64"), Arch("i386")];
 
-        match suite.id() {
-            // Launchpad used prior to Pop 21.10
-            "bionic" | "focal" => Self {
-                key: fs::canonicalize("scripts/.ppa.asc").expect("failed to find PPA key"),
-                release: "http://ppa.launchpad.net/system76/pop/ubuntu",
-                staging: "http://ppa.launchpad.net/system76/proposed/ubuntu",
-                dput: Some("ppa:system76/proposed"),
-                archs: OLD_ARCHS,
-            },
-            // apt.pop-os.org for Pop 21.10 and later
-            _ => Self {
-                key: fs::canonicalize("scripts/.iso.asc").expect("failed to find ISO key"),
-                release: "http://apt.pop-os.org/release",
-                staging: "http://apt.pop-os.org/staging/master",
-                dput: None,
-                archs: ARCHS,
-            },
+        match (suite.id(), dev) {
+            ("bionic", _) | ("focal", false) => OLD_ARCHS,
+            _ => ARCHS,
         }
     }
 }
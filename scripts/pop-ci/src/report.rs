@@ -0,0 +1,65 @@
+use json::JsonValue;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Results recorded for packages across runs, stored as a JSON object of sections, each of
+/// which is an object keyed by package
+pub struct Report {
+    path: PathBuf,
+    value: JsonValue,
+}
+
+impl Report {
+    /// Load a report, or start an empty one if it does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let value = match fs::read_to_string(&path) {
+            Ok(data) => {
+                json::parse(&data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => JsonValue::new_object(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self { path, value })
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> &JsonValue {
+        &self.value[section][key]
+    }
+
+    pub fn set(&mut self, section: &str, key: &str, value: JsonValue) {
+        if !self.value[section].is_object() {
+            self.value[section] = JsonValue::new_object();
+        }
+        self.value[section][key] = value;
+    }
+
+    pub fn remove(&mut self, section: &str, key: &str) {
+        self.value[section].remove(key);
+    }
+
+    /// Keys of a section, or None if the section does not exist
+    pub fn keys(&self, section: &str) -> Option<Vec<String>> {
+        if self.value[section].is_object() {
+            Some(
+                self.value[section]
+                    .entries()
+                    .map(|(key, _)| key.to_string())
+                    .collect(),
+            )
+        } else {
+            None
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial_path = self.path.with_extension("partial");
+        fs::write(&partial_path, self.value.pretty(2))?;
+        fs::rename(partial_path, &self.path)
+    }
+}
@@ -0,0 +1,383 @@
+use json::JsonValue;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{
+    control::Control,
+    deb,
+    util::{rfc3339, sha256_file},
+};
+
+/// A crate from a Cargo.lock
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct CargoPackage {
+    pub name: String,
+    pub version: String,
+    pub source: Option<String>,
+    pub checksum: Option<String>,
+}
+
+impl CargoPackage {
+    fn is_crates_io(&self) -> bool {
+        self.source.as_deref().is_some_and(|source| {
+            source == "registry+https://github.com/rust-lang/crates.io-index"
+                || source == "sparse+https://index.crates.io/"
+        })
+    }
+}
+
+/// Parse the packages of a Cargo.lock
+pub fn parse_cargo_lock(data: &str) -> Vec<CargoPackage> {
+    let mut packages = Vec::new();
+    let mut current: Option<CargoPackage> = None;
+    for line in data.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            packages.extend(current.take());
+            if line == "[[package]]" {
+                current = Some(CargoPackage {
+                    name: String::new(),
+                    version: String::new(),
+                    source: None,
+                    checksum: None,
+                });
+            }
+            continue;
+        }
+
+        let package = match current.as_mut() {
+            Some(some) => some,
+            None => continue,
+        };
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim().trim_matches('"').to_string()),
+            None => continue,
+        };
+        match key {
+            "name" => package.name = value,
+            "version" => package.version = value,
+            "source" => package.source = Some(value),
+            "checksum" => package.checksum = Some(value),
+            _ => (),
+        }
+    }
+    packages.extend(current);
+    packages.retain(|package| !package.name.is_empty());
+    packages
+}
+
+/// A package installed in a build environment, from its manifest
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct DebPackage {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+}
+
+/// Parse a build environment manifest, with a package, version and architecture per line
+pub fn parse_manifest(data: &str) -> Vec<DebPackage> {
+    data.lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some(DebPackage {
+                name: parts.next()?.to_string(),
+                version: parts.next()?.to_string(),
+                arch: parts.next().unwrap_or("all").to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Files of a source package that describe what goes into its builds
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SourceFiles {
+    /// Contents of each Cargo.lock, by path in the source package
+    pub cargo_locks: BTreeMap<String, String>,
+    /// Contents of debian/control
+    pub control: Option<String>,
+}
+
+impl SourceFiles {
+    /// Find the Cargo.lock files and debian/control in a source tarball. Vendored crates and
+    /// build output are skipped.
+    pub fn from_tarball(path: &Path) -> io::Result<Self> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let data = fs::read(path)?;
+        let mut archive = tar::Archive::new(deb::decompress(&name, &data)?);
+
+        let mut files = Self::default();
+        for entry_res in archive.entries()? {
+            let mut entry = entry_res?;
+            let entry_path = entry.path()?.to_string_lossy().into_owned();
+            // Strip the directory of the source package
+            let relative = entry_path
+                .trim_start_matches("./")
+                .split_once('/')
+                .map_or("", |(_, rest)| rest)
+                .to_string();
+
+            let is_cargo_lock = (relative == "Cargo.lock" || relative.ends_with("/Cargo.lock"))
+                && !relative
+                    .split('/')
+                    .any(|part| part == "vendor" || part == "target");
+            if !is_cargo_lock && relative != "debian/control" {
+                continue;
+            }
+
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            if is_cargo_lock {
+                files.cargo_locks.insert(relative, contents);
+            } else {
+                files.control = Some(contents);
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// A software bill of materials for the build of a source package, in SPDX 2.3 JSON
+pub struct Sbom {
+    pub source: String,
+    pub version: String,
+    /// Unique URI of this document
+    pub namespace: String,
+    /// Seconds since the epoch the document was created
+    pub created: u64,
+    pub crates: BTreeSet<CargoPackage>,
+    pub build_depends: BTreeSet<String>,
+    pub environment: Vec<DebPackage>,
+    /// Built packages and their SHA256, by file name
+    pub binaries: BTreeMap<String, String>,
+}
+
+fn spdx_id(parts: &[&str]) -> String {
+    let mut id = "SPDXRef".to_string();
+    for part in parts.iter().filter(|part| !part.is_empty()) {
+        id.push('-');
+        id.push_str(part);
+    }
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+fn purl_escape(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace(':', "%3A")
+        .replace('+', "%2B")
+}
+
+fn spdx_package(id: &str, name: &str, version: Option<&str>, download: &str) -> JsonValue {
+    let mut package = JsonValue::new_object();
+    package["SPDXID"] = id.into();
+    package["name"] = name.into();
+    if let Some(version) = version {
+        package["versionInfo"] = version.into();
+    }
+    package["downloadLocation"] = download.into();
+    package["filesAnalyzed"] = false.into();
+    package
+}
+
+fn purl(locator: String) -> JsonValue {
+    let mut reference = JsonValue::new_object();
+    reference["referenceCategory"] = "PACKAGE-MANAGER".into();
+    reference["referenceType"] = "purl".into();
+    reference["referenceLocator"] = locator.into();
+    JsonValue::Array(vec![reference])
+}
+
+fn sha256_checksum(value: &str) -> JsonValue {
+    let mut checksum = JsonValue::new_object();
+    checksum["algorithm"] = "SHA256".into();
+    checksum["checksumValue"] = value.into();
+    JsonValue::Array(vec![checksum])
+}
+
+fn relationship(element: &str, kind: &str, related: &str) -> JsonValue {
+    let mut relationship = JsonValue::new_object();
+    relationship["spdxElementId"] = element.into();
+    relationship["relationshipType"] = kind.into();
+    relationship["relatedSpdxElement"] = related.into();
+    relationship
+}
+
+impl Sbom {
+    /// Collect the bill of materials of a binary build directory, built from a source package
+    /// with the build environment described by a manifest
+    pub fn new(
+        dsc_path: &Path,
+        manifest_path: &Path,
+        path: &Path,
+        namespace: String,
+        created: u64,
+    ) -> io::Result<Self> {
+        let dsc = Control::parse(&fs::read_to_string(dsc_path)?);
+        let source = dsc.get("Source").unwrap_or_default().to_string();
+        let version = dsc.get("Version").unwrap_or_default().to_string();
+
+        // The source tarball is next to the .dsc
+        let mut files = SourceFiles::default();
+        let source_dir = dsc_path.parent().unwrap_or(Path::new("."));
+        for entry_res in fs::read_dir(source_dir)? {
+            let entry = entry_res?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.contains(".tar.") && !file_name.ends_with(".asc") {
+                let tarball = SourceFiles::from_tarball(&entry.path())?;
+                files.cargo_locks.extend(tarball.cargo_locks);
+                if tarball.control.is_some() {
+                    files.control = tarball.control;
+                }
+            }
+        }
+
+        let mut crates = BTreeSet::new();
+        for data in files.cargo_locks.values() {
+            crates.extend(parse_cargo_lock(data));
+        }
+
+        let mut build_depends = BTreeSet::new();
+        match files
+            .control
+            .as_deref()
+            .and_then(|data| Control::parse_all(data).into_iter().next())
+        {
+            Some(source_control) => build_depends.extend(source_control.build_depends()),
+            // Fall back to the relationships copied to the .dsc
+            None => build_depends.extend(dsc.build_depends()),
+        }
+
+        let environment = if manifest_path.is_file() {
+            parse_manifest(&fs::read_to_string(manifest_path)?)
+        } else {
+            Vec::new()
+        };
+
+        let mut binaries = BTreeMap::new();
+        for entry_res in fs::read_dir(path)? {
+            let entry = entry_res?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.ends_with(".deb") {
+                binaries.insert(file_name, sha256_file(entry.path())?);
+            }
+        }
+
+        Ok(Self {
+            source,
+            version,
+            namespace,
+            created,
+            crates,
+            build_depends,
+            environment,
+            binaries,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let source_id = "SPDXRef-source";
+        let mut packages = Vec::new();
+        let mut relationships = vec![relationship("SPDXRef-DOCUMENT", "DESCRIBES", source_id)];
+
+        packages.push(spdx_package(
+            source_id,
+            &self.source,
+            Some(&self.version),
+            "NOASSERTION",
+        ));
+
+        for (file_name, sha256) in self.binaries.iter() {
+            let mut parts = file_name.trim_end_matches(".deb").split('_');
+            let name = parts.next().unwrap_or_default();
+            let version = parts.next().unwrap_or_default();
+            let arch = parts.next().unwrap_or_default();
+            let id = spdx_id(&["binary", name, arch]);
+            let mut package = spdx_package(&id, name, Some(version), "NOASSERTION");
+            package["packageFileName"] = file_name.as_str().into();
+            package["checksums"] = sha256_checksum(sha256);
+            package["externalRefs"] = purl(format!(
+                "pkg:deb/pop/{}@{}?arch={}",
+                name,
+                purl_escape(version),
+                arch
+            ));
+            packages.push(package);
+            relationships.push(relationship(&id, "GENERATED_FROM", source_id));
+        }
+
+        for package in self.crates.iter() {
+            let id = spdx_id(&["crate", &package.name, &package.version]);
+            let download = if package.is_crates_io() {
+                format!(
+                    "https://crates.io/api/v1/crates/{}/{}/download",
+                    package.name, package.version
+                )
+            } else {
+                "NOASSERTION".to_string()
+            };
+            let mut spdx = spdx_package(&id, &package.name, Some(&package.version), &download);
+            if let Some(checksum) = &package.checksum {
+                spdx["checksums"] = sha256_checksum(checksum);
+            }
+            spdx["externalRefs"] = purl(format!(
+                "pkg:cargo/{}@{}",
+                package.name,
+                purl_escape(&package.version)
+            ));
+            packages.push(spdx);
+            relationships.push(relationship(source_id, "CONTAINS", &id));
+        }
+
+        for name in self.build_depends.iter() {
+            // Versions of build dependencies are only known inside the build
+            let id = spdx_id(&["build-depends", name]);
+            packages.push(spdx_package(&id, name, None, "NOASSERTION"));
+            relationships.push(relationship(&id, "BUILD_DEPENDENCY_OF", source_id));
+        }
+
+        for package in self.environment.iter() {
+            let id = spdx_id(&[
+                "environment",
+                &package.name,
+                &package.version,
+                &package.arch,
+            ]);
+            let mut spdx = spdx_package(&id, &package.name, Some(&package.version), "NOASSERTION");
+            spdx["externalRefs"] = purl(format!(
+                "pkg:deb/ubuntu/{}@{}?arch={}",
+                package.name,
+                purl_escape(&package.version),
+                package.arch
+            ));
+            packages.push(spdx);
+            relationships.push(relationship(&id, "BUILD_TOOL_OF", source_id));
+        }
+
+        let mut value = JsonValue::new_object();
+        value["spdxVersion"] = "SPDX-2.3".into();
+        value["dataLicense"] = "CC0-1.0".into();
+        value["SPDXID"] = "SPDXRef-DOCUMENT".into();
+        value["name"] = format!("{}_{}", self.source, self.version).into();
+        value["documentNamespace"] = self.namespace.as_str().into();
+        value["creationInfo"]["created"] = rfc3339(self.created).into();
+        value["creationInfo"]["creators"] =
+            JsonValue::Array(vec![
+                format!("Tool: pop-ci-{}", env!("CARGO_PKG_VERSION")).into()
+            ]);
+        value["packages"] = JsonValue::Array(packages);
+        value["relationships"] = JsonValue::Array(relationships);
+        value
+    }
+}
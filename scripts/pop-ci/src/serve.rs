@@ -0,0 +1,124 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    thread,
+};
+
+/// Minimal static file server, used to provide local apt repositories to builders
+pub struct Server {
+    addr: SocketAddr,
+}
+
+impl Server {
+    /// Serve files in root on the given address, in a background thread
+    pub fn spawn<P: AsRef<Path>>(root: P, bind: &str) -> io::Result<Self> {
+        let root = fs::canonicalize(root.as_ref())?;
+        let listener = TcpListener::bind(bind)?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            for stream_res in listener.incoming() {
+                let stream = match stream_res {
+                    Ok(ok) => ok,
+                    Err(err) => {
+                        eprintln!("Server: failed to accept connection: {}", err);
+                        continue;
+                    }
+                };
+                let root = root.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handle(&root, stream) {
+                        eprintln!("Server: failed to handle connection: {}", err);
+                    }
+                });
+            }
+        });
+        Ok(Self { addr })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn resolve(root: &Path, url_path: &str) -> Option<PathBuf> {
+    // apt escapes characters like + and ~ in file names
+    let url_path = percent_decode(url_path.split(['?', '#']).next().unwrap_or(""))?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(url_path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => (),
+            // Refuse to serve anything outside of root
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+fn respond(stream: &mut TcpStream, status: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    )
+}
+
+fn handle(root: &Path, mut stream: TcpStream) -> io::Result<()> {
+    let mut request = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request)?;
+    // Headers are not used, but must be read before responding
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let url_path = parts.next().unwrap_or("");
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed");
+    }
+
+    let path = match resolve(root, url_path) {
+        Some(some) if some.is_file() => some,
+        _ => return respond(&mut stream, "404 Not Found"),
+    };
+
+    let mut file = fs::File::open(&path)?;
+    let len = file.metadata()?.len();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n\r\n",
+        len
+    )?;
+    if method == "GET" {
+        io::copy(&mut file, &mut stream)?;
+    }
+    stream.flush()
+}
@@ -0,0 +1,272 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    config::{SigningBackend, SigningConfig},
+    util::{check_output, check_status},
+};
+
+/// Temporary directory for keyrings and certificates, removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "pop-ci-signing.{}.{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Signs repository metadata and build provenance with one or more OpenPGP keys, and verifies
+/// the signatures it made, using gpg or an external sop command
+#[derive(Clone, Debug)]
+pub struct Signer {
+    backend: SigningBackend,
+    keys: Vec<String>,
+    gnupghome: Option<PathBuf>,
+}
+
+impl Signer {
+    /// Signer using the configured keys, or the gpg key of default_key if none are configured
+    pub fn new(config: &SigningConfig, default_key: &str) -> Self {
+        if config.keys.is_empty() {
+            Self {
+                backend: SigningBackend::Gpg,
+                keys: vec![default_key.to_string()],
+                gnupghome: config.gnupghome.clone(),
+            }
+        } else {
+            Self {
+                backend: config.backend.clone(),
+                keys: config.keys.clone(),
+                gnupghome: config.gnupghome.clone(),
+            }
+        }
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    fn gpg_command(&self, program: &str) -> process::Command {
+        let mut command = process::Command::new(program);
+        if let Some(gnupghome) = &self.gnupghome {
+            command.env("GNUPGHOME", gnupghome);
+        }
+        command
+    }
+
+    fn gpg_sign(&self, mode: &str, input: &Path, output: &Path) -> io::Result<()> {
+        let mut command = self.gpg_command("gpg");
+        command.arg(mode);
+        for key in self.keys.iter() {
+            command.arg("--local-user").arg(key);
+        }
+        command
+            .arg("--batch")
+            .arg("--yes")
+            .arg("--digest-algo")
+            .arg("sha512")
+            .arg("-o")
+            .arg(output)
+            .arg(input)
+            .status()
+            .and_then(check_status)
+    }
+
+    /// Primary key fingerprint of a gpg key specification
+    fn gpg_fingerprint(&self, key: &str) -> io::Result<String> {
+        let output = self
+            .gpg_command("gpg")
+            .arg("--batch")
+            .arg("--with-colons")
+            .arg("--fingerprint")
+            .arg(key.trim_end_matches('!'))
+            .stdout(process::Stdio::piped())
+            .spawn()?
+            .wait_with_output()
+            .and_then(check_output)?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| {
+                let fields: Vec<&str> = line.split(':').collect();
+                if fields[0] == "fpr" {
+                    fields.get(9).map(|fpr| fpr.to_string())
+                } else {
+                    None
+                }
+            })
+            .ok_or_else(|| io::Error::other(format!("no fingerprint found for key {}", key)))
+    }
+
+    /// Verify with gpgv against an exported keyring, like apt does, and check that each key made
+    /// a valid signature
+    fn gpg_verify(&self, args: &[&Path]) -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let keyring = temp_dir.0.join("keyring.gpg");
+        let mut export = self.gpg_command("gpg");
+        export.arg("--batch").arg("--yes").arg("-o").arg(&keyring);
+        export.arg("--export");
+        for key in self.keys.iter() {
+            export.arg(key.trim_end_matches('!'));
+        }
+        export.status().and_then(check_status)?;
+
+        let output = self
+            .gpg_command("gpgv")
+            .arg("--status-fd")
+            .arg("1")
+            .arg("--keyring")
+            .arg(&keyring)
+            .args(args)
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::null())
+            .spawn()?
+            .wait_with_output()
+            .and_then(check_output)?;
+        let status = String::from_utf8_lossy(&output.stdout);
+        for key in self.keys.iter() {
+            let fingerprint = self.gpg_fingerprint(key)?;
+            let signed = status.lines().any(|line| {
+                let mut fields = line.split_whitespace();
+                fields.nth(1) == Some("VALIDSIG") && fields.last() == Some(&fingerprint)
+            });
+            if !signed {
+                return Err(io::Error::other(format!(
+                    "no valid signature by {} on {}",
+                    fingerprint,
+                    args.last().unwrap().display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a sop subcommand with input on stdin, writing stdout to output
+    fn sop_run(
+        command: &str,
+        args: &[&str],
+        files: &[&Path],
+        input: &Path,
+        output: Option<&Path>,
+    ) -> io::Result<()> {
+        let stdout = match output {
+            Some(output) => process::Stdio::from(fs::File::create(output)?),
+            None => process::Stdio::null(),
+        };
+        process::Command::new(command)
+            .args(args)
+            .args(files)
+            .stdin(fs::File::open(input)?)
+            .stdout(stdout)
+            .status()
+            .and_then(check_status)
+    }
+
+    fn sop_sign(
+        &self,
+        command: &str,
+        args: &[&str],
+        input: &Path,
+        output: &Path,
+    ) -> io::Result<()> {
+        let keys: Vec<&Path> = self.keys.iter().map(Path::new).collect();
+        Self::sop_run(command, args, &keys, input, Some(output))
+    }
+
+    /// Verify with the certificate of each key in turn, so that each key must have made a valid
+    /// signature
+    fn sop_verify(&self, command: &str, signature: Option<&Path>, input: &Path) -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        for (i, key) in self.keys.iter().enumerate() {
+            let cert = temp_dir.0.join(format!("{}.cert", i));
+            Self::sop_run(command, &["extract-cert"], &[], Path::new(key), Some(&cert))?;
+            match signature {
+                Some(signature) => {
+                    Self::sop_run(command, &["verify"], &[signature, &cert], input, None)
+                }
+                None => Self::sop_run(command, &["inline-verify"], &[&cert], input, None),
+            }
+            .map_err(|err| {
+                io::Error::other(format!(
+                    "no valid signature by {} on {}: {}",
+                    key,
+                    input.display(),
+                    err
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Write a clearsigned copy of input to output
+    pub fn clearsign(&self, input: &Path, output: &Path) -> io::Result<()> {
+        match &self.backend {
+            SigningBackend::Gpg => self.gpg_sign("--clearsign", input, output),
+            SigningBackend::SopCommand(command) => {
+                self.sop_sign(command, &["inline-sign", "--as=clearsigned"], input, output)
+            }
+        }
+    }
+
+    /// Write an armored detached signature of input to output
+    pub fn detach_sign(&self, input: &Path, output: &Path) -> io::Result<()> {
+        match &self.backend {
+            SigningBackend::Gpg => self.gpg_sign("-abs", input, output),
+            SigningBackend::SopCommand(command) => self.sop_sign(command, &["sign"], input, output),
+        }
+    }
+
+    /// Check that a clearsigned file has valid signatures by all keys
+    pub fn verify_clearsigned(&self, path: &Path) -> io::Result<()> {
+        match &self.backend {
+            SigningBackend::Gpg => self.gpg_verify(&[path]),
+            SigningBackend::SopCommand(command) => self.sop_verify(command, None, path),
+        }
+    }
+
+    /// Check that a detached signature of a file has valid signatures by all keys
+    pub fn verify_detached(&self, path: &Path, signature: &Path) -> io::Result<()> {
+        match &self.backend {
+            SigningBackend::Gpg => self.gpg_verify(&[signature, path]),
+            SigningBackend::SopCommand(command) => self.sop_verify(command, Some(signature), path),
+        }
+    }
+
+    /// Sign the Release file of a dists directory as InRelease and Release.gpg, and verify the
+    /// signatures before they are published
+    pub fn sign_release(&self, dists_path: &Path) -> io::Result<()> {
+        let release_file = dists_path.join("Release");
+        let in_release = dists_path.join("InRelease");
+        let release_gpg = dists_path.join("Release.gpg");
+        // Old signatures are removed instead of overwritten, as they may be linked from
+        // snapshots
+        for path in [&in_release, &release_gpg] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        self.clearsign(&release_file, &in_release)?;
+        self.detach_sign(&release_file, &release_gpg)?;
+        self.verify_clearsigned(&in_release)?;
+        self.verify_detached(&release_file, &release_gpg)
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{index::link_or_copy, util::rfc3339};
+
+/// Directory of a pocket containing its snapshots
+pub const SNAPSHOTS: &str = "snapshots";
+
+/// Directories of a pocket that are snapshotted
+const SNAPSHOT_DIRS: &[&str] = &["dists", "pool"];
+
+/// Recreate a directory tree with hard links to its files, copying files that can not be
+/// linked
+pub fn link_tree(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry_res in fs::read_dir(from)? {
+        let entry = entry_res?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            link_tree(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            link_or_copy(&entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of a snapshot taken at seconds since the epoch, which sorts by time
+pub fn name(secs: u64) -> String {
+    rfc3339(secs).replace(['-', ':'], "")
+}
+
+/// Names of the snapshots of a pocket, from oldest to newest
+pub fn list(pocket_path: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    let snapshots_path = pocket_path.join(SNAPSHOTS);
+    if snapshots_path.is_dir() {
+        for entry_res in fs::read_dir(&snapshots_path)? {
+            let entry = entry_res?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && !file_name.starts_with("partial.") {
+                names.push(file_name);
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Snapshot the dists and pool of a pocket, as snapshots/<name>. The snapshot shares files
+/// with the pocket, so files of the pocket must be replaced instead of modified in place.
+pub fn create(pocket_path: &Path, name: &str) -> io::Result<PathBuf> {
+    let snapshots_path = pocket_path.join(SNAPSHOTS);
+    let snapshot_path = snapshots_path.join(name);
+    if snapshot_path.exists() {
+        return Ok(snapshot_path);
+    }
+
+    let partial_path = snapshots_path.join(format!("partial.{}", name));
+    if partial_path.exists() {
+        fs::remove_dir_all(&partial_path)?;
+    }
+    fs::create_dir_all(&partial_path)?;
+    for dir in SNAPSHOT_DIRS.iter() {
+        if pocket_path.join(dir).is_dir() {
+            link_tree(&pocket_path.join(dir), &partial_path.join(dir))?;
+        }
+    }
+    fs::rename(&partial_path, &snapshot_path)?;
+    Ok(snapshot_path)
+}
+
+/// Remove all but the newest keep snapshots of a pocket. Returns the number of snapshots
+/// removed.
+pub fn prune(pocket_path: &Path, keep: usize) -> io::Result<usize> {
+    let names = list(pocket_path)?;
+    let remove = names.len().saturating_sub(keep);
+    for name in names.iter().take(remove) {
+        fs::remove_dir_all(pocket_path.join(SNAPSHOTS).join(name))?;
+    }
+    Ok(remove)
+}
+
+/// Replace the dists and pool of a pocket with those of a snapshot
+pub fn restore(pocket_path: &Path, name: &str) -> io::Result<()> {
+    let snapshot_path = pocket_path.join(SNAPSHOTS).join(name);
+    if !snapshot_path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("snapshot {} not found", name),
+        ));
+    }
+
+    for dir in SNAPSHOT_DIRS.iter() {
+        let partial_path = pocket_path.join(format!("partial.{}", dir));
+        if partial_path.exists() {
+            fs::remove_dir_all(&partial_path)?;
+        }
+        if snapshot_path.join(dir).is_dir() {
+            link_tree(&snapshot_path.join(dir), &partial_path)?;
+        } else {
+            fs::create_dir(&partial_path)?;
+        }
+
+        let path = pocket_path.join(dir);
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::rename(&partial_path, &path)?;
+    }
+    Ok(())
+}
@@ -1,4 +1,5 @@
-use std::{io, process};
+use sha2::{Digest, Sha256};
+use std::{fmt::Write, fs, io, path::Path, process};
 
 pub fn check_output(output: process::Output) -> io::Result<process::Output> {
     check_status(output.status)?;
@@ -9,6 +10,144 @@ pub fn check_status(status: process::ExitStatus) -> io::Result<()> {
     if status.success() {
         Ok(())
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, format!("{}", status)))
+        Err(io::Error::other(format!("{}", status)))
+    }
+}
+
+/// Lowercase hex SHA256 digest of data
+pub fn sha256(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(data).iter() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    Ok(sha256(&fs::read(path)?))
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// UTC date and time of seconds since the epoch, as year, month, day, hour, minute, second
+/// and day of the week, with Sunday as 0
+fn utc(secs: u64) -> (i64, i64, i64, u64, u64, u64, i64) {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Civil date from days since 1970-01-01, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    // 1970-01-01 was a Thursday
+    let weekday = (days + 4).rem_euclid(7);
+
+    (
+        year,
+        month,
+        day,
+        time / 3600,
+        (time / 60) % 60,
+        time % 60,
+        weekday,
+    )
+}
+
+/// Format seconds since the epoch as an RFC 3339 UTC timestamp
+pub fn rfc3339(secs: u64) -> String {
+    let (year, month, day, hour, minute, second, _) = utc(secs);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Format seconds since the epoch as an RFC 2822 UTC date, as used in apt Release files
+pub fn rfc2822(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let (year, month, day, hour, minute, second, weekday) = utc(secs);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} UTC",
+        WEEKDAYS[weekday as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// Parse an RFC 2822 date in UTC, as written by rfc2822, to seconds since the epoch
+pub fn parse_rfc2822(date: &str) -> Option<u64> {
+    // The day of the week is optional
+    let date = date.split_once(", ").map_or(date, |(_, date)| date);
+    let mut parts = date.split_whitespace();
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|name| *name == month_name)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':');
+    let hour: u64 = time.next()?.parse().ok()?;
+    let minute: u64 = time.next()?.parse().ok()?;
+    let second: u64 = time.next().unwrap_or("0").parse().ok()?;
+    match parts.next() {
+        None | Some("UTC") | Some("GMT") | Some("+0000") | Some("Z") => (),
+        Some(_) => return None,
+    }
+
+    // Days since 1970-01-01 from a civil date, the inverse of utc
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    if days < 0 {
+        return None;
+    }
+    Some(days as u64 * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc2822_round_trip() {
+        assert_eq!(rfc2822(0), "Thu, 01 Jan 1970 00:00:00 UTC");
+        assert_eq!(rfc2822(951_782_400), "Tue, 29 Feb 2000 00:00:00 UTC");
+        assert_eq!(rfc2822(1_700_000_000), "Tue, 14 Nov 2023 22:13:20 UTC");
+        for secs in [0, 59, 86_399, 951_782_400, 1_700_000_000, 4_102_444_800] {
+            assert_eq!(parse_rfc2822(&rfc2822(secs)), Some(secs));
+        }
+    }
+
+    #[test]
+    fn parse_rfc2822_formats() {
+        assert_eq!(
+            parse_rfc2822("14 Nov 2023 22:13:20 +0000"),
+            Some(1_700_000_000)
+        );
+        assert_eq!(
+            parse_rfc2822("Tue, 14 Nov 2023 22:13:20 GMT"),
+            Some(1_700_000_000)
+        );
+        assert_eq!(parse_rfc2822("Tue, 14 Nov 2023 22:13"), Some(1_699_999_980));
+        // Only UTC is supported
+        assert_eq!(parse_rfc2822("Tue, 14 Nov 2023 22:13:20 +0100"), None);
+        assert_eq!(parse_rfc2822("Tue, 14 Foo 2023 22:13:20 UTC"), None);
+        assert_eq!(parse_rfc2822("Wed, 31 Dec 1969 23:59:59 UTC"), None);
+        assert_eq!(parse_rfc2822(""), None);
     }
 }
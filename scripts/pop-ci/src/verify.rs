@@ -0,0 +1,180 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    deb::{self, Deb, TarEntry},
+    util::sha256,
+};
+
+/// Result of rebuilding a package and comparing it to the original build
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Reproducibility {
+    Reproducible,
+    Unreproducible,
+    /// The rebuild or the comparison failed
+    Failed,
+}
+
+impl Reproducibility {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Reproducible => "reproducible",
+            Self::Unreproducible => "unreproducible",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for Reproducibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+fn debs(path: &Path) -> io::Result<BTreeMap<String, PathBuf>> {
+    let mut debs = BTreeMap::new();
+    for entry_res in fs::read_dir(path)? {
+        let entry = entry_res?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if file_name.ends_with(".deb") {
+            debs.insert(file_name, entry.path());
+        }
+    }
+    Ok(debs)
+}
+
+fn compare_entries(member: &str, original: &[TarEntry], rebuilt: &[TarEntry]) -> Vec<String> {
+    let original: BTreeMap<&str, &TarEntry> = original
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+    let rebuilt: BTreeMap<&str, &TarEntry> = rebuilt
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+
+    let mut differences = Vec::new();
+    let paths: BTreeSet<&str> = original.keys().chain(rebuilt.keys()).copied().collect();
+    for path in paths {
+        let (a, b) = match (original.get(path), rebuilt.get(path)) {
+            (Some(a), Some(b)) => (a, b),
+            (Some(_), None) => {
+                differences.push(format!("{}: {}: only in original", member, path));
+                continue;
+            }
+            (None, Some(_)) => {
+                differences.push(format!("{}: {}: only in rebuild", member, path));
+                continue;
+            }
+            (None, None) => continue,
+        };
+
+        let mut parts = Vec::new();
+        if a.kind != b.kind {
+            parts.push("type");
+        }
+        if a.sha256 != b.sha256 {
+            parts.push("content");
+        }
+        if a.link != b.link {
+            parts.push("link target");
+        }
+        if a.mode != b.mode {
+            parts.push("mode");
+        }
+        if a.uid != b.uid || a.gid != b.gid {
+            parts.push("owner");
+        }
+        if a.mtime != b.mtime {
+            parts.push("mtime");
+        }
+        if !parts.is_empty() {
+            differences.push(format!(
+                "{}: {}: {} differs",
+                member,
+                path,
+                parts.join(", ")
+            ));
+        }
+    }
+    differences
+}
+
+fn compare_debs(name: &str, original: &Path, rebuilt: &Path) -> io::Result<Vec<String>> {
+    let original = Deb::open(original)?;
+    let rebuilt = Deb::open(rebuilt)?;
+
+    let mut differences = Vec::new();
+    let names: BTreeSet<&str> = original
+        .members
+        .iter()
+        .chain(rebuilt.members.iter())
+        .map(|member| member.name.as_str())
+        .collect();
+    for member_name in names {
+        let find = |deb: &Deb| {
+            deb.members
+                .iter()
+                .find(|member| member.name == member_name)
+                .cloned()
+        };
+        match (find(&original), find(&rebuilt)) {
+            (Some(a), Some(b)) => {
+                if sha256(&a.data) == sha256(&b.data) {
+                    continue;
+                }
+                let prefix = format!("{}: {}", name, member_name);
+                if member_name.contains(".tar") {
+                    let entry_differences =
+                        compare_entries(&prefix, &deb::tar_entries(&a)?, &deb::tar_entries(&b)?);
+                    if entry_differences.is_empty() {
+                        // Same files, but the archive itself differs, like in file order
+                        differences.push(format!("{}: archive differs", prefix));
+                    }
+                    differences.extend(entry_differences);
+                } else {
+                    differences.push(format!("{}: content differs", prefix));
+                }
+            }
+            (Some(_), None) => {
+                differences.push(format!("{}: {}: only in original", name, member_name))
+            }
+            (None, Some(_)) => {
+                differences.push(format!("{}: {}: only in rebuild", name, member_name))
+            }
+            (None, None) => (),
+        }
+    }
+    Ok(differences)
+}
+
+/// Compare the debs of two binary builds byte for byte, summarizing the files that differ
+/// inside of debs that are not identical. Returns no differences if the builds are identical.
+pub fn compare_builds(original: &Path, rebuilt: &Path) -> io::Result<Vec<String>> {
+    let original_debs = debs(original)?;
+    let rebuilt_debs = debs(rebuilt)?;
+
+    let mut differences = Vec::new();
+    let names: BTreeSet<&String> = original_debs.keys().chain(rebuilt_debs.keys()).collect();
+    for name in names {
+        match (original_debs.get(name), rebuilt_debs.get(name)) {
+            (Some(a), Some(b)) => {
+                if fs::read(a)? != fs::read(b)? {
+                    let deb_differences = compare_debs(name, a, b)?;
+                    if deb_differences.is_empty() {
+                        // Same members, but the ar container differs, like in member timestamps
+                        differences.push(format!("{}: ar container differs", name));
+                    }
+                    differences.extend(deb_differences);
+                }
+            }
+            (Some(_), None) => differences.push(format!("{}: only in original", name)),
+            (None, Some(_)) => differences.push(format!("{}: only in rebuild", name)),
+            (None, None) => (),
+        }
+    }
+    Ok(differences)
+}